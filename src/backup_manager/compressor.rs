@@ -1,166 +1,448 @@
-use async_compression::tokio::write::GzipEncoder;
+use super::hashing::{self, DuplicateTracker};
+use super::output::{self, OutputWriter};
+use super::BackupMetadata;
+use crate::configs::{BackupsConfig, S3Config};
+use async_compression::tokio::write::{BzEncoder, GzipEncoder, XzEncoder, ZstdEncoder};
 use async_compression::Level;
 use async_trait::async_trait;
 use async_zip::write::ZipFileWriter;
 use async_zip::ZipEntryBuilder;
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::{bail, eyre, ContextCompat, WrapErr};
 use color_eyre::Result;
+use std::fs::Metadata;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 use tokio::fs::File;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Unix mode of symlinks in zip archives
+pub(super) const ZIP_SYMLINK_MODE: u16 = 0o120777;
+
+/// Name of the metadata entry in tar archives
+pub(super) const METADATA_ENTRY: &str = ".dolorous-backup.json";
 
 #[async_trait]
 pub trait Compressor {
     const NAME: &'static str;
-    async fn new(path: PathBuf) -> Result<Box<Self>>;
-    /// Returns: size of original size
-    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<f64>;
-    /// Returns: size of compressed file
-    async fn finish(self) -> Result<f64>;
+    /// Whether small files can be read ahead and added with `add_file_data`
+    const PREFETCH: bool = false;
+    async fn new(path: PathBuf, config: &BackupsConfig) -> Result<Box<Self>>;
+    /// Returns: size of original file, if known
+    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<Option<u64>>;
+    /// Adds a file already read into memory. Only called if `PREFETCH` is set.
+    async fn add_file_data(
+        &mut self,
+        relative_path: &Path,
+        _metadata: &Metadata,
+        _data: Vec<u8>,
+    ) -> Result<Option<u64>> {
+        bail!(
+            "Can't add {} from memory in {} backups",
+            relative_path.display(),
+            Self::NAME
+        )
+    }
+    /// Stores a symlink itself, instead of its target
+    async fn add_symlink(
+        &mut self,
+        _path: &Path,
+        relative_path: &Path,
+        _target: &Path,
+    ) -> Result<()> {
+        bail!(
+            "Can't store symlink {} in {} backups",
+            relative_path.display(),
+            Self::NAME
+        )
+    }
+    /// Embeds backup metadata into the archive, if the format supports it
+    async fn add_metadata(&mut self, _metadata: &BackupMetadata) -> Result<()> {
+        Ok(())
+    }
+    /// Duplicates found while adding files, if the compressor deduplicates
+    fn duplicates(&self) -> Option<&DuplicateTracker> {
+        None
+    }
+    /// Returns: size of compressed file, if known
+    async fn finish(self) -> Result<Option<u64>>;
+}
+
+async fn append_tar_metadata<W: AsyncWrite + Unpin + Send + 'static>(
+    writer: &mut tokio_tar::Builder<W>,
+    metadata: &BackupMetadata,
+) -> Result<()> {
+    let data = serde_json::to_vec_pretty(metadata)?;
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    writer
+        .append_data(&mut header, METADATA_ENTRY, data.as_slice())
+        .await
+        .wrap_err("Failed to write metadata entry")?;
+    Ok(())
+}
+
+/// Appends a file, keeping its mode, owner and modification time.
+/// With deduplication, files with the same contents as an earlier file are stored as hardlinks to it.
+async fn append_tar_file<W: AsyncWrite + Unpin + Send + 'static>(
+    writer: &mut tokio_tar::Builder<W>,
+    dedup: Option<&mut DuplicateTracker>,
+    path: &Path,
+    relative_path: &Path,
+) -> Result<Option<u64>> {
+    let file = File::open(path).await.wrap_err("Failed to open file")?;
+    let metadata = file.metadata().await.wrap_err("Failed to read metadata")?;
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tokio_tar::HeaderMode::Complete);
+    if let Some(dedup) = dedup {
+        let hash = hashing::hash_file(path).await?;
+        if let Some(first) = dedup.record(hash, relative_path, metadata.len()) {
+            debug!("Storing {:?} as a link to {:?}", relative_path, first);
+            header.set_entry_type(tokio_tar::EntryType::Link);
+            header.set_size(0);
+            header
+                .set_link_name(first)
+                .wrap_err("Invalid link target")?;
+            writer
+                .append_data(&mut header, relative_path, tokio::io::empty())
+                .await
+                .wrap_err("Failed to add link")?;
+            return Ok(Some(metadata.len()));
+        }
+    }
+    writer
+        .append_data(&mut header, relative_path, file)
+        .await
+        .wrap_err("Failed to compress file")?;
+    Ok(Some(metadata.len()))
+}
+
+/// Appends a symlink, keeping the owner and modification time of the link itself
+async fn append_tar_symlink<W: AsyncWrite + Unpin + Send + 'static>(
+    writer: &mut tokio_tar::Builder<W>,
+    path: &Path,
+    relative_path: &Path,
+    target: &Path,
+) -> Result<()> {
+    let metadata = tokio::fs::symlink_metadata(path)
+        .await
+        .wrap_err("Failed to read symlink metadata")?;
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_metadata_in_mode(&metadata, tokio_tar::HeaderMode::Complete);
+    header.set_entry_type(tokio_tar::EntryType::Symlink);
+    header.set_size(0);
+    header
+        .set_link_name(target)
+        .wrap_err("Invalid symlink target")?;
+    writer
+        .append_data(&mut header, relative_path, tokio::io::empty())
+        .await
+        .wrap_err("Failed to add symlink")?;
+    Ok(())
+}
+
+fn zip_entry(relative_path: &Path, metadata: &Metadata) -> Result<ZipEntryBuilder> {
+    // TODO: more compressions
+    let mut builder = ZipEntryBuilder::new(
+        relative_path
+            .to_str()
+            .ok_or_else(|| eyre!("Invalid file name"))?
+            .to_string(),
+        async_zip::Compression::Deflate,
+    )
+    .unix_permissions(metadata.permissions().mode() as u16);
+    if let Ok(modified) = metadata.modified() {
+        builder = builder.last_modification_date(DateTime::<Utc>::from(modified));
+    }
+    Ok(builder)
 }
 
 pub struct ZipCompressor {
-    writer: ZipFileWriter<File>,
+    writer: ZipFileWriter<OutputWriter>,
     path: PathBuf,
+    s3: Option<S3Config>,
 }
 
 #[async_trait]
 impl Compressor for ZipCompressor {
     const NAME: &'static str = "zip";
+    const PREFETCH: bool = true;
 
-    #[tracing::instrument]
-    async fn new(path: PathBuf) -> Result<Box<Self>> {
-        let writer = ZipFileWriter::new(
-            File::create(&path)
-                .await
-                .wrap_err("Failed to create output file!")?,
-        );
-        Ok(Box::new(Self { writer, path }))
+    #[tracing::instrument(skip(config))]
+    async fn new(path: PathBuf, config: &BackupsConfig) -> Result<Box<Self>> {
+        let writer = ZipFileWriter::new(output::create(&path, config).await?);
+        Ok(Box::new(Self {
+            writer,
+            path,
+            s3: config.s3.clone(),
+        }))
     }
 
     #[tracing::instrument(skip(self))]
-    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<f64> {
-        // TODO: more compressions
-        let builder = ZipEntryBuilder::new(
-            relative_path
-                .to_str()
-                .ok_or_else(|| eyre!("Invalid file name"))?
-                .to_string(),
-            async_zip::Compression::Deflate,
-        );
-        let mut stream_writer = self.writer.write_entry_stream(builder).await?;
+    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<Option<u64>> {
         let mut input_file = File::open(path).await.wrap_err("Failed to open file")?;
+        let metadata = input_file
+            .metadata()
+            .await
+            .wrap_err("Failed to read metadata")?;
+        let builder = zip_entry(relative_path, &metadata)?;
+        let mut stream_writer = self.writer.write_entry_stream(builder).await?;
         let compressed = tokio::io::copy(&mut input_file, &mut stream_writer)
             .await
             .wrap_err("Failed to compress file!")?;
-        Ok(compressed as f64)
+        Ok(Some(compressed))
+    }
+
+    #[tracing::instrument(skip(self, metadata, data))]
+    async fn add_file_data(
+        &mut self,
+        relative_path: &Path,
+        metadata: &Metadata,
+        data: Vec<u8>,
+    ) -> Result<Option<u64>> {
+        let builder = zip_entry(relative_path, metadata)?;
+        self.writer
+            .write_entry_whole(builder, &data)
+            .await
+            .wrap_err("Failed to compress file!")?;
+        Ok(Some(data.len() as u64))
     }
 
     #[tracing::instrument(skip(self))]
-    async fn finish(mut self) -> Result<f64> {
+    async fn add_symlink(
+        &mut self,
+        path: &Path,
+        relative_path: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        // Zip stores symlinks as entries with the symlink file type, containing the target
+        let mut builder = ZipEntryBuilder::new(
+            relative_path
+                .to_str()
+                .ok_or_else(|| eyre!("Invalid file name"))?
+                .to_string(),
+            async_zip::Compression::Stored,
+        )
+        .unix_permissions(ZIP_SYMLINK_MODE);
+        let metadata = tokio::fs::symlink_metadata(path)
+            .await
+            .wrap_err("Failed to read symlink metadata")?;
+        if let Ok(modified) = metadata.modified() {
+            builder = builder.last_modification_date(DateTime::<Utc>::from(modified));
+        }
+        let target = target
+            .to_str()
+            .ok_or_else(|| eyre!("Invalid symlink target"))?;
         self.writer
+            .write_entry_whole(builder, target.as_bytes())
+            .await
+            .wrap_err("Failed to add symlink")?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_metadata(&mut self, metadata: &BackupMetadata) -> Result<()> {
+        self.writer.comment(serde_json::to_string(metadata)?);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn finish(self) -> Result<Option<u64>> {
+        let mut output = self
+            .writer
             .close()
             .await
             .wrap_err("Failed to compress files")?;
-        let output_size = tokio::fs::metadata(&self.path)
+        output
+            .shutdown()
             .await
-            .map(|r| r.len() as f64)
-            .unwrap_or(f64::NAN);
-        Ok(output_size)
+            .wrap_err("Failed to flush output file")?;
+        Ok(output::size(&self.path, self.s3.as_ref()).await)
     }
 }
 
-pub struct TarGzCompressor<const LEVEL: u32> {
-    writer: tokio_tar::Builder<GzipEncoder<File>>,
-    path: PathBuf,
+/// Compression a tar archive is written through
+pub trait TarEncoding: Send + 'static {
+    const NAME: &'static str;
+    type Writer: AsyncWrite + Unpin + Send + 'static;
+    fn encoder(output: OutputWriter, config: &BackupsConfig) -> Self::Writer;
 }
 
-#[async_trait]
-impl<const LEVEL: u32> Compressor for TarGzCompressor<LEVEL> {
+/// No compression, a plain tar archive
+pub struct Uncompressed;
+
+impl TarEncoding for Uncompressed {
+    const NAME: &'static str = "tar";
+    type Writer = OutputWriter;
+
+    fn encoder(output: OutputWriter, _config: &BackupsConfig) -> Self::Writer {
+        output
+    }
+}
+
+pub struct Gzip<const LEVEL: u32>;
+
+impl<const LEVEL: u32> TarEncoding for Gzip<LEVEL> {
     const NAME: &'static str = "targz";
+    type Writer = GzipEncoder<OutputWriter>;
 
-    #[tracing::instrument]
-    async fn new(path: PathBuf) -> Result<Box<Self>> {
-        let compressor = GzipEncoder::with_quality(
-            File::create(&path).await.wrap_err("Failed to open file")?,
-            Level::Precise(LEVEL),
-        );
-        let writer = tokio_tar::Builder::new(compressor);
-        Ok(Box::new(Self { writer, path }))
+    fn encoder(output: OutputWriter, _config: &BackupsConfig) -> Self::Writer {
+        GzipEncoder::with_quality(output, Level::Precise(LEVEL))
     }
+}
 
-    #[tracing::instrument(skip(self))]
-    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<f64> {
-        let mut file = File::open(path).await.wrap_err("Failed to open file")?;
-        self.writer
-            .append_file(relative_path, &mut file)
-            .await
-            .wrap_err("Failed to compress file")?;
-        let size = file
-            .metadata()
-            .await
-            .map(|m| m.len() as f64)
-            .unwrap_or(f64::NAN);
-        Ok(size)
+pub struct Zstd<const LEVEL: u32>;
+
+impl<const LEVEL: u32> TarEncoding for Zstd<LEVEL> {
+    const NAME: &'static str = "tarzstd";
+    type Writer = ZstdEncoder<OutputWriter>;
+
+    fn encoder(output: OutputWriter, config: &BackupsConfig) -> Self::Writer {
+        let level = config.compression_level.unwrap_or(LEVEL);
+        ZstdEncoder::with_quality(output, Level::Precise(level))
     }
+}
 
-    #[tracing::instrument(skip(self))]
-    async fn finish(mut self) -> Result<f64> {
-        self.writer
-            .finish()
-            .await
-            .wrap_err("Failed to compress files")?;
-        drop(self.writer);
-        let output_size = tokio::fs::metadata(self.path)
-            .await
-            .map(|m| m.len() as f64)
-            .unwrap_or(f64::NAN);
-        Ok(output_size)
+pub struct Xz<const LEVEL: u32>;
+
+impl<const LEVEL: u32> TarEncoding for Xz<LEVEL> {
+    const NAME: &'static str = "tarxz";
+    type Writer = XzEncoder<OutputWriter>;
+
+    fn encoder(output: OutputWriter, config: &BackupsConfig) -> Self::Writer {
+        let level = config.compression_level.unwrap_or(LEVEL);
+        XzEncoder::with_quality(output, Level::Precise(level))
     }
 }
 
-pub struct TarCompressor {
-    writer: tokio_tar::Builder<File>,
+pub struct Bz2<const LEVEL: u32>;
+
+impl<const LEVEL: u32> TarEncoding for Bz2<LEVEL> {
+    const NAME: &'static str = "tarbz2";
+    type Writer = BzEncoder<OutputWriter>;
+
+    fn encoder(output: OutputWriter, config: &BackupsConfig) -> Self::Writer {
+        let level = config.compression_level.unwrap_or(LEVEL);
+        BzEncoder::with_quality(output, Level::Precise(level))
+    }
+}
+
+pub type TarGzCompressor<const LEVEL: u32> = TarCompressor<Gzip<LEVEL>>;
+pub type TarZstdCompressor<const LEVEL: u32> = TarCompressor<Zstd<LEVEL>>;
+pub type TarXzCompressor<const LEVEL: u32> = TarCompressor<Xz<LEVEL>>;
+pub type TarBz2Compressor<const LEVEL: u32> = TarCompressor<Bz2<LEVEL>>;
+
+pub struct TarCompressor<E: TarEncoding = Uncompressed> {
+    writer: tokio_tar::Builder<FlushingWriter<E::Writer>>,
     path: PathBuf,
+    s3: Option<S3Config>,
+    /// Hashes of added files, if deduplicating
+    dedup: Option<DuplicateTracker>,
+}
+
+/// Writer flushing its inner writer after every `threshold` bytes, also in the middle
+/// of a large file. The flush happens before the write that follows crossing it.
+struct FlushingWriter<W> {
+    inner: W,
+    threshold: Option<u64>,
+    unflushed: u64,
+}
+
+impl<W> FlushingWriter<W> {
+    fn new(inner: W, threshold: Option<u64>) -> Self {
+        Self {
+            inner,
+            threshold,
+            unflushed: 0,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FlushingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if matches!(this.threshold, Some(threshold) if this.unflushed >= threshold) {
+            ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+            this.unflushed = 0;
+        }
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.unflushed += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_flush(cx))?;
+        this.unflushed = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
 }
 
 #[async_trait]
-impl Compressor for TarCompressor {
-    const NAME: &'static str = "tar";
+impl<E: TarEncoding> Compressor for TarCompressor<E> {
+    const NAME: &'static str = E::NAME;
 
-    #[tracing::instrument]
-    async fn new(path: PathBuf) -> Result<Box<Self>> {
-        let writer =
-            tokio_tar::Builder::new(File::create(&path).await.wrap_err("Failed to open file")?);
-        Ok(Box::new(Self { writer, path }))
+    #[tracing::instrument(skip(config))]
+    async fn new(path: PathBuf, config: &BackupsConfig) -> Result<Box<Self>> {
+        let encoder = E::encoder(output::create(&path, config).await?, config);
+        let writer = tokio_tar::Builder::new(FlushingWriter::new(encoder, config.flush_threshold));
+        Ok(Box::new(Self {
+            writer,
+            path,
+            s3: config.s3.clone(),
+            dedup: config.dedup.then(DuplicateTracker::default),
+        }))
     }
 
     #[tracing::instrument(skip(self))]
-    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<f64> {
-        let mut file = File::open(path).await.wrap_err("Failed to open file")?;
-        self.writer
-            .append_file(relative_path, &mut file)
-            .await
-            .wrap_err("Failed to compress file")?;
-        let size = file
-            .metadata()
-            .await
-            .map(|m| m.len() as f64)
-            .unwrap_or(f64::NAN);
-        Ok(size)
+    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<Option<u64>> {
+        append_tar_file(&mut self.writer, self.dedup.as_mut(), path, relative_path).await
     }
 
     #[tracing::instrument(skip(self))]
-    async fn finish(mut self) -> Result<f64> {
-        self.writer
-            .finish()
+    async fn add_symlink(
+        &mut self,
+        path: &Path,
+        relative_path: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        append_tar_symlink(&mut self.writer, path, relative_path, target).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_metadata(&mut self, metadata: &BackupMetadata) -> Result<()> {
+        append_tar_metadata(&mut self.writer, metadata).await
+    }
+
+    fn duplicates(&self) -> Option<&DuplicateTracker> {
+        self.dedup.as_ref()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn finish(mut self) -> Result<Option<u64>> {
+        let mut output = self
+            .writer
+            .into_inner()
             .await
             .wrap_err("Failed to compress files")?;
-        drop(self.writer);
-        let output_size = tokio::fs::metadata(self.path)
+        output
+            .shutdown()
             .await
-            .map(|m| m.len() as f64)
-            .unwrap_or(f64::NAN);
-        Ok(output_size)
+            .wrap_err("Failed to flush output file")?;
+        Ok(output::size(&self.path, self.s3.as_ref()).await)
     }
 }
 
@@ -171,9 +453,16 @@ pub struct CopyCompressor {
 #[async_trait]
 impl Compressor for CopyCompressor {
     const NAME: &'static str = "copy";
+    const PREFETCH: bool = true;
 
-    #[tracing::instrument]
-    async fn new(path: PathBuf) -> Result<Box<Self>> {
+    #[tracing::instrument(skip(config))]
+    async fn new(path: PathBuf, config: &BackupsConfig) -> Result<Box<Self>> {
+        if config.encryption.is_some() {
+            bail!("Copy backups can't be encrypted");
+        }
+        if output::is_remote(&path) {
+            bail!("Copy backups can't be uploaded");
+        }
         if path.exists() {
             bail!("Output path already exists");
         }
@@ -184,7 +473,7 @@ impl Compressor for CopyCompressor {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<f64> {
+    async fn add_file(&mut self, path: &Path, relative_path: &Path) -> Result<Option<u64>> {
         let output_path = self.path.join(relative_path);
         tokio::fs::create_dir_all(output_path.parent().wrap_err("Invalid path")?)
             .await
@@ -192,12 +481,212 @@ impl Compressor for CopyCompressor {
         let output = tokio::fs::copy(path, output_path)
             .await
             .wrap_err("Failed to copy file")?;
-        Ok(output as f64)
+        Ok(Some(output))
+    }
+
+    #[tracing::instrument(skip(self, metadata, data))]
+    async fn add_file_data(
+        &mut self,
+        relative_path: &Path,
+        metadata: &Metadata,
+        data: Vec<u8>,
+    ) -> Result<Option<u64>> {
+        let output_path = self.path.join(relative_path);
+        tokio::fs::create_dir_all(output_path.parent().wrap_err("Invalid path")?)
+            .await
+            .wrap_err("Failed to create directory")?;
+        tokio::fs::write(&output_path, &data)
+            .await
+            .wrap_err("Failed to copy file")?;
+        tokio::fs::set_permissions(&output_path, metadata.permissions())
+            .await
+            .wrap_err("Failed to copy permissions")?;
+        Ok(Some(data.len() as u64))
     }
 
     #[tracing::instrument(skip(self))]
-    async fn finish(self) -> Result<f64> {
+    async fn add_symlink(
+        &mut self,
+        _path: &Path,
+        relative_path: &Path,
+        target: &Path,
+    ) -> Result<()> {
+        let output_path = self.path.join(relative_path);
+        tokio::fs::create_dir_all(output_path.parent().wrap_err("Invalid path")?)
+            .await
+            .wrap_err("Failed to create directory")?;
+        tokio::fs::symlink(target, output_path)
+            .await
+            .wrap_err("Failed to create symlink")?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn finish(self) -> Result<Option<u64>> {
         let size = fs_extra::dir::get_size(self.path);
-        Ok(size.map(|r| r as f64).unwrap_or(f64::NAN))
+        Ok(size.ok())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::backup_manager::restore;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use std::fs::Permissions;
+    use tokio::io::BufReader;
+
+    /// Empty directory for a test, removed first if a previous run left it behind
+    pub(in crate::backup_manager) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dolorous-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Backup of all files in `location`, written to `output`
+    pub(in crate::backup_manager) fn test_config(location: &Path, output: &Path) -> BackupsConfig {
+        let yaml = format!(
+            "output: {}\nlocation: {}\nfiles: ['**/*']\n",
+            output.display(),
+            location.display()
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    /// An executable script in a new source directory
+    fn executable(dir: &Path) -> PathBuf {
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        let script = source.join("start.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+        script
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test]
+    async fn tar_gz_keeps_executable_mode() {
+        let dir = test_dir("tar-gz-mode");
+        let script = executable(&dir);
+        let archive = dir.join("backup.tar.gz");
+        let config = test_config(&dir.join("source"), &dir);
+        let mut compressor = TarGzCompressor::<6>::new(archive.clone(), &config)
+            .await
+            .unwrap();
+        compressor
+            .add_file(&script, Path::new("start.sh"))
+            .await
+            .unwrap();
+        compressor.finish().await.unwrap();
+
+        let restored = dir.join("restored");
+        let reader = BufReader::new(File::open(&archive).await.unwrap());
+        restore::extract_tar(GzipDecoder::new(reader), &restored, None)
+            .await
+            .unwrap();
+        assert_eq!(mode(&restored.join("start.sh")), 0o755);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn zip_keeps_executable_mode() {
+        let dir = test_dir("zip-mode");
+        let script = executable(&dir);
+        let archive = dir.join("backup.zip");
+        let config = test_config(&dir.join("source"), &dir);
+        let mut compressor = ZipCompressor::new(archive.clone(), &config).await.unwrap();
+        compressor
+            .add_file(&script, Path::new("start.sh"))
+            .await
+            .unwrap();
+        compressor.finish().await.unwrap();
+
+        let restored = dir.join("restored");
+        restore::extract_zip(&archive, &restored, None)
+            .await
+            .unwrap();
+        assert_eq!(mode(&restored.join("start.sh")), 0o755);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deduplicated_files_are_restored_as_copies() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir("dedup");
+        let source = dir.join("source");
+        std::fs::create_dir(&source).unwrap();
+        for name in ["r.0.0.mca", "r.0.1.mca"] {
+            std::fs::write(source.join(name), "region").unwrap();
+        }
+        let archive = dir.join("backup.tar");
+        let mut config = test_config(&source, &dir);
+        config.dedup = true;
+        let mut compressor = TarCompressor::<Uncompressed>::new(archive.clone(), &config)
+            .await
+            .unwrap();
+        for name in ["r.0.0.mca", "r.0.1.mca"] {
+            compressor
+                .add_file(&source.join(name), Path::new(name))
+                .await
+                .unwrap();
+        }
+        assert_eq!(compressor.duplicates().unwrap().duplicate_files, 1);
+        compressor.finish().await.unwrap();
+
+        let restored = dir.join("restored");
+        restore::extract_tar(File::open(&archive).await.unwrap(), &restored, None)
+            .await
+            .unwrap();
+        let first = std::fs::metadata(restored.join("r.0.0.mca")).unwrap();
+        let second = std::fs::metadata(restored.join("r.0.1.mca")).unwrap();
+        assert_ne!(first.ino(), second.ino());
+        assert_eq!(
+            std::fs::read(restored.join("r.0.1.mca")).unwrap(),
+            b"region"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Counts the bytes written to it and how often it was flushed
+    #[derive(Default)]
+    struct FlushCounter {
+        written: usize,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for FlushCounter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.get_mut().written += buf.len();
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.get_mut().flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn large_file_is_flushed_while_copying() {
+        let mut writer = FlushingWriter::new(FlushCounter::default(), Some(1024));
+        // One file, written in chunks like a tar entry
+        for chunk in [0u8; 10 * 1024].chunks(512) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        assert_eq!(writer.inner.written, 10 * 1024);
+        assert_eq!(writer.inner.flushes, 9);
     }
 }
@@ -0,0 +1,69 @@
+use super::{progress, ArchiveSize};
+use crate::configs::{BackupFileType, BackupsConfig};
+use chrono::Local;
+use color_eyre::Result;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+/// Machine readable result of a backup
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct BackupEvent<'a> {
+    backup: &'a str,
+    file_type: &'a BackupFileType,
+    output: &'a Path,
+    /// RFC 3339 time the backup finished at
+    finished: String,
+    original_size: Option<u64>,
+    compressed_size: Option<u64>,
+    duration_ms: u64,
+    success: bool,
+    error: Option<String>,
+}
+
+/// Publishes the result of a backup to socket subscribers and the event file, if configured
+pub async fn emit(
+    backup: &str,
+    backup_config: &BackupsConfig,
+    output_path: &Path,
+    duration: Duration,
+    result: &Result<ArchiveSize>,
+) {
+    let event = BackupEvent {
+        backup,
+        file_type: &backup_config.file_type,
+        output: output_path,
+        finished: Local::now().to_rfc3339(),
+        original_size: result.as_ref().ok().map(|size| size.original),
+        compressed_size: result.as_ref().ok().and_then(|size| size.compressed),
+        duration_ms: duration.as_millis() as u64,
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|err| format!("{err:#}")),
+    };
+    let json = match serde_json::to_string(&event) {
+        Ok(json) => json,
+        Err(err) => {
+            warn!(?err, "Failed to serialize backup event");
+            return;
+        }
+    };
+    progress::publish_result(&json);
+    if let Some(path) = &backup_config.event_file {
+        if let Err(err) = append(path, &json).await {
+            warn!(?err, "Failed to write backup event to {}", path.display());
+        }
+    }
+}
+
+async fn append(path: &Path, json: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{json}\n").as_bytes()).await
+}
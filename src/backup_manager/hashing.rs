@@ -0,0 +1,116 @@
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+
+pub type FileHash = [u8; 32];
+
+/// Hashes of finished outputs, by output path
+static CHECKSUMS: Mutex<BTreeMap<PathBuf, FileHash>> = Mutex::new(BTreeMap::new());
+
+/// Computes the SHA-256 of a file's contents
+pub async fn hash_file(path: &Path) -> Result<FileHash> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path).wrap_err("Failed to open file")?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buffer).wrap_err("Failed to read file")?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        Ok(hasher.finalize().into())
+    })
+    .await?
+}
+
+/// Formats a hash as lowercase hex
+pub fn to_hex(hash: &FileHash) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Keeps track of files with identical contents
+#[derive(Default)]
+pub struct DuplicateTracker {
+    seen: HashMap<FileHash, PathBuf>,
+    pub duplicate_files: u64,
+    pub duplicate_bytes: u64,
+}
+
+impl DuplicateTracker {
+    /// Records a file. Returns the first file with the same contents, if any.
+    pub fn record(&mut self, hash: FileHash, relative_path: &Path, size: u64) -> Option<&Path> {
+        if self.seen.contains_key(&hash) {
+            self.duplicate_files += 1;
+            self.duplicate_bytes += size;
+        } else {
+            self.seen.insert(hash, relative_path.to_path_buf());
+        }
+        self.seen
+            .get(&hash)
+            .map(PathBuf::as_path)
+            .filter(|first| *first != relative_path)
+    }
+}
+
+/// Returns the hash of everything written to the output, once it's shut down
+pub fn take_checksum(path: &Path) -> Option<FileHash> {
+    CHECKSUMS.lock().remove(path)
+}
+
+/// Writer hashing everything written through it. The hash is available
+/// through [`take_checksum`] after shutdown.
+pub struct HashingWriter<W> {
+    inner: W,
+    path: PathBuf,
+    hasher: Option<Sha256>,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W, path: PathBuf) -> Self {
+        Self {
+            inner,
+            path,
+            hasher: Some(Sha256::new()),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if let Some(hasher) = &mut this.hasher {
+            hasher.update(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_shutdown(cx))?;
+        if let Some(hasher) = this.hasher.take() {
+            CHECKSUMS
+                .lock()
+                .insert(this.path.clone(), hasher.finalize().into());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
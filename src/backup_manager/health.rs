@@ -0,0 +1,63 @@
+use crate::configs::{ActionType, DolorousConfig};
+use chrono::{DateTime, Local};
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use tokio::sync::OnceCell;
+
+/// Time of the last successful run of each backup
+pub static LAST_SUCCESS: Mutex<BTreeMap<String, DateTime<Local>>> = Mutex::new(BTreeMap::new());
+/// Backups are considered fresh until their interval passes after startup
+static STARTED_AT: OnceCell<DateTime<Local>> = OnceCell::const_new();
+
+pub fn init() {
+    let _ = STARTED_AT.set(Local::now());
+}
+
+pub fn record_success(backup: &str) {
+    LAST_SUCCESS.lock().insert(backup.to_string(), Local::now());
+}
+
+/// Returns a description of every scheduled backup that didn't succeed within its interval
+pub fn stale_backups(config: &DolorousConfig) -> Vec<String> {
+    let now = Local::now();
+    let started_at = STARTED_AT.get().copied().unwrap_or(now);
+    let last_success = LAST_SUCCESS.lock().clone();
+    let grace = chrono::Duration::from_std(config.backup_grace)
+        .unwrap_or_else(|_| chrono::Duration::zero());
+
+    let mut stale = Vec::new();
+    for backup in config.backups.keys() {
+        let Some(interval) = backup_interval(config, backup) else {
+            continue;
+        };
+        let since = last_success.get(backup).copied().unwrap_or(started_at);
+        if now - since > interval + grace {
+            let last = match last_success.get(backup) {
+                Some(time) => time.to_rfc3339(),
+                None => "never".into(),
+            };
+            stale.push(format!("{backup} (last success: {last})"));
+        }
+    }
+    stale
+}
+
+/// Shortest interval between runs of any task that runs the backup
+fn backup_interval(config: &DolorousConfig, backup: &str) -> Option<chrono::Duration> {
+    config
+        .tasks
+        .values()
+        .filter(|task| runs_backup(&task.actions, backup))
+        .filter_map(|task| crate::tasks::TaskSchedule::new(task).ok()?.interval())
+        .min()
+}
+
+fn runs_backup(actions: &[ActionType], backup: &str) -> bool {
+    actions.iter().any(|action| match action {
+        ActionType::Backup { backup: name, .. } => name == backup,
+        ActionType::If { then, r#else, .. } => {
+            runs_backup(then, backup) || runs_backup(r#else, backup)
+        }
+        _ => false,
+    })
+}
@@ -0,0 +1,177 @@
+use super::{output, retention, sidecar_path};
+use crate::configs::BackupsConfig;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+/// Extension of the manifest written next to incremental backups
+pub const MANIFEST_EXTENSION: &str = "manifest.json";
+
+/// Files making up a snapshot, and which archive each of them is stored in
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Manifest {
+    /// Archives the snapshot is stored in, oldest first. The last one is the archive itself.
+    pub chain: Vec<String>,
+    pub files: BTreeMap<PathBuf, FileEntry>,
+    /// Files of the previous snapshot, to compare against
+    #[serde(skip)]
+    previous: BTreeMap<PathBuf, FileEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileEntry {
+    pub size: u64,
+    /// Modification time, in milliseconds since the unix epoch
+    pub modified: u64,
+    /// Name of the archive containing this version of the file
+    pub archive: String,
+}
+
+impl Manifest {
+    /// Starts the manifest of a new archive, based on the previous snapshot if there is one
+    pub fn new(archive: String, previous: Option<Manifest>) -> Self {
+        let (mut chain, previous) = match previous {
+            Some(previous) => (previous.chain, previous.files),
+            None => {
+                info!("No previous manifest, creating a full backup");
+                (Vec::new(), BTreeMap::new())
+            }
+        };
+        chain.push(archive);
+        Self {
+            chain,
+            files: BTreeMap::new(),
+            previous,
+        }
+    }
+
+    fn archive(&self) -> &str {
+        self.chain.last().map(String::as_str).unwrap_or_default()
+    }
+
+    /// Records a file. Returns whether it changed since the previous snapshot and needs to be archived.
+    pub fn record(&mut self, relative_path: &Path, metadata: &Metadata) -> bool {
+        let size = metadata.len();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default();
+        let entry = match self.previous.get(relative_path) {
+            Some(previous) if previous.size == size && previous.modified == modified => {
+                previous.clone()
+            }
+            _ => FileEntry {
+                size,
+                modified,
+                archive: self.archive().to_string(),
+            },
+        };
+        let changed = entry.archive == self.archive();
+        self.files.insert(relative_path.to_path_buf(), entry);
+        changed
+    }
+
+    /// Drops archives no file refers to anymore from the chain
+    fn trim_chain(&mut self) {
+        let files = &self.files;
+        let current = self.archive().to_string();
+        self.chain
+            .retain(|archive| *archive == current || files.values().any(|f| f.archive == *archive));
+    }
+
+    /// Files of the snapshot, grouped by the archive they are stored in, oldest archive first
+    pub fn snapshot(&self) -> Vec<(&str, Vec<&Path>)> {
+        self.chain
+            .iter()
+            .map(|archive| {
+                let files = self
+                    .files
+                    .iter()
+                    .filter(|(_, entry)| entry.archive == *archive)
+                    .map(|(path, _)| path.as_path())
+                    .collect();
+                (archive.as_str(), files)
+            })
+            .collect()
+    }
+}
+
+/// Loads the manifest of the newest backup, if it has one
+pub async fn previous_manifest(backup_config: &BackupsConfig) -> Option<Manifest> {
+    if output::is_remote(&backup_config.output) {
+        warn!("Incremental backups need a local output, creating a full backup");
+        return None;
+    }
+    let latest = match retention::latest(backup_config, None).await {
+        Ok(latest) => latest?,
+        Err(err) => {
+            warn!(?err, "Failed to find previous backup");
+            return None;
+        }
+    };
+    match read_manifest(&latest).await {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            warn!(?err, "Failed to read previous manifest");
+            None
+        }
+    }
+}
+
+/// Reads the manifest next to an archive, if there is one
+pub async fn read_manifest(archive: &Path) -> Result<Option<Manifest>> {
+    let path = sidecar_path(archive, MANIFEST_EXTENSION);
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err("Failed to read manifest"),
+    };
+    let manifest = serde_json::from_slice(&data).wrap_err("Invalid manifest")?;
+    Ok(Some(manifest))
+}
+
+/// Writes the manifest next to the backup
+pub async fn write_manifest(
+    output_path: &Path,
+    mut manifest: Manifest,
+    backup_config: &BackupsConfig,
+) -> Result<()> {
+    manifest.trim_chain();
+    let path = sidecar_path(output_path, MANIFEST_EXTENSION);
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    let mut writer = output::open(&path, backup_config).await?;
+    writer.write_all(&data).await?;
+    writer.shutdown().await?;
+    debug!(
+        "Wrote manifest with {} files in {} archives",
+        manifest.files.len(),
+        manifest.chain.len()
+    );
+    Ok(())
+}
+
+/// Names of the archives the newest snapshot still depends on
+pub async fn referenced_archives(backup_config: &BackupsConfig) -> Result<Vec<String>> {
+    let Some(latest) = retention::latest(backup_config, None).await? else {
+        return Ok(Vec::new());
+    };
+    let manifest = read_manifest(&latest).await?;
+    Ok(manifest.map(|m| m.chain).unwrap_or_default())
+}
+
+/// Name of an archive, as stored in manifests
+pub fn archive_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| eyre!("Invalid backup name"))
+}
@@ -1,169 +1,822 @@
-use self::compressor::{Compressor, CopyCompressor, TarCompressor, TarGzCompressor, ZipCompressor};
-use crate::configs::{BackupFileType, DolorousConfig};
+use self::compressor::{
+    Compressor, CopyCompressor, TarBz2Compressor, TarCompressor, TarGzCompressor, TarXzCompressor,
+    TarZstdCompressor, ZipCompressor,
+};
+use self::hashing::DuplicateTracker;
+use self::incremental::Manifest;
+use crate::configs::{
+    BackupFileType, BackupMode, BackupsConfig, DolorousConfig, NotificationEvent, SymlinkMode,
+};
+use crate::notifications::{notify, Notification};
 use chrono::Local;
 use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
-use globwalk::GlobWalkerBuilder;
+use futures::stream::{self, StreamExt};
+use globwalk::{DirEntry, GlobWalkerBuilder};
 use new_string_template::template::Template;
+use parking_lot::Mutex;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::fs::Metadata;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Instant;
-use tracing::{debug, info, info_span, Instrument};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, info_span, warn, Instrument};
 
 mod compressor;
+mod events;
+mod hashing;
+pub mod health;
+mod incremental;
+mod output;
+pub mod progress;
+pub mod restore;
+mod retention;
+mod s3;
+mod sftp;
+mod split;
+mod staging;
+mod verify;
+
+/// Files up to this size are read ahead by the workers, larger ones are streamed
+const PREFETCH_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// Limit and semaphore for concurrently running backups
+static BACKUP_SLOTS: Mutex<Option<(usize, Arc<Semaphore>)>> = Mutex::new(None);
+/// Serializes updates of the persisted `{counter}` files
+static COUNTER_LOCK: Mutex<()> = Mutex::new(());
 
 #[tracing::instrument(skip(config))]
-pub async fn run_backup(config: &DolorousConfig, backup: &str) -> Result<PathBuf> {
+pub async fn run_backup(
+    config: &DolorousConfig,
+    backup: &str,
+    tag: Option<&str>,
+) -> Result<PathBuf> {
     let backup_config = config
         .backups
         .get(backup)
         .ok_or_else(|| eyre!("Undefined backup: {}", backup))?;
-    let name = render_name(
-        &backup_config.name,
-        &backup_config.time_format,
-        &backup_config.file_type,
-    )?;
+    let _permit = match config.max_concurrent_backups {
+        Some(limit) => Some(acquire_slot(limit).await?),
+        None => None,
+    };
+    let name = render_name(backup, backup_config, tag).await?;
     let file_path = backup_config.output.as_path().join(&name);
 
-    match &backup_config.file_type {
+    let post_hook = PostHook {
+        command: backup_config.post_command.as_deref(),
+        process: backup_config.process.as_deref(),
+    };
+    if let Some(command) = &backup_config.pre_command {
+        if send_hook(command, backup_config.process.as_deref()) {
+            tokio::time::sleep(backup_config.pre_command_delay).await;
+        }
+    }
+    let result = match &backup_config.file_type {
         BackupFileType::Zip => {
-            create_backup_wrapped::<ZipCompressor>(
-                &backup_config.location,
-                file_path.clone(),
-                &backup_config.files,
-            )
-            .await?
+            create_backup_wrapped::<ZipCompressor>(backup, backup_config, file_path.clone()).await
         }
         BackupFileType::TarGz => {
-            create_backup_wrapped::<TarGzCompressor<6>>(
-                &backup_config.location,
-                file_path.clone(),
-                &backup_config.files,
-            )
-            .await?
+            create_backup_wrapped::<TarGzCompressor<6>>(backup, backup_config, file_path.clone())
+                .await
         }
         BackupFileType::TarGzFast => {
-            create_backup_wrapped::<TarGzCompressor<1>>(
-                &backup_config.location,
-                file_path.clone(),
-                &backup_config.files,
-            )
-            .await?
+            create_backup_wrapped::<TarGzCompressor<1>>(backup, backup_config, file_path.clone())
+                .await
         }
         BackupFileType::TarGzSmall => {
-            create_backup_wrapped::<TarGzCompressor<9>>(
-                &backup_config.location,
-                file_path.clone(),
-                &backup_config.files,
-            )
-            .await?
+            create_backup_wrapped::<TarGzCompressor<9>>(backup, backup_config, file_path.clone())
+                .await
+        }
+        BackupFileType::TarZstd => {
+            create_backup_wrapped::<TarZstdCompressor<3>>(backup, backup_config, file_path.clone())
+                .await
+        }
+        BackupFileType::TarZstdFast => {
+            create_backup_wrapped::<TarZstdCompressor<1>>(backup, backup_config, file_path.clone())
+                .await
+        }
+        BackupFileType::TarZstdSmall => {
+            create_backup_wrapped::<TarZstdCompressor<19>>(backup, backup_config, file_path.clone())
+                .await
+        }
+        BackupFileType::TarXz => {
+            create_backup_wrapped::<TarXzCompressor<6>>(backup, backup_config, file_path.clone())
+                .await
+        }
+        BackupFileType::TarBz2 => {
+            create_backup_wrapped::<TarBz2Compressor<9>>(backup, backup_config, file_path.clone())
+                .await
         }
         BackupFileType::Tar => {
-            create_backup_wrapped::<TarCompressor>(
-                &backup_config.location,
-                file_path.clone(),
-                &backup_config.files,
-            )
-            .await?
+            create_backup_wrapped::<TarCompressor>(backup, backup_config, file_path.clone()).await
         }
         BackupFileType::Copy => {
-            create_backup_wrapped::<CopyCompressor>(
-                &backup_config.location,
-                file_path.clone(),
-                &backup_config.files,
-            )
-            .await?
+            create_backup_wrapped::<CopyCompressor>(backup, backup_config, file_path.clone()).await
         }
     };
+    drop(post_hook);
+    result?;
+    health::record_success(backup);
+    if let Err(err) = retention::prune(backup_config).await {
+        warn!(?err, "Failed to prune old backups");
+    }
 
     Ok(file_path)
 }
 
+/// Waits until fewer than `limit` backups are running
+async fn acquire_slot(limit: usize) -> Result<OwnedSemaphorePermit> {
+    let slots = {
+        let mut slots = BACKUP_SLOTS.lock();
+        match &*slots {
+            Some((current, semaphore)) if *current == limit => semaphore.clone(),
+            // The limit changed on reload, running backups keep their old permits
+            _ => {
+                let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+                *slots = Some((limit, semaphore.clone()));
+                semaphore
+            }
+        }
+    };
+    if let Ok(permit) = slots.clone().try_acquire_owned() {
+        return Ok(permit);
+    }
+    info!("Waiting for a free backup slot ({} running)", limit);
+    Ok(slots.acquire_owned().await?)
+}
+
+/// Sends the post command once dropped. Runs even if the backup failed or was cancelled,
+/// e.g. to turn saving back on.
+struct PostHook<'a> {
+    command: Option<&'a str>,
+    process: Option<&'a str>,
+}
+
+impl Drop for PostHook<'_> {
+    fn drop(&mut self) {
+        if let Some(command) = self.command {
+            send_hook(command, self.process);
+        }
+    }
+}
+
+/// Removes a partially written archive if the backup is cancelled, e.g. by a task timeout
+struct PartialArchive<'a>(Option<&'a Path>);
+
+impl<'a> PartialArchive<'a> {
+    fn new(path: &'a Path) -> Self {
+        Self(Some(path))
+    }
+
+    /// Writing finished or failed, cleanup is up to the caller from here on
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for PartialArchive<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.0 {
+            warn!("Backup cancelled, removing {}", path.display());
+            staging::remove_now(path);
+        }
+    }
+}
+
+/// Sends a backup hook command to the process, if it's running. Returns whether it was sent.
+fn send_hook(command: &str, process: Option<&str>) -> bool {
+    let sender = match crate::process::get(process) {
+        Ok(process) => process.stdin.lock().clone(),
+        Err(err) => {
+            warn!(?err, "Failed to send backup hook");
+            return false;
+        }
+    };
+    let Some(sender) = sender else {
+        debug!("Process not running, skipping backup hook {:?}", command);
+        return false;
+    };
+    info!("Sending backup hook: {}", command);
+    if sender
+        .send(crate::process::StdinData::Line(command.to_string()))
+        .is_err()
+    {
+        warn!("Failed to send backup hook, stdin closed");
+        return false;
+    }
+    true
+}
+
 async fn create_backup_wrapped<C: Compressor>(
-    base_path: &Path,
+    backup: &str,
+    backup_config: &BackupsConfig,
     output_path: PathBuf,
-    globs: &[String],
 ) -> Result<()> {
     let outp = output_path.clone();
-    create_backup::<C>(base_path, output_path, globs)
+    let base_path = &backup_config.location;
+    let start = Instant::now();
+    let result = create_backup::<C>(backup, backup_config, output_path)
         .instrument(info_span!(
             "create_backup",
             backup_type = C::NAME,
             output = ?outp,
             ?base_path,
         ))
-        .await
+        .await;
+    events::emit(backup, backup_config, &outp, start.elapsed(), &result).await;
+    match &result {
+        Ok(size) => crate::telemetry::backup_succeeded(backup, start.elapsed(), size.compressed),
+        Err(_) => crate::telemetry::backup_failed(backup),
+    }
+    let (event, message) = match &result {
+        Ok(_) => (
+            NotificationEvent::BackupSuccess,
+            format!("Backup {backup} created at {}", outp.display()),
+        ),
+        Err(err) => (
+            NotificationEvent::BackupFailure,
+            format!("Backup {backup} failed: {err:#}"),
+        ),
+    };
+    notify(Notification::new(
+        event,
+        backup_config.process.as_deref(),
+        message,
+    ));
+    result.map(|_| ())
 }
 
 async fn create_backup<C: Compressor>(
-    base_path: &Path,
+    backup: &str,
+    backup_config: &BackupsConfig,
     output_path: PathBuf,
-    globs: &[String],
-) -> Result<()> {
+) -> Result<ArchiveSize> {
     info!("Starting backup...");
-    if output_path.exists() {
+    if output_path.exists() || split::part_path(&output_path, 1).exists() {
         bail!("Output path already exists");
     }
+    if backup_config.split_size.is_some()
+        && (backup_config.staging_dir.is_some() || output::is_remote(&output_path))
+    {
+        bail!("Split backups can't be staged or uploaded to S3");
+    }
     let start = Instant::now();
+    let files = walk_files(backup_config)?;
+    if backup_config.check_free_space {
+        check_free_space(backup_config, &output_path, &files)?;
+    }
 
-    let mut compressor = C::new(output_path).await?;
-    for file in GlobWalkerBuilder::from_patterns(base_path, globs)
-        .follow_links(true)
-        .build()
-        .wrap_err("Failed to create glob walker!")?
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let size = compressor
-            .add_file(
-                file.path(),
-                file.path()
-                    .strip_prefix(base_path)
-                    .wrap_err("File outside base path!")?,
+    let mut manifest = match backup_config.backup_mode {
+        BackupMode::Full => None,
+        BackupMode::Incremental => Some(Manifest::new(
+            incremental::archive_name(&output_path)?,
+            incremental::previous_manifest(backup_config).await,
+        )),
+    };
+
+    let (size, checksum) = match &backup_config.staging_dir {
+        Some(staging_dir) => {
+            let staged = staging::staged_path(staging_dir, &output_path)?;
+            if staged.exists() {
+                bail!("Staged backup already exists");
+            }
+            let partial = PartialArchive::new(&staged);
+            let result =
+                write_archive::<C>(backup, backup_config, &staged, files, manifest.as_mut()).await;
+            partial.disarm();
+            let checksum = hashing::take_checksum(&staged);
+            if result.is_err() {
+                staging::remove(&staged).await;
+            }
+            let size = result?;
+            staging::move_to_output(&staged, &output_path, backup_config).await?;
+            (size, checksum)
+        }
+        None => {
+            let partial =
+                (!output::is_remote(&output_path)).then(|| PartialArchive::new(&output_path));
+            let result = write_archive::<C>(
+                backup,
+                backup_config,
+                &output_path,
+                files,
+                manifest.as_mut(),
             )
-            .await?;
-        let human_size = if size.is_nan() {
-            "unknown".into()
+            .await;
+            if let Some(partial) = partial {
+                partial.disarm();
+            }
+            let checksum = hashing::take_checksum(&output_path);
+            if result.is_err() && !output::is_remote(&output_path) {
+                staging::remove(&output_path).await;
+            }
+            (result?, checksum)
+        }
+    };
+    if backup_config.checksum {
+        match checksum {
+            Some(checksum) => write_checksum(&output_path, &checksum, backup_config)
+                .await
+                .wrap_err("Failed to write checksum")?,
+            None => warn!("Checksums are not supported for {} backups", C::NAME),
+        }
+    }
+    if let Some(manifest) = manifest {
+        incremental::write_manifest(&output_path, manifest, backup_config)
+            .await
+            .wrap_err("Failed to write manifest")?;
+    }
+    if backup_config.durable && !output::is_remote(&output_path) {
+        sync_output(output_path.clone())
+            .await
+            .wrap_err("Failed to sync backup to disk")?;
+    }
+    let elapsed = humantime::format_duration(start.elapsed());
+    info!(
+        "Backup complete! (size: {}, elapsed: {})",
+        format_size(size.compressed),
+        elapsed
+    );
+    if backup_config.split_size.is_some() {
+        let parts = split::parts(&output_path);
+        let names: Vec<_> = parts
+            .iter()
+            .filter_map(|part| part.file_name())
+            .map(|name| name.to_string_lossy())
+            .collect();
+        info!(
+            "Backup split into {} parts: {}",
+            parts.len(),
+            names.join(", ")
+        );
+    }
+    if let Some(sftp_config) = &backup_config.sftp {
+        if output::is_remote(&output_path) {
+            warn!("Backup output is already remote, skipping SFTP upload");
+        } else {
+            sftp::upload(sftp_config, &output_path)
+                .await
+                .wrap_err("Failed to upload backup")?;
+        }
+    }
+    Ok(size)
+}
+
+/// Files the backup would contain, relative to its location, with their sizes.
+/// Nothing is written.
+pub fn dry_run(config: &DolorousConfig, backup: &str) -> Result<Vec<(PathBuf, u64)>> {
+    let backup_config = config
+        .backups
+        .get(backup)
+        .ok_or_else(|| eyre!("Undefined backup: {}", backup))?;
+    let base_path = backup_config.location.as_path();
+    walk_files(backup_config)?
+        .into_iter()
+        .map(|file| {
+            let relative_path = file
+                .path()
+                .strip_prefix(base_path)
+                .wrap_err("File outside base path!")?
+                .to_path_buf();
+            let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            Ok((relative_path, size))
+        })
+        .collect()
+}
+
+/// Sizes of a written archive
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveSize {
+    /// Total size of the added files
+    pub original: u64,
+    /// Size of the archive, if known
+    pub compressed: Option<u64>,
+}
+
+/// Writes the files to an archive at the given path.
+/// With a manifest, only files changed since the previous snapshot are added.
+async fn write_archive<C: Compressor>(
+    backup: &str,
+    backup_config: &BackupsConfig,
+    output_path: &Path,
+    files: Vec<DirEntry>,
+    mut manifest: Option<&mut Manifest>,
+) -> Result<ArchiveSize> {
+    let base_path = backup_config.location.as_path();
+    let mut compressor = C::new(output_path.to_path_buf(), backup_config)
+        .await
+        .map_err(|err| crate::permissions::explain(err, output_path))?;
+    if backup_config.embed_metadata {
+        compressor
+            .add_metadata(&BackupMetadata::new(backup))
+            .await
+            .wrap_err("Failed to embed metadata")?;
+    }
+    let mut duplicates = DuplicateTracker::default();
+    let mut progress = progress::Tracker::start(backup, files.len() as u64);
+    let mut changed = Vec::with_capacity(files.len());
+    for file in files {
+        if let Some(manifest) = manifest.as_deref_mut() {
+            let relative_path = file
+                .path()
+                .strip_prefix(base_path)
+                .wrap_err("File outside base path!")?;
+            let metadata = file.metadata().wrap_err("Failed to read file metadata")?;
+            if !manifest.record(relative_path, &metadata) {
+                debug!("Unchanged since the previous backup: {:?}", relative_path);
+                progress.file_done(0);
+                continue;
+            }
+        }
+        changed.push(file);
+    }
+
+    // Files are read ahead in parallel, but added in order
+    let workers = backup_config.read_workers.max(1);
+    let mut pending = stream::iter(changed)
+        .map(|file| prefetch::<C>(file, workers > 1))
+        .buffered(workers);
+    let mut added = 0;
+    let mut original = 0;
+    // Deduplicating compressors hash every file already
+    let hashed = compressor.duplicates().is_some();
+    while let Some(pending_file) = pending.next().await {
+        let PendingFile { entry: file, data } = pending_file?;
+        let relative_path = file
+            .path()
+            .strip_prefix(base_path)
+            .wrap_err("File outside base path!")?;
+        let size = if file.path_is_symlink() && !file.file_type().is_file() {
+            let target = std::fs::read_link(file.path()).wrap_err("Failed to read symlink")?;
+            compressor
+                .add_symlink(file.path(), relative_path, &target)
+                .await?;
+            None
+        } else if let Some((metadata, data)) = data {
+            compressor
+                .add_file_data(relative_path, &metadata, data)
+                .await?
         } else {
-            human_bytes::human_bytes(size)
+            compressor.add_file(file.path(), relative_path).await?
         };
+        progress.file_done(size.unwrap_or(0));
+        added += 1;
+        original += size.unwrap_or(0);
+        if backup_config.report_duplicates && !hashed && size.is_some() {
+            let hash = hashing::hash_file(file.path()).await?;
+            if let Some(first) = duplicates.record(hash, relative_path, size.unwrap_or(0)) {
+                debug!("{:?} is a duplicate of {:?}", relative_path, first);
+            }
+        }
         debug!(
             "Compressed file {:?} (original size: {})",
             file.path(),
-            human_size
+            format_size(size)
         );
     }
-    let size = compressor.finish().await?;
-    let elapsed = humantime::format_duration(start.elapsed());
-    let human_size = if size.is_nan() {
-        "unknown".into()
-    } else {
-        human_bytes::human_bytes(size)
-    };
-    info!(
-        "Backup complete! (size: {}, elapsed: {})",
-        human_size, elapsed
+    let found = compressor.duplicates().unwrap_or(&duplicates);
+    if backup_config.report_duplicates && found.duplicate_files > 0 {
+        info!(
+            "Duplicate files: {} (size: {})",
+            found.duplicate_files,
+            format_size(Some(found.duplicate_bytes))
+        );
+    }
+    let compressed = compressor.finish().await?;
+    if backup_config.verify {
+        if output::is_remote(output_path) {
+            warn!("Uploaded backups can't be verified");
+        } else if backup_config.split_size.is_some() {
+            warn!("Split backups can't be verified");
+        } else {
+            verify::verify(backup_config, output_path, added).await?;
+        }
+    }
+    Ok(ArchiveSize {
+        original,
+        compressed,
+    })
+}
+
+/// A file to add, read into memory ahead of time if the compressor supports it
+struct PendingFile {
+    entry: DirEntry,
+    data: Option<(Metadata, Vec<u8>)>,
+}
+
+/// Reads small regular files into memory, if enabled and supported by the compressor
+async fn prefetch<C: Compressor>(entry: DirEntry, enabled: bool) -> Result<PendingFile> {
+    if !enabled || !C::PREFETCH || !entry.file_type().is_file() {
+        return Ok(PendingFile { entry, data: None });
+    }
+    let metadata = entry.metadata().wrap_err("Failed to read file metadata")?;
+    if metadata.len() > PREFETCH_LIMIT {
+        return Ok(PendingFile { entry, data: None });
+    }
+    let data = tokio::fs::read(entry.path())
+        .await
+        .wrap_err("Failed to read file")?;
+    Ok(PendingFile {
+        entry,
+        data: Some((metadata, data)),
+    })
+}
+
+/// Writes `<name>.sha256` next to the backup, in `sha256sum` format
+async fn write_checksum(
+    output_path: &Path,
+    checksum: &hashing::FileHash,
+    backup_config: &BackupsConfig,
+) -> Result<()> {
+    let name = output_path
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid backup name"))?;
+    let sidecar = sidecar_path(output_path, "sha256");
+    let line = format!(
+        "{}  {}\n",
+        hashing::to_hex(checksum),
+        name.to_string_lossy()
     );
+    let mut writer = output::open(&sidecar, backup_config).await?;
+    writer.write_all(line.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Path of a file stored next to a backup, e.g. its checksum
+fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    PathBuf::from(sidecar)
+}
+
+/// Flushes the backup (and its directory entry) to stable storage
+async fn sync_output(path: PathBuf) -> Result<()> {
+    fn sync_recursive(path: &Path) -> std::io::Result<()> {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                sync_recursive(&entry?.path())?;
+            }
+        }
+        std::fs::File::open(path)?.sync_all()
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let parts = split::parts(&path);
+        if parts.is_empty() {
+            sync_recursive(&path)?;
+        }
+        for part in parts {
+            sync_recursive(&part)?;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// Fails if the filesystems the backup is written to have less free space
+/// than the size of the files to back up, times the safety factor.
+/// The uncompressed size is used, so compressed backups are overestimated.
+fn check_free_space(
+    backup_config: &BackupsConfig,
+    output_path: &Path,
+    files: &[DirEntry],
+) -> Result<()> {
+    let mut directories = Vec::new();
+    if let Some(staging_dir) = &backup_config.staging_dir {
+        directories.push(staging_dir.as_path());
+    }
+    if !output::is_remote(output_path) {
+        directories.push(backup_config.output.as_path());
+    }
+    if directories.is_empty() {
+        return Ok(());
+    }
+    let estimate: u64 = files
+        .iter()
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    let required = (estimate as f64 * backup_config.free_space_factor) as u64;
+    for directory in directories {
+        let stat = nix::sys::statvfs::statvfs(directory)
+            .wrap_err_with(|| format!("Failed to check free space of {}", directory.display()))?;
+        let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        debug!(
+            "Free space in {}: {} (required: {})",
+            directory.display(),
+            format_size(Some(available)),
+            format_size(Some(required))
+        );
+        if available < required {
+            bail!(
+                "Not enough free space in {}: {} available, {} required",
+                directory.display(),
+                format_size(Some(available)),
+                format_size(Some(required))
+            );
+        }
+    }
     Ok(())
 }
 
-fn render_name(template: &str, time_format: &str, file_type: &BackupFileType) -> Result<String> {
-    let template = Template::new(template);
+/// Finds the files to back up. Symlinks are followed, skipped or returned
+/// themselves, depending on the symlink mode. Symlink loops are skipped.
+fn walk_files(backup_config: &BackupsConfig) -> Result<Vec<DirEntry>> {
+    let mode = backup_config.symlinks;
+    let files =
+        GlobWalkerBuilder::from_patterns(&backup_config.location, &glob_patterns(backup_config))
+            .follow_links(matches!(mode, SymlinkMode::Follow))
+            .build()
+            .wrap_err("Failed to create glob walker!")?
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    warn!(%err, "Skipping file");
+                    None
+                }
+            })
+            .filter(|e| {
+                e.file_type().is_file()
+                    || (matches!(mode, SymlinkMode::Store) && e.path_is_symlink())
+            })
+            .collect();
+    Ok(files)
+}
+
+/// Include globs followed by the negated exclude globs, so excludes take precedence
+fn glob_patterns(backup_config: &BackupsConfig) -> Vec<String> {
+    let excludes = backup_config
+        .exclude
+        .iter()
+        .map(|pattern| format!("!{pattern}"));
+    backup_config
+        .files
+        .iter()
+        .cloned()
+        .chain(excludes)
+        .collect()
+}
+
+fn format_size(size: Option<u64>) -> String {
+    match size {
+        Some(size) => human_bytes::human_bytes(size as f64),
+        None => "unknown".into(),
+    }
+}
+
+/// Information about a backup, embedded into the archive itself
+#[derive(Debug, Serialize)]
+pub struct BackupMetadata {
+    pub backup: String,
+    pub timestamp: String,
+    pub host: String,
+    pub version: String,
+}
+
+impl BackupMetadata {
+    fn new(backup: &str) -> Self {
+        Self {
+            backup: backup.to_string(),
+            timestamp: Local::now().to_rfc3339(),
+            host: hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+async fn render_name(
+    backup: &str,
+    backup_config: &BackupsConfig,
+    tag: Option<&str>,
+) -> Result<String> {
+    if tag.is_some() && !backup_config.name.contains("{tag}") {
+        warn!("Backup name has no {{tag}}, the tag is ignored");
+    }
+    // Only advance the counter for templates using it
+    let counter = if backup_config.name.contains("{counter}") {
+        Some(next_counter(backup, backup_config).await?)
+    } else {
+        None
+    };
+    let template = Template::new(&backup_config.name);
     let data = {
         let mut map = HashMap::new();
-        map.insert("date", format!("{}", Local::now().format(time_format)));
-        map.insert("extension", find_extension(file_type).to_string());
+        map.insert(
+            "date",
+            format!("{}", Local::now().format(&backup_config.time_format)),
+        );
+        map.insert("extension", full_extension(backup_config));
+        map.insert("tag", tag.unwrap_or_default().to_string());
+        map.insert("hostname", hostname());
+        map.insert("backup_name", backup.to_string());
+        if let Some(counter) = counter {
+            map.insert("counter", format!("{counter:06}"));
+        }
         map
     };
     template.render(&data).wrap_err("Failed to render name!")
 }
 
+/// Increments and returns the counter persisted in the output directory. Starts at 1.
+async fn next_counter(backup: &str, backup_config: &BackupsConfig) -> Result<u64> {
+    if output::is_remote(&backup_config.output) {
+        bail!("{{counter}} needs a local output directory");
+    }
+    let output = backup_config.output.clone();
+    let path = output.join(format!(".{backup}.counter"));
+    // The lock is only held on the blocking thread, never across an await
+    tokio::task::spawn_blocking(move || {
+        let _guard = COUNTER_LOCK.lock();
+        let previous = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .trim()
+                .parse::<u64>()
+                .wrap_err_with(|| format!("Invalid counter in {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err).wrap_err("Failed to read backup counter"),
+        };
+        let counter = previous + 1;
+        std::fs::create_dir_all(&output).wrap_err("Failed to create output directory")?;
+        std::fs::write(&path, format!("{counter}\n")).wrap_err("Failed to write backup counter")?;
+        Ok(counter)
+    })
+    .await?
+}
+
+fn hostname() -> String {
+    nix::unistd::gethostname()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+/// Extension of the backup, including the encryption suffix
+fn full_extension(backup_config: &BackupsConfig) -> String {
+    let extension = find_extension(&backup_config.file_type);
+    if backup_config.encryption.is_some() {
+        format!("{extension}.age")
+    } else {
+        extension.to_string()
+    }
+}
+
 fn find_extension(typ: &BackupFileType) -> &str {
     match typ {
         BackupFileType::Zip => "zip",
         BackupFileType::TarGz | BackupFileType::TarGzSmall | BackupFileType::TarGzFast => "tar.gz",
+        BackupFileType::TarZstd | BackupFileType::TarZstdSmall | BackupFileType::TarZstdFast => {
+            "tar.zst"
+        }
+        BackupFileType::TarXz => "tar.xz",
+        BackupFileType::TarBz2 => "tar.bz2",
         BackupFileType::Tar => "tar",
         BackupFileType::Copy => "d",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::compressor::tests::{test_config, test_dir};
+    use super::*;
+
+    /// Many small files, read one at a time and read ahead in parallel. Both backups have to
+    /// be identical. Run with `--nocapture` to compare the timings.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parallel_reads_match_serial_reads() {
+        const FILES: usize = 2000;
+        let dir = test_dir("read-workers");
+        let source = dir.join("source");
+        let name = |index: usize| PathBuf::from(format!("{}/{index}.dat", index % 20));
+        for index in 0..FILES {
+            let path = source.join(name(index));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, format!("file {index}\n").repeat(64)).unwrap();
+        }
+        let mut config = test_config(&source, &dir);
+        for workers in [1, 8] {
+            config.read_workers = workers;
+            let files = walk_files(&config).unwrap();
+            assert_eq!(files.len(), FILES);
+            let output = dir.join(format!("copy-{workers}"));
+            let start = Instant::now();
+            write_archive::<CopyCompressor>("test", &config, &output, files, None)
+                .await
+                .unwrap();
+            println!("{workers} read workers: {:?}", start.elapsed());
+        }
+        for index in 0..FILES {
+            let serial = std::fs::read(dir.join("copy-1").join(name(index))).unwrap();
+            let parallel = std::fs::read(dir.join("copy-8").join(name(index))).unwrap();
+            assert_eq!(serial, parallel);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
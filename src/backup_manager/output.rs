@@ -0,0 +1,84 @@
+use super::hashing::HashingWriter;
+use super::s3;
+use super::split::{self, SplitWriter};
+use crate::configs::{BackupsConfig, EncryptionConfig, S3Config};
+use age::secrecy::SecretString;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use std::path::Path;
+use std::str::FromStr;
+use tokio::fs::File;
+use tokio::io::AsyncWrite;
+use tokio_util::compat::{FuturesAsyncWriteCompatExt, TokioAsyncWriteCompatExt};
+
+/// Writer the compressors write the backup to
+pub type OutputWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Whether the output is uploaded instead of written to local disk
+pub fn is_remote(path: &Path) -> bool {
+    s3::parse_url(path).is_some()
+}
+
+/// Creates the output file or upload, without encryption
+pub async fn open(path: &Path, config: &BackupsConfig) -> Result<OutputWriter> {
+    Ok(match s3::parse_url(path) {
+        Some((bucket, key)) => Box::new(s3::S3Writer::start(config.s3.as_ref(), bucket, key)?),
+        None => Box::new(
+            File::create(path)
+                .await
+                .wrap_err("Failed to create output file!")?,
+        ),
+    })
+}
+
+/// Creates the output file or upload, encrypting and splitting it if configured
+pub async fn create(path: &Path, config: &BackupsConfig) -> Result<OutputWriter> {
+    let mut output: OutputWriter = match config.split_size {
+        Some(split_size) if !is_remote(path) => {
+            Box::new(SplitWriter::create(path, split_size.as_u64()).await?)
+        }
+        _ => open(path, config).await?,
+    };
+    if config.checksum {
+        output = Box::new(HashingWriter::new(output, path.to_path_buf()));
+    }
+    let Some(encryption) = &config.encryption else {
+        return Ok(output);
+    };
+    let writer = encryptor(encryption)?
+        .wrap_async_output(output.compat_write())
+        .await
+        .wrap_err("Failed to start encryption")?;
+    Ok(Box::new(writer.compat_write()))
+}
+
+/// Returns the size of the written output, if known
+pub async fn size(path: &Path, s3_config: Option<&S3Config>) -> Option<u64> {
+    if is_remote(path) {
+        s3::object_size(s3_config, path).await
+    } else if let Ok(metadata) = tokio::fs::metadata(path).await {
+        Some(metadata.len())
+    } else {
+        split::total_size(path).await
+    }
+}
+
+fn encryptor(config: &EncryptionConfig) -> Result<age::Encryptor> {
+    match (&config.passphrase, config.recipients.as_slice()) {
+        (Some(passphrase), []) => Ok(age::Encryptor::with_user_passphrase(SecretString::new(
+            passphrase.clone(),
+        ))),
+        (None, recipients) if !recipients.is_empty() => {
+            let recipients = recipients
+                .iter()
+                .map(|recipient| {
+                    age::x25519::Recipient::from_str(recipient)
+                        .map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+                        .map_err(|err| eyre!("Invalid recipient {}: {}", recipient, err))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            age::Encryptor::with_recipients(recipients).ok_or_else(|| eyre!("No recipients"))
+        }
+        _ => bail!("Encryption needs either a passphrase or recipients"),
+    }
+}
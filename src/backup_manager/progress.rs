@@ -0,0 +1,106 @@
+use crate::control::CONTROL_PREFIX;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Minimum time between progress events of a backup
+const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Progress of running backups, by backup name
+static PROGRESS: Mutex<BTreeMap<String, BackupProgress>> = Mutex::new(BTreeMap::new());
+static EVENTS: Mutex<Option<broadcast::Sender<String>>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackupProgress {
+    pub total_files: u64,
+    pub files_done: u64,
+    pub bytes_processed: u64,
+}
+
+impl BackupProgress {
+    fn describe(&self, backup: &str) -> String {
+        format!(
+            "backing up {}: {}/{} files ({})",
+            backup,
+            self.files_done,
+            self.total_files,
+            human_bytes::human_bytes(self.bytes_processed as f64)
+        )
+    }
+}
+
+/// Subscribes to progress events, formatted as `!progress <description>` lines,
+/// and backup results
+pub fn subscribe() -> broadcast::Receiver<String> {
+    let mut events = EVENTS.lock();
+    events
+        .get_or_insert_with(|| broadcast::channel(64).0)
+        .subscribe()
+}
+
+fn publish(event: String) {
+    send(format!("{CONTROL_PREFIX}progress {event}\n"));
+}
+
+/// Publishes the JSON result of a finished backup as a `!backup-result <json>` line
+pub fn publish_result(json: &str) {
+    send(format!("{CONTROL_PREFIX}backup-result {json}\n"));
+}
+
+fn send(line: String) {
+    if let Some(events) = &*EVENTS.lock() {
+        // No subscribers is fine
+        let _ = events.send(line);
+    }
+}
+
+/// Describes all running backups, one per line
+pub fn describe() -> Vec<String> {
+    PROGRESS
+        .lock()
+        .iter()
+        .map(|(backup, progress)| progress.describe(backup))
+        .collect()
+}
+
+/// Tracks the progress of a backup until dropped
+pub struct Tracker {
+    backup: String,
+    progress: BackupProgress,
+    last_event: Instant,
+}
+
+impl Tracker {
+    pub fn start(backup: &str, total_files: u64) -> Self {
+        let progress = BackupProgress {
+            total_files,
+            files_done: 0,
+            bytes_processed: 0,
+        };
+        PROGRESS.lock().insert(backup.to_string(), progress);
+        publish(progress.describe(backup));
+        Self {
+            backup: backup.to_string(),
+            progress,
+            last_event: Instant::now(),
+        }
+    }
+
+    pub fn file_done(&mut self, bytes: u64) {
+        self.progress.files_done += 1;
+        self.progress.bytes_processed += bytes;
+        PROGRESS.lock().insert(self.backup.clone(), self.progress);
+        if self.last_event.elapsed() >= EVENT_INTERVAL {
+            self.last_event = Instant::now();
+            publish(self.progress.describe(&self.backup));
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        PROGRESS.lock().remove(&self.backup);
+        publish(format!("finished {}", self.backup));
+    }
+}
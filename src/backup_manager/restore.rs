@@ -0,0 +1,364 @@
+use super::compressor::{METADATA_ENTRY, ZIP_SYMLINK_MODE};
+use super::{hashing, incremental, output, retention, sidecar_path, split};
+use crate::configs::{BackupFileType, BackupsConfig, DolorousConfig};
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use async_zip::read::fs::ZipFileReader;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use fs_extra::dir::CopyOptions;
+use std::collections::HashSet;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio_stream::StreamExt;
+use tokio_tar::EntryType;
+use tracing::{debug, info, warn};
+
+/// Finds the newest backup in the output directory, optionally only with the given tag
+pub async fn find_latest(
+    config: &DolorousConfig,
+    backup: &str,
+    tag: Option<&str>,
+) -> Result<PathBuf> {
+    let backup_config = backup_config(config, backup)?;
+    if output::is_remote(&backup_config.output) {
+        bail!("Restoring from remote outputs is not supported");
+    }
+    retention::latest(backup_config, tag)
+        .await?
+        .ok_or_else(|| eyre!("No backups found in {}", backup_config.output.display()))
+}
+
+/// Resolves a backup name to its newest archive, or an archive path to the backup writing it.
+/// Returns the backup name and the archive path.
+pub async fn find_archive(
+    config: &DolorousConfig,
+    target: &str,
+    tag: Option<&str>,
+) -> Result<(String, PathBuf)> {
+    if config.backups.contains_key(target) {
+        let archive = find_latest(config, target, tag).await?;
+        return Ok((target.to_string(), archive));
+    }
+    let archive = Path::new(target);
+    if !archive.exists() && split::parts(archive).is_empty() {
+        bail!(
+            "{} is neither a backup name nor an existing archive",
+            target
+        );
+    }
+    let file_name = archive
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| eyre!("Invalid archive path"))?;
+    let directory = match archive.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let directory = directory
+        .canonicalize()
+        .wrap_err("Failed to resolve archive directory")?;
+    let backup = config
+        .backups
+        .iter()
+        .find(|(_, backup_config)| {
+            !output::is_remote(&backup_config.output)
+                && backup_config.output.canonicalize().ok().as_ref() == Some(&directory)
+                && retention::name_pattern(backup_config)
+                    .map_or(false, |pattern| pattern.is_match(file_name))
+        })
+        .map(|(name, _)| name.clone())
+        .ok_or_else(|| eyre!("No configured backup writes {}", archive.display()))?;
+    Ok((backup, directory.join(file_name)))
+}
+
+/// Verifies the archive against its checksum and extracts it into the backup location.
+/// Incremental backups are restored from all archives of their snapshot.
+pub async fn restore(config: &DolorousConfig, backup: &str, archive: &Path) -> Result<()> {
+    let backup_config = backup_config(config, backup)?;
+    if backup_config.encryption.is_some() {
+        bail!("Restoring encrypted backups is not supported");
+    }
+    if !archive.exists() && !split::parts(archive).is_empty() {
+        bail!(
+            "{} is split into volumes, reassemble it first with `cat {}.part* > {}`",
+            archive.display(),
+            archive.display(),
+            archive.display()
+        );
+    }
+    let location = &backup_config.location;
+    tokio::fs::create_dir_all(location)
+        .await
+        .map_err(|err| crate::permissions::explain(err.into(), location))?;
+    match incremental::read_manifest(archive).await? {
+        Some(manifest) => {
+            let directory = archive
+                .parent()
+                .ok_or_else(|| eyre!("Invalid archive path"))?;
+            for (name, files) in manifest.snapshot() {
+                let part = directory.join(name);
+                info!("Restoring {} files from {}", files.len(), part.display());
+                verify_checksum(&part).await?;
+                let files: HashSet<&Path> = files.into_iter().collect();
+                extract(backup_config, &part, Some(&files)).await?;
+            }
+        }
+        None => {
+            verify_checksum(archive).await?;
+            extract(backup_config, archive, None).await?;
+        }
+    }
+    info!("Restored {} to {}", archive.display(), location.display());
+    Ok(())
+}
+
+/// Extracts the archive into the backup location, optionally only the given files
+async fn extract(
+    backup_config: &BackupsConfig,
+    archive: &Path,
+    only: Option<&HashSet<&Path>>,
+) -> Result<()> {
+    let location = &backup_config.location;
+    match &backup_config.file_type {
+        BackupFileType::Zip => extract_zip(archive, location, only).await,
+        BackupFileType::TarGz | BackupFileType::TarGzFast | BackupFileType::TarGzSmall => {
+            let reader = BufReader::new(File::open(archive).await?);
+            extract_tar(GzipDecoder::new(reader), location, only).await
+        }
+        BackupFileType::TarZstd | BackupFileType::TarZstdFast | BackupFileType::TarZstdSmall => {
+            let reader = BufReader::new(File::open(archive).await?);
+            extract_tar(ZstdDecoder::new(reader), location, only).await
+        }
+        BackupFileType::TarXz => {
+            let reader = BufReader::new(File::open(archive).await?);
+            extract_tar(XzDecoder::new(reader), location, only).await
+        }
+        BackupFileType::TarBz2 => {
+            let reader = BufReader::new(File::open(archive).await?);
+            extract_tar(BzDecoder::new(reader), location, only).await
+        }
+        BackupFileType::Tar => extract_tar(File::open(archive).await?, location, only).await,
+        BackupFileType::Copy => copy_directory(archive, location, only).await,
+    }
+}
+
+fn backup_config<'a>(config: &'a DolorousConfig, backup: &str) -> Result<&'a BackupsConfig> {
+    config
+        .backups
+        .get(backup)
+        .ok_or_else(|| eyre!("Undefined backup: {}", backup))
+}
+
+/// Checks the archive against its `.sha256` sidecar, if there is one
+async fn verify_checksum(archive: &Path) -> Result<()> {
+    let sidecar = sidecar_path(archive, "sha256");
+    let expected = match tokio::fs::read_to_string(&sidecar).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            warn!("No checksum found for {}", archive.display());
+            return Ok(());
+        }
+        Err(err) => return Err(err).wrap_err("Failed to read checksum"),
+    };
+    let expected = expected
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| eyre!("Empty checksum file"))?;
+    let actual = hashing::to_hex(&hashing::hash_file(archive).await?);
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("Checksum mismatch: expected {}, got {}", expected, actual);
+    }
+    debug!("Checksum verified");
+    Ok(())
+}
+
+pub(super) async fn extract_tar<R: AsyncRead + Unpin + Send>(
+    reader: R,
+    location: &Path,
+    only: Option<&HashSet<&Path>>,
+) -> Result<()> {
+    let mut archive = tokio_tar::Archive::new(reader);
+    let mut entries = archive.entries().wrap_err("Failed to read archive")?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.wrap_err("Failed to read archive entry")?;
+        let path = entry.path()?;
+        if path.as_ref() == Path::new(METADATA_ENTRY) {
+            continue;
+        }
+        if matches!(only, Some(only) if !only.contains(path.as_ref())) {
+            continue;
+        }
+        if entry.header().entry_type() == EntryType::Link {
+            restore_duplicate(&entry, &path, location).await?;
+            continue;
+        }
+        entry
+            .unpack_in(location)
+            .await
+            .wrap_err("Failed to extract file")?;
+    }
+    Ok(())
+}
+
+/// Restores a deduplicated file, stored as a hardlink, as a copy of the first file with its
+/// contents. A hardlink would make changes to either file show up in the other.
+async fn restore_duplicate<R: AsyncRead + Unpin + Send>(
+    entry: &tokio_tar::Entry<tokio_tar::Archive<R>>,
+    path: &Path,
+    location: &Path,
+) -> Result<()> {
+    let first = entry
+        .link_name()?
+        .ok_or_else(|| eyre!("Link without a target: {}", path.display()))?;
+    for relative_path in [path, first.as_ref()] {
+        if relative_path.is_absolute()
+            || relative_path
+                .components()
+                .any(|c| matches!(c, Component::ParentDir))
+        {
+            bail!("Invalid path in archive: {}", relative_path.display());
+        }
+    }
+    let destination = location.join(path);
+    if let Some(parent) = destination.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    tokio::fs::copy(location.join(first.as_ref()), &destination)
+        .await
+        .wrap_err_with(|| format!("Failed to restore {}", destination.display()))?;
+    Ok(())
+}
+
+pub(super) async fn extract_zip(
+    archive: &Path,
+    location: &Path,
+    only: Option<&HashSet<&Path>>,
+) -> Result<()> {
+    let name = archive
+        .to_str()
+        .ok_or_else(|| eyre!("Invalid archive path"))?;
+    let reader = ZipFileReader::new(name.to_string())
+        .await
+        .wrap_err("Failed to open archive")?;
+    for index in 0..reader.file().entries().len() {
+        let mut entry = reader.entry(index).await?;
+        let relative_path = PathBuf::from(entry.entry().filename());
+        // Don't write outside of the location
+        if relative_path.is_absolute()
+            || relative_path
+                .components()
+                .any(|c| matches!(c, Component::ParentDir))
+        {
+            bail!("Invalid path in archive: {}", relative_path.display());
+        }
+        if matches!(only, Some(only) if !only.contains(relative_path.as_path())) {
+            continue;
+        }
+        let path = location.join(&relative_path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mode = entry.entry().unix_permissions();
+        if mode.map_or(false, |mode| mode & 0o170000 == ZIP_SYMLINK_MODE & 0o170000) {
+            let mut target = String::new();
+            entry.read_to_string(&mut target).await?;
+            if !link_stays_inside(&relative_path, Path::new(&target)) {
+                bail!(
+                    "Symlink {} points outside of the location: {}",
+                    relative_path.display(),
+                    target
+                );
+            }
+            tokio::fs::symlink(&target, &path)
+                .await
+                .wrap_err_with(|| format!("Failed to create symlink {}", path.display()))?;
+            continue;
+        }
+        let mut file = File::create(&path)
+            .await
+            .wrap_err_with(|| format!("Failed to create {}", path.display()))?;
+        tokio::io::copy(&mut entry, &mut file)
+            .await
+            .wrap_err("Failed to extract file")?;
+        if let Some(mode) = mode {
+            tokio::fs::set_permissions(&path, Permissions::from_mode(mode as u32)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a symlink at `relative_path` with the given target resolves to a path inside the
+/// location. Only the target itself is checked, earlier symlinks are checked when extracted.
+fn link_stays_inside(relative_path: &Path, target: &Path) -> bool {
+    let mut depth = relative_path.components().count().saturating_sub(1);
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+async fn copy_directory(
+    archive: &Path,
+    location: &Path,
+    only: Option<&HashSet<&Path>>,
+) -> Result<()> {
+    if let Some(only) = only {
+        for relative_path in only {
+            let target = location.join(relative_path);
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::copy(archive.join(relative_path), &target)
+                .await
+                .wrap_err_with(|| format!("Failed to restore {}", relative_path.display()))?;
+        }
+        return Ok(());
+    }
+    let archive = archive.to_path_buf();
+    let location = location.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut options = CopyOptions::new();
+        options.content_only = true;
+        options.overwrite = true;
+        fs_extra::dir::copy(archive, location, &options).map_err(|err| eyre!("{}", err))
+    })
+    .await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symlinks_outside_the_location_are_rejected() {
+        assert!(link_stays_inside(
+            Path::new("world/latest"),
+            Path::new("region")
+        ));
+        assert!(link_stays_inside(
+            Path::new("world/latest"),
+            Path::new("../config")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("world/latest"),
+            Path::new("../../etc")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("latest"),
+            Path::new("/etc/passwd")
+        ));
+        assert!(!link_stays_inside(
+            Path::new("a/b"),
+            Path::new("c/../../../d")
+        ));
+    }
+}
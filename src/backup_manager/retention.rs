@@ -0,0 +1,202 @@
+use super::{full_extension, incremental, sidecar_path, split};
+use crate::configs::{BackupsConfig, RetentionRule};
+use chrono::format::{Parsed, StrftimeItems};
+use chrono::{Local, NaiveDateTime};
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+
+/// A backup found in the output directory
+struct ExistingBackup {
+    path: PathBuf,
+    date: NaiveDateTime,
+    tag: Option<String>,
+}
+
+/// Deletes old backups exceeding the retention policy.
+/// Only files matching the backup name template are considered.
+/// Each tag is pruned separately, using its own rule if there is one.
+pub async fn prune(backup_config: &BackupsConfig) -> Result<()> {
+    let has_limits = |rule: &RetentionRule| rule.keep_last.is_some() || rule.keep_within.is_some();
+    if !has_limits(&default_rule(backup_config))
+        && !backup_config.tag_retention.values().any(has_limits)
+    {
+        return Ok(());
+    }
+    if super::output::is_remote(&backup_config.output) {
+        debug!("Retention is not supported for remote outputs");
+        return Ok(());
+    }
+    // Deleting these would break the newest incremental snapshot
+    let referenced = incremental::referenced_archives(backup_config).await?;
+    let mut by_tag: BTreeMap<Option<String>, Vec<ExistingBackup>> = BTreeMap::new();
+    for backup in list_backups(backup_config).await? {
+        by_tag.entry(backup.tag.clone()).or_default().push(backup);
+    }
+
+    let now = Local::now().naive_local();
+    for (tag, mut backups) in by_tag {
+        let rule = tag
+            .as_ref()
+            .and_then(|tag| backup_config.tag_retention.get(tag).copied())
+            .unwrap_or_else(|| default_rule(backup_config));
+        if !has_limits(&rule) {
+            continue;
+        }
+        // Newest first
+        backups.sort_by(|a, b| b.date.cmp(&a.date));
+        for (index, backup) in backups.iter().enumerate() {
+            let keep_by_count = matches!(rule.keep_last, Some(n) if index < n);
+            let keep_by_age = match rule.keep_within {
+                Some(within) => chrono::Duration::from_std(within)
+                    .map(|within| now - backup.date <= within)
+                    .unwrap_or(true),
+                None => false,
+            };
+            if keep_by_count || keep_by_age {
+                continue;
+            }
+            let name = backup.path.file_name().map(|n| n.to_string_lossy());
+            if matches!(name, Some(name) if referenced.iter().any(|r| *r == name)) {
+                debug!(
+                    "Keeping {}: needed by the newest snapshot",
+                    backup.path.display()
+                );
+                continue;
+            }
+            delete(backup).await;
+        }
+    }
+    Ok(())
+}
+
+fn default_rule(backup_config: &BackupsConfig) -> RetentionRule {
+    RetentionRule {
+        keep_last: backup_config.keep_last,
+        keep_within: backup_config.keep_within,
+    }
+}
+
+async fn delete(backup: &ExistingBackup) {
+    info!("Deleting old backup {}", backup.path.display());
+    let parts = split::parts(&backup.path);
+    let files = if parts.is_empty() {
+        vec![backup.path.clone()]
+    } else {
+        parts
+    };
+    for path in files {
+        let result = if path.is_dir() {
+            tokio::fs::remove_dir_all(&path).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        if let Err(err) = result {
+            warn!(?err, "Failed to delete {}", path.display());
+        }
+    }
+    for extension in ["sha256", incremental::MANIFEST_EXTENSION] {
+        let _ = tokio::fs::remove_file(sidecar_path(&backup.path, extension)).await;
+    }
+}
+
+/// Returns the newest backup in the output directory, optionally only with the given tag
+pub async fn latest(backup_config: &BackupsConfig, tag: Option<&str>) -> Result<Option<PathBuf>> {
+    let backups = list_backups(backup_config).await?;
+    Ok(backups
+        .into_iter()
+        .filter(|backup| tag.is_none() || backup.tag.as_deref() == tag)
+        .max_by(|a, b| a.date.cmp(&b.date))
+        .map(|backup| backup.path))
+}
+
+/// Lists all backups in the output directory matching the name template
+async fn list_backups(backup_config: &BackupsConfig) -> Result<Vec<ExistingBackup>> {
+    let pattern = name_pattern(backup_config)?;
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(&backup_config.output)
+        .await
+        .wrap_err("Failed to list backups")?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(mut file_name) = file_name.to_str() else {
+            continue;
+        };
+        // Split backups are listed once, by their first volume
+        if let Some((name, part)) = split::strip_part(file_name) {
+            if part != 1 {
+                continue;
+            }
+            file_name = name;
+        }
+        let Some(captures) = pattern.captures(file_name) else {
+            continue;
+        };
+        let Some(date) = captures
+            .name("date")
+            .and_then(|date| parse_date(date.as_str(), &backup_config.time_format))
+        else {
+            debug!("Skipping {}: no date in name", file_name);
+            continue;
+        };
+        let tag = captures
+            .name("tag")
+            .map(|tag| tag.as_str().to_string())
+            .filter(|tag| !tag.is_empty());
+        backups.push(ExistingBackup {
+            path: backup_config.output.join(file_name),
+            date,
+            tag,
+        });
+    }
+    Ok(backups)
+}
+
+/// Builds a regex matching names rendered from the backup name template
+pub fn name_pattern(backup_config: &BackupsConfig) -> Result<Regex> {
+    let mut pattern = String::from("^");
+    let mut rest = backup_config.name.as_str();
+    let mut has_date = false;
+    let mut has_tag = false;
+    while let Some(start) = rest.find('{') {
+        pattern.push_str(&regex::escape(&rest[..start]));
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let variable = &rest[start + 1..start + end];
+        match variable {
+            "date" if !has_date => {
+                has_date = true;
+                pattern.push_str("(?P<date>.+?)");
+            }
+            "tag" if !has_tag => {
+                has_tag = true;
+                pattern.push_str("(?P<tag>.*?)");
+            }
+            "extension" => pattern.push_str(&regex::escape(&full_extension(backup_config))),
+            _ => pattern.push_str(".+?"),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+    Regex::new(&pattern).wrap_err("Invalid backup name template")
+}
+
+/// Parses a date formatted with the time format. Missing time fields default to zero.
+fn parse_date(date: &str, time_format: &str) -> Option<NaiveDateTime> {
+    let mut parsed = Parsed::new();
+    chrono::format::parse(&mut parsed, date, StrftimeItems::new(time_format)).ok()?;
+    if parsed.hour_div_12.is_none() && parsed.hour_mod_12.is_none() {
+        parsed.set_hour(0).ok()?;
+    }
+    if parsed.minute.is_none() {
+        parsed.set_minute(0).ok()?;
+    }
+    let date = parsed.to_naive_date().ok()?;
+    let time = parsed.to_naive_time().ok()?;
+    Some(date.and_time(time))
+}
@@ -0,0 +1,192 @@
+use crate::configs::S3Config;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWrite, DuplexStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Scheme of S3 output URLs
+const SCHEME: &str = "s3://";
+/// Size of the buffer between the compressor and the upload
+const PIPE_SIZE: usize = 8 * 1024 * 1024;
+/// Size of the uploaded parts. S3 needs at least 5 MiB for all but the last.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Splits an `s3://bucket/key` path into bucket and key
+pub fn parse_url(path: &Path) -> Option<(&str, &str)> {
+    let url = path.to_str()?.strip_prefix(SCHEME)?;
+    let (bucket, key) = url.split_once('/').unwrap_or((url, ""));
+    Some((bucket, key.trim_start_matches('/')))
+}
+
+fn bucket(config: Option<&S3Config>, name: &str) -> Result<Bucket> {
+    let config = config.ok_or_else(|| eyre!("S3 output requires an s3 config"))?;
+    let region = match &config.endpoint {
+        Some(endpoint) => Region::Custom {
+            region: config.region.clone(),
+            endpoint: endpoint.clone(),
+        },
+        None => config.region.parse().wrap_err("Invalid S3 region")?,
+    };
+    let credentials = match (&config.access_key, &config.secret_key) {
+        (Some(access_key), Some(secret_key)) => Credentials::new(
+            Some(access_key.as_str()),
+            Some(secret_key.as_str()),
+            None,
+            None,
+            None,
+        ),
+        _ => Credentials::default(),
+    }
+    .wrap_err("Failed to load S3 credentials")?;
+    let bucket = Bucket::new(name, region, credentials).wrap_err("Invalid S3 bucket")?;
+    Ok(if config.path_style {
+        bucket.with_path_style()
+    } else {
+        bucket
+    })
+}
+
+/// Returns the size of an uploaded object
+pub async fn object_size(config: Option<&S3Config>, path: &Path) -> Option<u64> {
+    let (bucket_name, key) = parse_url(path)?;
+    let bucket = bucket(config, bucket_name).ok()?;
+    let (head, _) = bucket.head_object(key).await.ok()?;
+    head.content_length.map(|length| length as u64)
+}
+
+/// Writer uploading everything written to it to S3 using a multipart upload.
+/// The upload is completed on shutdown. Dropped before that, e.g. after a failed write,
+/// the multipart upload is aborted, so no truncated object appears under the key.
+pub struct S3Writer {
+    pipe: DuplexStream,
+    /// Tells the upload task to complete the upload. Dropped unsent, it's aborted.
+    finish: Option<oneshot::Sender<()>>,
+    upload: Option<JoinHandle<Result<()>>>,
+}
+
+impl S3Writer {
+    pub fn start(config: Option<&S3Config>, bucket_name: &str, key: &str) -> Result<Self> {
+        let bucket = bucket(config, bucket_name)?;
+        let (pipe, reader) = tokio::io::duplex(PIPE_SIZE);
+        let (finish, finished) = oneshot::channel();
+        let key = key.to_string();
+        let upload = tokio::spawn(async move {
+            debug!("Uploading to {}", key);
+            let upload_id = bucket
+                .initiate_multipart_upload(&key, CONTENT_TYPE)
+                .await
+                .wrap_err("Failed to start upload")?
+                .upload_id;
+            let result = upload_parts(&bucket, &key, &upload_id, reader, finished).await;
+            if result.is_err() {
+                if let Err(err) = bucket.abort_upload(&key, &upload_id).await {
+                    warn!(?err, "Failed to abort upload of {}", key);
+                }
+            }
+            result
+        });
+        Ok(Self {
+            pipe,
+            finish: Some(finish),
+            upload: Some(upload),
+        })
+    }
+}
+
+/// Uploads the piped data in parts. Completes the upload once the pipe is closed,
+/// if the writer was shut down.
+async fn upload_parts(
+    bucket: &Bucket,
+    key: &str,
+    upload_id: &str,
+    mut reader: DuplexStream,
+    finished: oneshot::Receiver<()>,
+) -> Result<()> {
+    let mut parts = Vec::new();
+    loop {
+        let mut chunk = Vec::with_capacity(PART_SIZE);
+        (&mut reader)
+            .take(PART_SIZE as u64)
+            .read_to_end(&mut chunk)
+            .await?;
+        // A completed upload needs a part, even an empty one
+        if chunk.is_empty() && !parts.is_empty() {
+            break;
+        }
+        let last = chunk.len() < PART_SIZE;
+        let part_number = parts.len() as u32 + 1;
+        let part = bucket
+            .put_multipart_chunk(chunk, key, part_number, upload_id, CONTENT_TYPE)
+            .await
+            .wrap_err_with(|| format!("Failed to upload part {part_number}"))?;
+        parts.push(part);
+        if last {
+            break;
+        }
+    }
+    // The pipe also closes when the writer is dropped
+    finished
+        .await
+        .map_err(|_| eyre!("Backup output dropped before it was finished"))?;
+    let response = bucket
+        .complete_multipart_upload(key, upload_id, parts)
+        .await
+        .wrap_err("Failed to complete upload")?;
+    let status = response.status_code();
+    if !(200..300).contains(&status) {
+        return Err(eyre!("Upload failed with status {}", status));
+    }
+    info!("Uploaded {}", key);
+    Ok(())
+}
+
+impl Drop for S3Writer {
+    fn drop(&mut self) {
+        if self.finish.is_some() {
+            // Closing the pipe without finishing makes the task abort the upload
+            warn!("Upload dropped before it was finished, aborting it");
+        }
+    }
+}
+
+impl AsyncWrite for S3Writer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().pipe).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().pipe).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.pipe).poll_shutdown(cx))?;
+        if let Some(finish) = this.finish.take() {
+            let _ = finish.send(());
+        }
+        let Some(upload) = &mut this.upload else {
+            return Poll::Ready(Ok(()));
+        };
+        let result = ready!(Pin::new(upload).poll(cx));
+        this.upload = None;
+        Poll::Ready(match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(io::Error::new(io::ErrorKind::Other, format!("{err:#}"))),
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+        })
+    }
+}
@@ -0,0 +1,183 @@
+use super::incremental::MANIFEST_EXTENSION;
+use super::{format_size, sidecar_path, split, staging};
+use crate::configs::SftpConfig;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use ssh2::{CheckResult, HashType, KnownHostFileKind, RenameFlags, Session};
+use std::fs::File;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{debug, info};
+
+/// Uploads a finished backup and its sidecar files to the remote host
+pub async fn upload(config: &SftpConfig, archive: &Path) -> Result<()> {
+    if archive.is_dir() {
+        bail!("Only archives can be uploaded over SFTP");
+    }
+    let mut files = split::parts(archive);
+    if files.is_empty() {
+        files.push(archive.to_path_buf());
+    }
+    files.extend(
+        ["sha256", MANIFEST_EXTENSION]
+            .into_iter()
+            .map(|ext| sidecar_path(archive, ext))
+            .filter(|path| path.exists()),
+    );
+
+    let start = Instant::now();
+    let size = {
+        let config = config.clone();
+        let files = files.clone();
+        tokio::task::spawn_blocking(move || upload_blocking(&config, &files)).await??
+    };
+    info!(
+        "Uploaded backup to {} (size: {}, elapsed: {})",
+        config.host,
+        format_size(Some(size)),
+        humantime::format_duration(start.elapsed())
+    );
+
+    if !config.keep_local {
+        for file in &files {
+            staging::remove(file).await;
+        }
+        debug!("Removed local copy");
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes uploaded
+fn upload_blocking(config: &SftpConfig, files: &[PathBuf]) -> Result<u64> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port))
+        .wrap_err_with(|| format!("Failed to connect to {}:{}", config.host, config.port))?;
+    let mut session = Session::new().wrap_err("Failed to create SSH session")?;
+    session.set_tcp_stream(tcp);
+    session.handshake().wrap_err("SSH handshake failed")?;
+    check_host_key(config, &session)?;
+    session
+        .userauth_pubkey_file(&config.user, None, &config.key_path, None)
+        .wrap_err("SSH authentication failed")?;
+    let sftp = session.sftp().wrap_err("Failed to start SFTP")?;
+
+    let mut total = 0;
+    for file in files {
+        let name = file
+            .file_name()
+            .ok_or_else(|| eyre!("Invalid backup name"))?;
+        let remote_path = config.remote_dir.join(name);
+        // Uploaded under a temporary name, so a partial upload isn't taken for a backup
+        let partial_path = config
+            .remote_dir
+            .join(format!(".{}.partial", name.to_string_lossy()));
+        debug!("Uploading {} to {}", file.display(), remote_path.display());
+        let mut local = File::open(file).wrap_err("Failed to open backup")?;
+        let uploaded = sftp
+            .create(&partial_path)
+            .wrap_err_with(|| format!("Failed to create {}", partial_path.display()))
+            .and_then(|mut remote| {
+                std::io::copy(&mut local, &mut remote).wrap_err("Failed to upload backup")
+            })
+            .and_then(|size| {
+                let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+                sftp.rename(&partial_path, &remote_path, Some(flags))
+                    .wrap_err_with(|| format!("Failed to rename to {}", remote_path.display()))?;
+                Ok(size)
+            });
+        match uploaded {
+            Ok(size) => total += size,
+            Err(err) => {
+                let _ = sftp.unlink(&partial_path);
+                return Err(err);
+            }
+        }
+    }
+    Ok(total)
+}
+
+/// Fails unless the host key matches the configured fingerprint, or the known hosts file
+fn check_host_key(config: &SftpConfig, session: &Session) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| eyre!("No host key received"))?;
+    if let Some(expected) = &config.host_key_fingerprint {
+        let hash = session
+            .host_key_hash(HashType::Sha256)
+            .ok_or_else(|| eyre!("Failed to hash host key"))?;
+        let actual = fingerprint(hash);
+        if actual != expected.trim() {
+            bail!(
+                "Host key of {} doesn't match: expected {}, got {}",
+                config.host,
+                expected.trim(),
+                actual
+            );
+        }
+        return Ok(());
+    }
+    let path = match &config.known_hosts {
+        Some(path) => path.clone(),
+        None => std::env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".ssh/known_hosts"))
+            .ok_or_else(|| eyre!("No known-hosts set and HOME is unset"))?,
+    };
+    let mut known_hosts = session
+        .known_hosts()
+        .wrap_err("Failed to read known hosts")?;
+    known_hosts
+        .read_file(&path, KnownHostFileKind::OpenSSH)
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => bail!(
+            "Host key of {} not found in {}",
+            config.host,
+            path.display()
+        ),
+        CheckResult::Mismatch => bail!(
+            "Host key of {} doesn't match {}",
+            config.host,
+            path.display()
+        ),
+        CheckResult::Failure => bail!("Failed to check the host key of {}", config.host),
+    }
+}
+
+/// Formats a SHA-256 hash like OpenSSH, as `SHA256:` and unpadded base64
+fn fingerprint(hash: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::from("SHA256:");
+    for chunk in hash.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..=chunk.len() {
+            let sextet = (bits >> (18 - 6 * index)) & 0x3f;
+            encoded.push(ALPHABET[sextet as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_matches_openssh() {
+        // SHA-256 of an empty input, as `ssh-keygen -l` would print it
+        let hash = [
+            0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+            0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+            0x78, 0x52, 0xb8, 0x55,
+        ];
+        assert_eq!(
+            fingerprint(&hash),
+            "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::fs::File;
+use tokio::io::AsyncWrite;
+use tracing::debug;
+
+/// Path of a numbered volume of a split archive, `<name>.partNNN`
+pub fn part_path(path: &Path, part: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".part{part:03}"));
+    PathBuf::from(name)
+}
+
+/// Splits a volume file name into the archive name and the volume number
+pub fn strip_part(file_name: &str) -> Option<(&str, u32)> {
+    let (name, part) = file_name.rsplit_once(".part")?;
+    if part.len() < 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, part.parse().ok()?))
+}
+
+/// Existing volumes of a split archive, in order
+pub fn parts(path: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|part| part_path(path, part))
+        .take_while(|part| part.exists())
+        .collect()
+}
+
+/// Combined size of all volumes of a split archive, if there are any
+pub async fn total_size(path: &Path) -> Option<u64> {
+    let parts = parts(path);
+    if parts.is_empty() {
+        return None;
+    }
+    let mut total = 0;
+    for part in parts {
+        total += tokio::fs::metadata(part).await.ok()?.len();
+    }
+    Some(total)
+}
+
+type Opening = Pin<Box<dyn Future<Output = io::Result<File>> + Send>>;
+
+/// Writer rolling over to a new numbered volume whenever the current one reaches the split size
+pub struct SplitWriter {
+    path: PathBuf,
+    split_size: u64,
+    part: u32,
+    /// Bytes written to the current volume
+    written: u64,
+    current: Option<File>,
+    opening: Option<Opening>,
+}
+
+impl SplitWriter {
+    pub async fn create(path: &Path, split_size: u64) -> Result<Self> {
+        let first = part_path(path, 1);
+        let file = File::create(&first)
+            .await
+            .wrap_err("Failed to create output file!")?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            split_size: split_size.max(1),
+            part: 1,
+            written: 0,
+            current: Some(file),
+            opening: None,
+        })
+    }
+
+    /// Finishes opening the next volume, if one is being opened
+    fn poll_opening(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(opening) = &mut self.opening {
+            let file = ready!(opening.as_mut().poll(cx))?;
+            self.opening = None;
+            self.current = Some(file);
+            self.written = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn current(&mut self) -> io::Result<Pin<&mut File>> {
+        self.current
+            .as_mut()
+            .map(Pin::new)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No open volume"))
+    }
+}
+
+impl AsyncWrite for SplitWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_opening(cx))?;
+            let remaining = this.split_size - this.written;
+            if remaining == 0 {
+                // Only start the next volume once there's data for it
+                ready!(this.current()?.poll_shutdown(cx))?;
+                this.current = None;
+                this.part += 1;
+                let next = part_path(&this.path, this.part);
+                debug!("Starting volume {}", next.display());
+                this.opening = Some(Box::pin(File::create(next)));
+                continue;
+            }
+            let len = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+            let written = ready!(this.current()?.poll_write(cx, &buf[..len]))?;
+            this.written += written as u64;
+            return Poll::Ready(Ok(written));
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_opening(cx))?;
+        this.current()?.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_opening(cx))?;
+        this.current()?.poll_shutdown(cx)
+    }
+}
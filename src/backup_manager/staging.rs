@@ -0,0 +1,106 @@
+use super::output;
+use crate::configs::BackupsConfig;
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use fs_extra::dir::CopyOptions;
+use nix::errno::Errno;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+/// Path the archive is built at before being moved to the output
+pub fn staged_path(staging_dir: &Path, output_path: &Path) -> Result<PathBuf> {
+    let name = output_path
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid backup name"))?;
+    Ok(staging_dir.join(name))
+}
+
+/// Moves a staged backup to its final output, copying if it's on another filesystem
+pub async fn move_to_output(
+    staged: &Path,
+    output_path: &Path,
+    config: &BackupsConfig,
+) -> Result<()> {
+    info!("Moving backup from staging to {}", output_path.display());
+    if output::is_remote(output_path) {
+        upload(staged, output_path, config).await?;
+        remove(staged).await;
+        return Ok(());
+    }
+    match tokio::fs::rename(staged, output_path).await {
+        Ok(()) => return Ok(()),
+        Err(err) if err.raw_os_error() == Some(Errno::EXDEV as i32) => {
+            debug!("Staging directory on another filesystem, copying");
+        }
+        Err(err) => return Err(err).wrap_err("Failed to move staged backup"),
+    }
+    let result = copy(staged.to_path_buf(), output_path.to_path_buf()).await;
+    if result.is_err() {
+        remove(output_path).await;
+    }
+    result.wrap_err("Failed to copy staged backup")?;
+    remove(staged).await;
+    Ok(())
+}
+
+async fn copy(staged: PathBuf, output_path: PathBuf) -> Result<()> {
+    if staged.is_dir() {
+        let parent = output_path
+            .parent()
+            .ok_or_else(|| eyre!("Invalid output path"))?
+            .to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            fs_extra::dir::copy(&staged, parent, &CopyOptions::new())
+                .map_err(|err| eyre!("{}", err))
+        })
+        .await??;
+    } else {
+        tokio::fs::copy(&staged, &output_path).await?;
+    }
+    Ok(())
+}
+
+async fn upload(staged: &Path, output_path: &Path, config: &BackupsConfig) -> Result<()> {
+    let mut file = File::open(staged)
+        .await
+        .wrap_err("Failed to open staged backup")?;
+    let mut writer = output::open(output_path, config).await?;
+    tokio::io::copy(&mut file, &mut writer)
+        .await
+        .wrap_err("Failed to upload staged backup")?;
+    writer
+        .shutdown()
+        .await
+        .wrap_err("Failed to upload staged backup")?;
+    Ok(())
+}
+
+/// Removes a staged (or partially copied) backup, logging failures
+pub async fn remove(path: &Path) {
+    let result = if path.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    };
+    report_removal(path, result);
+}
+
+/// Like `remove`, for places that can't await, e.g. drop handlers
+pub fn remove_now(path: &Path) {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    report_removal(path, result);
+}
+
+fn report_removal(path: &Path, result: std::io::Result<()>) {
+    match result {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => warn!(?err, "Failed to remove {}", path.display()),
+    }
+}
@@ -0,0 +1,95 @@
+use super::compressor::METADATA_ENTRY;
+use crate::configs::{BackupFileType, BackupsConfig};
+use async_compression::tokio::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use async_zip::read::fs::ZipFileReader;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use globwalk::GlobWalkerBuilder;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, BufReader};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+/// Reads back every entry of the archive and checks the number of files
+pub async fn verify(backup_config: &BackupsConfig, archive: &Path, expected: u64) -> Result<()> {
+    if backup_config.encryption.is_some() {
+        warn!("Encrypted backups can't be verified");
+        return Ok(());
+    }
+    let found = match &backup_config.file_type {
+        BackupFileType::Zip => count_zip(archive).await?,
+        BackupFileType::TarGz | BackupFileType::TarGzFast | BackupFileType::TarGzSmall => {
+            let reader = BufReader::new(File::open(archive).await?);
+            count_tar(GzipDecoder::new(reader)).await?
+        }
+        BackupFileType::TarZstd | BackupFileType::TarZstdFast | BackupFileType::TarZstdSmall => {
+            let reader = BufReader::new(File::open(archive).await?);
+            count_tar(ZstdDecoder::new(reader)).await?
+        }
+        BackupFileType::TarXz => {
+            let reader = BufReader::new(File::open(archive).await?);
+            count_tar(XzDecoder::new(reader)).await?
+        }
+        BackupFileType::TarBz2 => {
+            let reader = BufReader::new(File::open(archive).await?);
+            count_tar(BzDecoder::new(reader)).await?
+        }
+        BackupFileType::Tar => count_tar(File::open(archive).await?).await?,
+        BackupFileType::Copy => count_directory(archive)?,
+    };
+    if found != expected {
+        bail!(
+            "Backup verification failed: expected {} files, found {}",
+            expected,
+            found
+        );
+    }
+    info!("Verified backup ({} files)", found);
+    Ok(())
+}
+
+async fn count_tar<R: AsyncRead + Unpin + Send>(reader: R) -> Result<u64> {
+    let mut archive = tokio_tar::Archive::new(reader);
+    let mut entries = archive.entries().wrap_err("Failed to read archive")?;
+    let mut count = 0;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.wrap_err("Corrupt archive entry")?;
+        if entry.path()?.as_ref() == Path::new(METADATA_ENTRY) {
+            continue;
+        }
+        tokio::io::copy(&mut entry, &mut tokio::io::sink())
+            .await
+            .wrap_err("Corrupt archive entry")?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+async fn count_zip(archive: &Path) -> Result<u64> {
+    let name = archive
+        .to_str()
+        .ok_or_else(|| eyre!("Invalid archive path"))?;
+    let reader = ZipFileReader::new(name.to_string())
+        .await
+        .wrap_err("Failed to open archive")?;
+    let total = reader.file().entries().len();
+    for index in 0..total {
+        let mut entry = reader.entry(index).await?;
+        tokio::io::copy(&mut entry, &mut tokio::io::sink())
+            .await
+            .wrap_err("Corrupt archive entry")?;
+    }
+    Ok(total as u64)
+}
+
+fn count_directory(path: &Path) -> Result<u64> {
+    let count = GlobWalkerBuilder::from_patterns(path, &["**"])
+        .build()
+        .wrap_err("Failed to create glob walker!")?
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file() || e.path_is_symlink())
+        .count();
+    Ok(count as u64)
+}
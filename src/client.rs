@@ -0,0 +1,256 @@
+use crate::backup_manager::{self, restore};
+use crate::configs::DolorousConfig;
+use crate::control::CONTROL_PREFIX;
+use clap::Subcommand;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::{Report, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::OwnedReadHalf;
+use tokio::net::UnixStream;
+
+#[derive(Subcommand, Debug, Deserialize, Serialize)]
+pub enum Command {
+    /// Run a backup in the running daemon
+    Backup {
+        /// Name of the backup
+        name: String,
+        /// Tag to label the backup with
+        #[arg(long)]
+        tag: Option<String>,
+        /// Only list the files that would be backed up, without the daemon
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show the state of the process and the progress of running backups
+    Status,
+    /// Run the actions of a task in the running daemon now, outside of its schedule
+    RunTask {
+        /// Name of the task
+        name: String,
+    },
+    /// Restore a backup into its location. The process has to be stopped.
+    Restore {
+        /// Name of the backup to restore the newest archive of, or the path of an archive
+        target: String,
+        /// Only consider backups with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Restore even if the process is running
+        #[arg(long)]
+        force: bool,
+        /// Back up the current state before restoring
+        #[arg(long)]
+        backup_first: bool,
+    },
+}
+
+/// Runs a client command against the running daemon
+pub async fn run(config: &DolorousConfig, command: Command) -> Result<()> {
+    if let Command::Backup {
+        name,
+        dry_run: true,
+        ..
+    } = &command
+    {
+        return dry_run(config, name);
+    }
+    let socket = config
+        .socket
+        .as_ref()
+        .ok_or_else(|| eyre!("No socket configured"))?;
+    let line = match command {
+        Command::Backup { name, tag, .. } => {
+            let mut args = vec!["backup", name.as_str()];
+            args.extend(tag.as_deref());
+            shell_words::join(args)
+        }
+        Command::Status => {
+            let processes = send_command(socket, "processes").await?;
+            let names: Vec<&str> = processes
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .collect();
+            if let [_] = names[..] {
+                println!("{}", send_command(socket, "status").await?);
+            } else {
+                for name in names {
+                    let status = send_command_to(socket, Some(name), "status").await?;
+                    println!("{name}: {status}");
+                }
+            }
+            "progress".into()
+        }
+        Command::RunTask { name } => shell_words::join(["run-task", name.as_str()]),
+        Command::Restore {
+            target,
+            tag,
+            force,
+            backup_first,
+        } => {
+            let options = RestoreOptions {
+                tag: tag.as_deref(),
+                force,
+                backup_first,
+            };
+            return restore(config, socket, &target, options).await;
+        }
+    };
+    let response = send_command(socket, &line).await?;
+    println!("{response}");
+    Ok(())
+}
+
+fn dry_run(config: &DolorousConfig, name: &str) -> Result<()> {
+    let files = backup_manager::dry_run(config, name)?;
+    for (path, size) in &files {
+        println!(
+            "{} ({})",
+            path.display(),
+            human_bytes::human_bytes(*size as f64)
+        );
+    }
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    println!(
+        "{} files, estimated uncompressed size: {}",
+        files.len(),
+        human_bytes::human_bytes(total as f64)
+    );
+    Ok(())
+}
+
+struct RestoreOptions<'a> {
+    tag: Option<&'a str>,
+    force: bool,
+    backup_first: bool,
+}
+
+async fn restore(
+    config: &DolorousConfig,
+    socket: &Path,
+    target: &str,
+    options: RestoreOptions<'_>,
+) -> Result<()> {
+    let (name, archive) = restore::find_archive(config, target, options.tag).await?;
+    let process = config
+        .backups
+        .get(&name)
+        .and_then(|backup| backup.process.as_deref());
+    let daemon_running = ensure_stopped(socket, process, options.force).await?;
+    if options.backup_first {
+        // Let the daemon run it if it's up, so backup hooks reach the process
+        let message = if daemon_running {
+            send_command(
+                socket,
+                &shell_words::join(["backup", name.as_str(), "pre-restore"]),
+            )
+            .await?
+        } else {
+            let path = backup_manager::run_backup(config, &name, Some("pre-restore")).await?;
+            format!("Backup created at {}", path.display())
+        };
+        println!("{message}");
+    }
+    println!("Restoring {}", archive.display());
+    restore::restore(config, &name, &archive).await?;
+    println!("Restore complete");
+    Ok(())
+}
+
+/// Fails unless the daemon reports the process as stopped, or isn't running at all.
+/// Returns whether the daemon is running.
+async fn ensure_stopped(socket: &Path, process: Option<&str>, force: bool) -> Result<bool> {
+    // A crash loop alert may follow the state
+    let state = send_command_to(socket, process, "state")
+        .await
+        .map(|state| state.lines().next().unwrap_or_default().to_string());
+    match state {
+        Ok(state) if state == "stopped" || state == "crash-looping" => Ok(true),
+        Ok(state) if force => {
+            println!("Process is {state}, restoring anyway");
+            Ok(true)
+        }
+        Ok(state) => bail!(
+            "Process is {}, stop it before restoring or use --force",
+            state
+        ),
+        Err(err) if daemon_not_running(&err) => {
+            println!("Daemon not running");
+            Ok(false)
+        }
+        Err(err) => Err(err.wrap_err("Unable to confirm the process is stopped")),
+    }
+}
+
+fn daemon_not_running(err: &Report) -> bool {
+    err.downcast_ref::<std::io::Error>().map_or(false, |err| {
+        matches!(
+            err.kind(),
+            ErrorKind::NotFound | ErrorKind::ConnectionRefused
+        )
+    })
+}
+
+/// Sends a control command over the socket and waits for its response
+pub async fn send_command(socket: &Path, command: &str) -> Result<String> {
+    send_command_to(socket, None, command).await
+}
+
+/// Sends a control command for a process over the socket and waits for its response.
+/// Without a process, the command goes to the default one.
+pub async fn send_command_to(
+    socket: &Path,
+    process: Option<&str>,
+    command: &str,
+) -> Result<String> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .wrap_err("Failed to connect to socket")
+        .map_err(|err| crate::permissions::explain(err, socket))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    if let Some(process) = process {
+        writer
+            .write_all(format!("{CONTROL_PREFIX}attach {process}\n").as_bytes())
+            .await?;
+        read_response(&mut lines).await?;
+    }
+    writer
+        .write_all(format!("{CONTROL_PREFIX}{command}\n").as_bytes())
+        .await?;
+    read_response(&mut lines).await
+}
+
+/// Reads the next control command response, skipping process output
+async fn read_response(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Result<String> {
+    let mut message = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        // Anything else is process output, which has a leading prefix doubled
+        let Some(reply) = line
+            .strip_prefix(CONTROL_PREFIX)
+            .filter(|reply| !reply.starts_with(CONTROL_PREFIX))
+        else {
+            continue;
+        };
+        let (success, rest) = if let Some(rest) = reply.strip_prefix("ok") {
+            (true, rest)
+        } else if let Some(rest) = reply.strip_prefix("err") {
+            (false, rest)
+        } else {
+            continue;
+        };
+        if let Some(text) = rest.strip_prefix('-') {
+            message.push(text.to_string());
+            continue;
+        }
+        message.push(rest.trim_start().to_string());
+        let message = message.join("\n");
+        if !success {
+            bail!(message);
+        }
+        return Ok(message);
+    }
+    bail!("Connection closed before a response was received")
+}
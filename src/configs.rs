@@ -1,27 +1,492 @@
+use bytesize::ByteSize;
+use chrono::{DateTime, Local};
+use chrono_tz::Tz;
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use nix::sys::signal::Signal;
+use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+/// Reads and parses the config file, with the files it includes
+pub fn load(path: &Path) -> Result<DolorousConfig> {
+    let value = read_with_includes(path, &mut Vec::new())?;
+    let mut config: DolorousConfig =
+        serde_yaml::from_value(value).wrap_err("Failed to read config!")?;
+    if let Some(process) = config.process.take() {
+        if config.processes.contains_key(DEFAULT_PROCESS) {
+            bail!(
+                "`process` conflicts with the process named {} in `processes`",
+                DEFAULT_PROCESS
+            );
+        }
+        config.processes.insert(DEFAULT_PROCESS.into(), process);
+    }
+    if config.processes.is_empty() {
+        bail!("No process configured");
+    }
+    for (name, task) in &config.tasks {
+        let forms = [
+            task.schedule.is_some(),
+            task.every.is_some(),
+            task.once.is_some(),
+        ];
+        if forms.iter().filter(|set| **set).count() != 1 {
+            bail!(
+                "Task {} needs exactly one of `schedule`, `every` and `once`",
+                name
+            );
+        }
+    }
+    expand_config(&mut config)?;
+    Ok(config)
+}
+
+/// Reads a config file and the files listed in its `include`, relative to it.
+/// The included files are merged in order, then the file itself on top of them.
+fn read_with_includes(path: &Path, including: &mut Vec<PathBuf>) -> Result<Value> {
+    let file = File::open(path)
+        .wrap_err_with(|| format!("Failed to read config {}", path.display()))
+        .map_err(|err| crate::permissions::explain(err, path))?;
+    let canonical = path
+        .canonicalize()
+        .wrap_err_with(|| format!("Failed to resolve {}", path.display()))?;
+    if including.contains(&canonical) {
+        let chain: Vec<_> = including
+            .iter()
+            .chain([&canonical])
+            .map(|path| path.display().to_string())
+            .collect();
+        bail!("Config include cycle: {}", chain.join(" -> "));
+    }
+    let mut value: Value = serde_yaml::from_reader(file)
+        .wrap_err_with(|| format!("Failed to read config {}", path.display()))?;
+    let includes = match &mut value {
+        Value::Mapping(mapping) => mapping.remove("include"),
+        _ => None,
+    };
+    let Some(includes) = includes else {
+        return Ok(value);
+    };
+    let includes: Vec<PathBuf> = serde_yaml::from_value(includes)
+        .wrap_err_with(|| format!("`include` of {} isn't a list of paths", path.display()))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+    including.push(canonical);
+    let mut merged = Value::Mapping(Mapping::new());
+    for include in includes {
+        merge(
+            &mut merged,
+            read_with_includes(&directory.join(include), including)?,
+        );
+    }
+    including.pop();
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merges maps by key, everything else in `overlay` replaces the value in `base`
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Name of the process configured with `process`, used when no process is named
+pub const DEFAULT_PROCESS: &str = "default";
+
+/// Expands environment variables in the fields that support them, see `expand_env`:
+/// - processes: `command`, `search-path`, `working-directory`, values of `env`,
+///   `crash-log-directory`, `command-history-file`, the rcon `password` and file log target paths
+/// - backups: `output`, `location`, `staging-dir`, `event-file`, the s3 `endpoint`,
+///   `access-key` and `secret-key`, and the sftp `host`, `user`, `key-path`, `remote-dir` and
+///   `known-hosts`
+/// - `socket`, `dump-directory`, the tcp socket and http `token`s
+/// - notifications: the webhook `url` and the email `host`, `username`, `password`, `from` and `to`
+///
+/// A `$` meant literally, e.g. in a password or token, has to be written as `$$`.
+fn expand_config(config: &mut DolorousConfig) -> Result<()> {
+    for (name, process) in &mut config.processes {
+        expand_process(process).wrap_err_with(|| format!("Invalid process {name}"))?;
+    }
+    for (name, backup) in &mut config.backups {
+        expand_backup(backup).wrap_err_with(|| format!("Invalid backup {name}"))?;
+    }
+    expand_optional_path(&mut config.socket).wrap_err("Invalid socket")?;
+    expand_optional_path(&mut config.dump_directory).wrap_err("Invalid dump directory")?;
+    if let Some(tcp_socket) = &mut config.tcp_socket {
+        expand_string(&mut tcp_socket.token).wrap_err("Invalid tcp socket token")?;
+    }
+    if let Some(http) = &mut config.http {
+        expand_string(&mut http.token).wrap_err("Invalid http token")?;
+    }
+    if let Some(webhook) = &mut config.notifications.webhook {
+        expand_string(&mut webhook.url).wrap_err("Invalid webhook url")?;
+    }
+    if let Some(email) = &mut config.notifications.email {
+        expand_string(&mut email.host).wrap_err("Invalid email host")?;
+        expand_optional_string(&mut email.username).wrap_err("Invalid email username")?;
+        expand_optional_string(&mut email.password).wrap_err("Invalid email password")?;
+        expand_string(&mut email.from).wrap_err("Invalid email sender")?;
+        for to in &mut email.to {
+            expand_string(to).wrap_err("Invalid email recipient")?;
+        }
+    }
+    Ok(())
+}
+
+fn expand_process(process: &mut ProcessConfig) -> Result<()> {
+    expand_string(&mut process.command).wrap_err("Invalid command")?;
+    expand_optional_string(&mut process.search_path).wrap_err("Invalid search path")?;
+    expand_path(&mut process.working_directory).wrap_err("Invalid working directory")?;
+    for (name, value) in &mut process.env {
+        expand_string(value).wrap_err_with(|| format!("Invalid environment variable {name}"))?;
+    }
+    expand_optional_path(&mut process.crash_log_directory)?;
+    expand_optional_path(&mut process.command_history_file)?;
+    if let Some(rcon) = &mut process.rcon {
+        expand_string(&mut rcon.password).wrap_err("Invalid rcon password")?;
+    }
+    for target in &mut process.log_targets {
+        if let LogTarget::File { path, .. } = target {
+            expand_path(path).wrap_err("Invalid log file")?;
+        }
+    }
+    Ok(())
+}
+
+fn expand_backup(backup: &mut BackupsConfig) -> Result<()> {
+    expand_path(&mut backup.output).wrap_err("Invalid output")?;
+    expand_path(&mut backup.location).wrap_err("Invalid location")?;
+    expand_optional_path(&mut backup.staging_dir).wrap_err("Invalid staging dir")?;
+    expand_optional_path(&mut backup.event_file).wrap_err("Invalid event file")?;
+    if let Some(s3) = &mut backup.s3 {
+        expand_optional_string(&mut s3.endpoint)?;
+        expand_optional_string(&mut s3.access_key)?;
+        expand_optional_string(&mut s3.secret_key)?;
+    }
+    if let Some(sftp) = &mut backup.sftp {
+        expand_string(&mut sftp.host)?;
+        expand_string(&mut sftp.user)?;
+        expand_path(&mut sftp.key_path)?;
+        expand_path(&mut sftp.remote_dir)?;
+        expand_optional_path(&mut sftp.known_hosts)?;
+    }
+    Ok(())
+}
+
+/// Replaces `$NAME` and `${NAME}` with the value of the environment variable, or with
+/// `${NAME:-default}` the default if it isn't set. `$$` is a literal `$`.
+/// Fails if a variable without a default isn't set.
+fn expand_env(text: &str) -> Result<String> {
+    let pattern = Regex::new(r"\$\$|\$\{(\w+)(?::-([^}]*))?\}|\$(\w+)").expect("Invalid pattern");
+    let mut missing = None;
+    let expanded = pattern.replace_all(text, |captures: &Captures| {
+        let Some(name) = captures.get(1).or_else(|| captures.get(3)) else {
+            return "$".to_string();
+        };
+        let name = name.as_str();
+        match (std::env::var(name), captures.get(2)) {
+            (Ok(value), _) => value,
+            (Err(_), Some(default)) => default.as_str().to_string(),
+            (Err(_), None) => {
+                missing.get_or_insert_with(|| name.to_string());
+                String::new()
+            }
+        }
+    });
+    if let Some(name) = missing {
+        bail!("Environment variable {} is not set", name);
+    }
+    Ok(expanded.into_owned())
+}
+
+fn expand_string(text: &mut String) -> Result<()> {
+    *text = expand_env(text)?;
+    Ok(())
+}
+
+fn expand_optional_string(text: &mut Option<String>) -> Result<()> {
+    match text {
+        Some(text) => expand_string(text),
+        None => Ok(()),
+    }
+}
+
+/// Paths that aren't valid UTF-8 are left as they are
+fn expand_path(path: &mut PathBuf) -> Result<()> {
+    if let Some(text) = path.to_str() {
+        *path = PathBuf::from(expand_env(text)?);
+    }
+    Ok(())
+}
+
+fn expand_optional_path(path: &mut Option<PathBuf>) -> Result<()> {
+    match path {
+        Some(path) => expand_path(path),
+        None => Ok(()),
+    }
+}
+
+/// Fields hidden by `dump` when redacting, by name
+const SECRET_FIELDS: [&str; 7] = [
+    "token",
+    "username",
+    "password",
+    "passphrase",
+    "access-key",
+    "secret-key",
+    // Webhook URLs, e.g. Discord's, contain their token
+    "url",
+];
+/// Maps whose values are all hidden by `dump` when redacting, by name.
+/// Environment variables often carry credentials.
+const SECRET_MAPS: [&str; 1] = ["env"];
+
+/// The config as YAML, optionally with secrets replaced by a placeholder
+pub fn dump(config: &DolorousConfig, redact_secrets: bool) -> Result<String> {
+    let mut value = serde_yaml::to_value(config).wrap_err("Failed to serialize config")?;
+    if redact_secrets {
+        redact(&mut value);
+    }
+    serde_yaml::to_string(&value).wrap_err("Failed to serialize config")
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                let key = key.as_str().unwrap_or_default();
+                match value {
+                    Value::Mapping(entries) if SECRET_MAPS.contains(&key) => {
+                        for (_, value) in entries.iter_mut() {
+                            *value = Value::String("<redacted>".into());
+                        }
+                    }
+                    Value::Null => {}
+                    _ if SECRET_FIELDS.contains(&key) => {
+                        *value = Value::String("<redacted>".into());
+                    }
+                    _ => redact(value),
+                }
+            }
+        }
+        Value::Sequence(sequence) => sequence.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Compares two config values by their serialized form
+pub fn same<T: Serialize>(a: &T, b: &T) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct DolorousConfig {
     pub socket: Option<PathBuf>,
+    /// Permissions of the socket file in octal, e.g. `660`
+    pub socket_mode: Option<String>,
+    /// Group owning the socket file, by name or gid
+    pub socket_group: Option<String>,
+    /// Users and groups allowed to use the socket
+    #[serde(default)]
+    pub socket_access: SocketAccessConfig,
+    /// Most lines of cached output replayed to clients. Everything cached if unset.
+    pub replay_lines: Option<usize>,
+    /// Directory `dump-output` writes to. The command is disabled if unset.
+    pub dump_directory: Option<PathBuf>,
+    /// Control socket over TCP, for tools that can't reach the Unix socket
+    pub tcp_socket: Option<TcpSocketConfig>,
+    /// HTTP API for status and control. Needs the `http` feature.
+    pub http: Option<HttpConfig>,
+    /// Prometheus metrics endpoint. Needs the `prometheus` feature.
+    pub metrics: Option<MetricsConfig>,
     #[serde(default = "default_log_filter")]
     pub log_filter: String,
-    pub process: ProcessConfig,
+    /// A single process, the same as a process named `default` in `processes`
+    pub process: Option<ProcessConfig>,
+    /// Processes by name. Moved here from `process` on load.
+    #[serde(default)]
+    pub processes: HashMap<String, ProcessConfig>,
     pub tasks: HashMap<String, TaskConfig>,
     pub backups: HashMap<String, BackupsConfig>,
+    /// Maximum number of backups running at once. Further backups wait for a slot.
+    pub max_concurrent_backups: Option<usize>,
+    /// Time a scheduled backup may be late before it's reported as stale
+    #[serde(with = "humantime_serde", default = "default_backup_grace")]
+    pub backup_grace: Duration,
+    /// Where to report crashes, restarts and backup results
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotificationsConfig {
+    pub webhook: Option<WebhookConfig>,
+    pub email: Option<EmailConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebhookConfig {
+    /// URL the notifications are POSTed to
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Events to send. All of them if empty.
+    #[serde(default)]
+    pub events: Vec<NotificationEvent>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EmailConfig {
+    /// SMTP server to send through
+    pub host: String,
+    /// 587 with `starttls`, 465 with `tls` and 25 without security by default
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    /// Environment variables are expanded, write a literal `$` as `$$`
+    pub password: Option<String>,
+    /// Sender address, e.g. `Dolorous <dolorous@example.com>`
+    pub from: String,
+    pub to: Vec<String>,
+    /// Events to send, by default crash loops, failed starts and failed backups.
+    /// All of them if empty.
+    #[serde(default = "default_email_events")]
+    pub events: Vec<NotificationEvent>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with STARTTLS, usually on port 587
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// Unencrypted, only for local relays
+    None,
+}
+
+impl SmtpSecurity {
+    /// Port used if none is configured
+    pub fn default_port(self) -> u16 {
+        match self {
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookFormat {
+    /// The event as a JSON object
+    Json,
+    /// A Discord webhook message
+    Discord,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationEvent {
+    /// The process exited with a non-zero exit code
+    Crash,
+    /// The process was started again after exiting
+    Restart,
+    BackupSuccess,
+    BackupFailure,
+    /// The process kept crashing and won't be restarted
+    CrashLoop,
+    /// The process kept exiting during startup and won't be started again
+    StartupFailure,
+}
+
+/// Peers allowed to connect to the Unix socket, by their credentials.
+/// The user the daemon runs as is always allowed, and without lists only it is.
+/// A peer is in an allowed group if it's its primary group or one of its user's
+/// supplementary groups. The check comes on top of the socket file permissions, so a
+/// group has to be let in by both, e.g. with `socket-group` and `socket-mode: 660`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SocketAccessConfig {
+    #[serde(default)]
+    pub allowed_uids: Vec<u32>,
+    #[serde(default)]
+    pub allowed_gids: Vec<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TcpSocketConfig {
+    #[serde(default = "default_tcp_address")]
+    pub address: IpAddr,
+    pub port: u16,
+    /// Shared secret clients have to send as their first line.
+    /// Environment variables are expanded, write a literal `$` as `$$`.
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    #[serde(default = "default_tcp_address")]
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HttpConfig {
+    #[serde(default = "default_tcp_address")]
+    pub address: IpAddr,
+    pub port: u16,
+    /// Bearer token required in the `Authorization` header, must not be empty.
+    /// Environment variables are expanded, write a literal `$` as `$$`.
+    pub token: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct ProcessConfig {
     pub command: String,
+    /// Directories to search for the command, in `PATH` format. Defaults to the inherited `PATH`.
+    pub search_path: Option<String>,
+    /// Environment variables set for the process
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Start the process with an empty environment, besides `env` and `search-path`
+    #[serde(default)]
+    pub env_clear: bool,
     #[serde(default = "default_cache_size")]
     pub cache_size: u32,
+    /// Size of an extra cache with only stderr. Stderr is still written to the output cache.
+    pub stderr_cache_size: Option<u32>,
     pub restart: RestartCondition,
     pub stop_config: StopProperties,
+    /// Created if missing. `$NAME` and `${NAME}` are replaced with environment variables.
     #[cfg_attr(feature = "docker", serde(default = "default_wroking_directory"))]
     pub working_directory: PathBuf,
     #[serde(default = "default_restart_attempts")]
@@ -31,39 +496,444 @@ pub struct ProcessConfig {
     /// Delay after witch the startup is considered done. Restart attempt counter is reset.
     #[serde(with = "humantime_serde", default = "default_watch_delay")]
     pub watch_delay: Duration,
+    /// Regex matched against the output during startup. Once a line matches, the startup is
+    /// considered done without waiting for the rest of `watch-delay`.
+    pub ready_pattern: Option<String>,
+    /// Stop the process after it has been idle for this long. Counting starts once the process
+    /// is running, and again when the output matches `idle-pattern`. It's stopped like on a stop
+    /// request, so `restart` doesn't apply; a start request or task starts it again.
+    #[serde(with = "humantime_serde", default)]
+    pub idle_timeout: Option<Duration>,
+    /// Regex marking the process as idle, e.g. when the last player left
+    pub idle_pattern: Option<String>,
+    /// Regex marking activity, e.g. a player joining. Stops counting until `idle-pattern` matches,
+    /// or without an `idle-pattern`, restarts counting.
+    pub active_pattern: Option<String>,
+    /// Restart the process after it has been running for this long
+    #[serde(with = "humantime_serde", default)]
+    pub max_uptime: Option<Duration>,
+    /// Commands sent to stdin only on the first start of the process.
+    #[serde(default)]
+    pub first_start_commands: Vec<String>,
+    /// Commands sent to stdin on every start of the process.
+    #[serde(default)]
+    pub startup_commands: Vec<String>,
+    /// Keep the process stdin open until it exits, even without any clients.
+    #[serde(default)]
+    pub keep_stdin_open: bool,
+    /// Stop the process once its stdin is closed, for programs that exit when their console is
+    /// detached. It's given `term-timeout` to exit by itself, then signalled like on a stop.
+    #[serde(default)]
+    pub stop_on_stdin_eof: bool,
+    /// Run the process in a pseudo-terminal instead of pipes.
+    /// Stdout and stderr can't be told apart then, all output counts as stdout.
+    #[serde(default)]
+    pub pty: bool,
+    /// Capacity of the stdout/stderr read buffers, in bytes
+    #[serde(default = "default_read_buffer_size")]
+    pub read_buffer_size: usize,
+    /// Longer output lines are truncated, so a line without end can't use up all memory
+    #[serde(default = "default_max_line_length")]
+    pub max_line_length: usize,
+    /// Where the process output is written to, besides connected clients
+    #[serde(default = "default_log_targets")]
+    pub log_targets: Vec<LogTarget>,
+    /// Prefixes of output lines in the cache and log files
+    #[serde(default)]
+    pub line_format: LineFormat,
+    /// Identifier of the process output in syslog and journald
+    #[serde(default = "default_log_identifier")]
+    pub log_identifier: String,
+    /// Maximum number of output lines per second forwarded to clients
+    pub output_rate_limit: Option<u32>,
+    /// Directory to write the output cache to when the process crashes
+    pub crash_log_directory: Option<PathBuf>,
+    /// Maximum address space of the process. Allocations beyond it fail.
+    /// The JVM reserves more address space than it uses, so leave room above the heap size.
+    pub memory_limit: Option<ByteSize>,
+    /// Maximum CPU time the process may use in total. It's killed once it's used up.
+    #[serde(with = "humantime_serde", default)]
+    pub cpu_limit: Option<Duration>,
+    /// Restart the process if it stops responding
+    pub health_check: Option<HealthCheckConfig>,
+    /// Stop restarting the process if it keeps crashing. It's only started again on request.
+    pub crash_loop: Option<CrashLoopConfig>,
+    /// Number of recent exits listed by the status command
+    #[serde(default = "default_exit_history_size")]
+    pub exit_history_size: usize,
+    /// Let a graceful restart run the new process next to the old one until it passed
+    /// the watch delay. Only for processes that can run twice, e.g. that don't bind a port
+    /// or lock their files. Otherwise `graceful-restart` stops the process and starts it again.
+    #[serde(default)]
+    pub graceful_restart: bool,
+    /// Lines of output buffered for each socket client. Clients falling further behind
+    /// are told how many lines they missed.
+    #[serde(default = "default_output_buffer_lines")]
+    pub output_buffer_lines: usize,
+    /// Number of lines sent to the process kept for the history command
+    #[serde(default = "default_command_history_size")]
+    pub command_history_size: usize,
+    /// Keeps the command history across restarts of dolorous
+    pub command_history_file: Option<PathBuf>,
+    /// Send commands over RCON instead of stdin, to get their responses
+    pub rcon: Option<RconConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RconConfig {
+    #[serde(default = "default_tcp_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_rcon_port")]
+    pub port: u16,
+    /// Environment variables are expanded, write a literal `$` as `$$`
+    pub password: String,
+    /// Time a command may take, connecting included
+    #[serde(with = "humantime_serde", default = "default_rcon_timeout")]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheckConfig {
+    #[serde(flatten)]
+    pub probe: HealthProbe,
+    #[serde(with = "humantime_serde", default = "default_health_check_interval")]
+    pub interval: Duration,
+    /// Time the probe may take before it counts as failed
+    #[serde(with = "humantime_serde", default = "default_health_check_timeout")]
+    pub timeout: Duration,
+    /// Number of failed probes in a row after which the process is restarted
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub struct LineFormat {
+    /// Prefix lines with an ISO-8601 timestamp
+    #[serde(default)]
+    pub timestamp: bool,
+    /// Prefix lines with their stream, `[stdout]` or `[stderr]`
+    #[serde(default)]
+    pub stream: bool,
+    /// Send prefixed lines to socket clients as well, instead of the raw output
+    #[serde(default)]
+    pub socket: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CrashLoopConfig {
+    /// Number of exits within the window that count as a crash loop. Only exits with a
+    /// non-zero code or by a signal count.
+    #[serde(default = "default_crash_loop_exits")]
+    pub max_exits: usize,
+    #[serde(with = "humantime_serde", default = "default_crash_loop_window")]
+    pub window: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub enum HealthProbe {
+    /// Connects to a TCP port
+    Tcp {
+        #[serde(default = "default_health_check_host")]
+        host: String,
+        port: u16,
+    },
+    /// Sends a command to stdin and waits for output matching the pattern
+    Command { command: String, pattern: String },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub enum LogTarget {
+    /// The output cache, sent to newly connected clients
+    Cache,
+    /// Stdout is logged as info, stderr as warning
+    Syslog,
+    /// Stdout is logged as info, stderr as warning
+    Journald,
+    /// Appends the output to a file, rotating it once it reaches `max-size`
+    File {
+        path: PathBuf,
+        max_size: Option<ByteSize>,
+        /// Number of rotated files to keep, as `<path>.1` (newest) to `<path>.<max-files>`
+        #[serde(default = "default_max_log_files")]
+        max_files: usize,
+    },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct BackupsConfig {
+    /// Directory to write backups to, or an `s3://bucket/prefix` URL to upload them to
     pub output: PathBuf,
     pub location: PathBuf,
     #[serde(default = "default_time_format")]
     pub time_format: String,
+    /// Name template. Available variables: `{date}`, `{extension}`, `{tag}`, `{hostname}`,
+    /// `{backup_name}` and `{counter}`, an index increasing with every backup
     #[serde(default = "default_name")]
     pub name: String,
     #[serde(default)]
     pub file_type: BackupFileType,
+    /// Incremental backups only contain files changed since the previous backup
+    #[serde(default)]
+    pub backup_mode: BackupMode,
+    /// Overrides the compression level of the file type (tar.zst, tar.xz and tar.bz2)
+    pub compression_level: Option<u32>,
+    /// Process the backup commands are sent to. Only needed with multiple processes.
+    pub process: Option<String>,
+    /// Sent to the process before the backup, e.g. `save-off`
+    pub pre_command: Option<String>,
+    /// Time to wait after the pre command, to let the process finish saving
+    #[serde(with = "humantime_serde", default = "default_pre_command_delay")]
+    pub pre_command_delay: Duration,
+    /// Sent to the process after the backup, even if it failed, e.g. `save-on`
+    pub post_command: Option<String>,
+    /// Globs of files to back up, relative to `location`
     pub files: Vec<String>,
+    /// How symlinks are handled
+    #[serde(default)]
+    pub symlinks: SymlinkMode,
+    /// Globs of files to leave out, relative to `location`.
+    /// A file matching both `files` and `exclude` is excluded.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Embed backup metadata into the archive (zip comment / tar entry)
+    #[serde(default)]
+    pub embed_metadata: bool,
+    /// Sync the backup to stable storage before reporting success
+    #[serde(default)]
+    pub durable: bool,
+    /// Flush the compression encoder after this many bytes (tar based types only)
+    pub flush_threshold: Option<u64>,
+    /// Number of files read ahead in parallel (zip and copy only)
+    #[serde(default = "default_read_workers")]
+    pub read_workers: usize,
+    /// Store files with identical contents only once, as hardlinks (tar based types only).
+    /// Restoring writes them as separate copies, other tools extract them as hardlinks.
+    #[serde(default)]
+    pub dedup: bool,
+    /// Hash files and report how much space duplicates take up
+    #[serde(default)]
+    pub report_duplicates: bool,
+    /// Number of most recent backups to keep
+    pub keep_last: Option<usize>,
+    /// Keep all backups younger than this
+    #[serde(with = "humantime_serde", default)]
+    pub keep_within: Option<Duration>,
+    /// Retention rules for tagged backups. Untagged backups and tags
+    /// without a rule use `keep-last` and `keep-within`.
+    #[serde(default)]
+    pub tag_retention: HashMap<String, RetentionRule>,
+    /// Encrypt the backup with age
+    pub encryption: Option<EncryptionConfig>,
+    /// Check for enough free space before starting the backup. The uncompressed size of the
+    /// files is compared against, so set `free-space-factor` below 1 for compressed backups.
+    #[serde(default)]
+    pub check_free_space: bool,
+    /// Required free space, as a multiple of the uncompressed size of the files to back up
+    #[serde(default = "default_free_space_factor")]
+    pub free_space_factor: f64,
+    /// Read the backup back after creating it, to check it isn't corrupt
+    #[serde(default)]
+    pub verify: bool,
+    /// Write a `sha256sum` compatible `.sha256` file next to the backup
+    #[serde(default)]
+    pub checksum: bool,
+    /// Build the backup here first, then move it to the output
+    pub staging_dir: Option<PathBuf>,
+    /// Endpoint and credentials for `s3://` outputs
+    pub s3: Option<S3Config>,
+    /// Split the archive into `<name>.partNNN` volumes of at most this size.
+    /// Reassemble them with `cat <name>.part* > <name>`.
+    pub split_size: Option<ByteSize>,
+    /// Append a JSON line describing the result of every backup to this file
+    pub event_file: Option<PathBuf>,
+    /// Upload finished backups to a remote host over SFTP
+    pub sftp: Option<SftpConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3Config {
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Custom endpoint for S3-compatible storage
+    pub endpoint: Option<String>,
+    /// Read from the environment or AWS profile if unset
+    pub access_key: Option<String>,
+    /// Environment variables are expanded, write a literal `$` as `$$`
+    pub secret_key: Option<String>,
+    /// Use path-style bucket addressing (needed by e.g. MinIO)
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_sftp_port")]
+    pub port: u16,
+    pub user: String,
+    /// Private key to authenticate with
+    pub key_path: PathBuf,
+    /// Directory on the remote host to upload backups into
+    pub remote_dir: PathBuf,
+    /// File the host key is checked against. Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+    /// SHA-256 fingerprint of the host key as shown by `ssh-keygen -l`, e.g. `SHA256:...`.
+    /// Checked instead of `known-hosts` if set.
+    pub host_key_fingerprint: Option<String>,
+    /// Keep the local backup after a successful upload. Without it, retention rules
+    /// have nothing to prune and uploaded backups have to be pruned on the remote host.
+    /// Incremental backups need the local copy.
+    #[serde(default = "default_true")]
+    pub keep_local: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetentionRule {
+    /// Number of most recent backups to keep
+    pub keep_last: Option<usize>,
+    /// Keep all backups younger than this
+    #[serde(with = "humantime_serde", default)]
+    pub keep_within: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EncryptionConfig {
+    /// Passphrase to encrypt with
+    pub passphrase: Option<String>,
+    /// Age public keys to encrypt to
+    #[serde(default)]
+    pub recipients: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct TaskConfig {
     /// When the task is scheduled. Uses cron syntax.
-    pub schedule: String,
+    /// Exactly one of `schedule`, `every` and `once` has to be set.
+    pub schedule: Option<String>,
+    /// Runs the task repeatedly with this interval, starting one interval after startup
+    #[serde(with = "humantime_serde", default)]
+    pub every: Option<Duration>,
+    /// Runs the task a single time, e.g. `2023-01-01T04:00:00+01:00`
+    pub once: Option<DateTime<Local>>,
+    /// Time zone the cron schedule is in, e.g. `UTC` or `Europe/Berlin`. Defaults to the local one.
+    pub timezone: Option<Tz>,
+    /// Run the task even if the process isn't running, e.g. while it restarts
+    #[serde(default)]
     pub run_if_stopped: bool,
+    /// Process the actions act on. Only needed with multiple processes.
+    pub process: Option<String>,
     pub actions: Vec<ActionType>,
+    /// What to do with actions that need a running process when there is none
+    #[serde(default)]
+    pub when_unavailable: UnavailablePolicy,
+    /// What to do with the remaining actions once one failed
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+    /// Longest time an action may take before it's aborted and the next one runs
+    #[serde(with = "humantime_serde", default)]
+    pub action_timeout: Option<Duration>,
+    /// Longest time all actions together may take. Remaining actions are skipped after it.
+    #[serde(with = "humantime_serde", default)]
+    pub timeout: Option<Duration>,
+    /// Number of recent runs listed by the status command
+    #[serde(default = "default_task_history_size")]
+    pub history_size: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnavailablePolicy {
+    /// Skip the action silently
+    Skip,
+    /// Fail the action
+    Error,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorPolicy {
+    /// Run the remaining actions anyway
+    Continue,
+    /// Skip the remaining actions, e.g. no backup if saving couldn't be turned off
+    Abort,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum ActionType {
-    Backup { backup: String },
-    Command { command: String },
+    Backup {
+        backup: String,
+        /// Tag inserted into the name as `{tag}`, used to select retention rules
+        #[serde(default)]
+        tag: Option<String>,
+    },
+    Command {
+        command: String,
+        /// Write the command exactly as is, without trimming it or adding a newline
+        #[serde(default)]
+        raw: bool,
+    },
     Start,
     Stop,
     Restart,
+    /// Starts a new process and stops the old one once the new one is up
+    GracefulRestart,
+    /// Sends a signal to the process, e.g. `SIGHUP`
+    Signal {
+        signal: String,
+    },
+    /// Sends a command and waits for an output line matching the regex, e.g. `Saved the game`.
+    /// Fails if no line matched within the timeout.
+    SendAndWait {
+        command: String,
+        pattern: String,
+        #[serde(with = "humantime_serde", default = "default_send_and_wait_timeout")]
+        timeout: Duration,
+    },
+    /// Does nothing for a while, e.g. to let the process flush its data
+    Wait {
+        #[serde(with = "humantime_serde")]
+        duration: Duration,
+    },
+    /// Waits until an output line of the process matches the regex
+    WaitFor {
+        pattern: String,
+        /// Fail the action if no line matched in time
+        #[serde(with = "humantime_serde", default)]
+        timeout: Option<Duration>,
+    },
+    /// Runs one of the branches depending on the condition
+    If {
+        condition: Condition,
+        then: Vec<ActionType>,
+        #[serde(default)]
+        r#else: Vec<ActionType>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub enum Condition {
+    /// Process is running
+    Running,
+    /// Process is stopped
+    Stopped,
+    /// Last exit code of the process matches
+    ExitCode { exit_code: i32 },
+    /// Inverts the inner condition
+    Not { condition: Box<Condition> },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -73,10 +943,49 @@ pub struct StopProperties {
     pub stop_command: String,
     #[serde(with = "humantime_serde", default = "default_duration")]
     pub term_timeout: Duration,
+    /// Sent if the stop command doesn't stop the process within `term-timeout`
+    #[serde(with = "signal_name", default = "default_term_signal")]
+    pub term_signal: Signal,
+    /// Time after the term signal until the process is killed with `SIGKILL`
     #[serde(with = "humantime_serde", default = "default_duration")]
     pub kill_timeout: Duration,
 }
 
+/// (De)serializes signals by name, with or without the `SIG` prefix
+mod signal_name {
+    use nix::sys::signal::Signal;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(signal: &Signal, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(signal.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signal, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        crate::process::parse_signal(&name).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkMode {
+    /// Back up the targets of symlinks
+    Follow,
+    /// Store symlinks themselves instead of their targets
+    Store,
+    /// Leave symlinks out
+    Skip,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupMode {
+    Full,
+    /// Falls back to a full backup if the newest backup has no manifest
+    Incremental,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum BackupFileType {
@@ -84,6 +993,11 @@ pub enum BackupFileType {
     TarGz,
     TarGzFast,
     TarGzSmall,
+    TarZstd,
+    TarZstdFast,
+    TarZstdSmall,
+    TarXz,
+    TarBz2,
     Tar,
     Copy,
 }
@@ -97,12 +1011,18 @@ pub enum RestartCondition {
     /// Non-zero exit code
     IfCrashed,
     Always,
+    /// Never restart, but alert and write a crash log on non-zero exit code
+    WarnOnly,
 }
 
 fn default_duration() -> Duration {
     Duration::from_secs(180)
 }
 
+fn default_backup_grace() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
 fn default_stop_command() -> String {
     "stop".into()
 }
@@ -115,15 +1035,61 @@ fn default_name() -> String {
     "{date}.{extension}".into()
 }
 
+fn default_pre_command_delay() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_free_space_factor() -> f64 {
+    1.0
+}
+
+fn default_read_workers() -> usize {
+    4
+}
+
+fn default_sftp_port() -> u16 {
+    22
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".into()
+}
+
 fn default_log_filter() -> String {
     "info".into()
 }
 
+fn default_log_targets() -> Vec<LogTarget> {
+    vec![LogTarget::Cache]
+}
+
+fn default_max_log_files() -> usize {
+    5
+}
+
+fn default_log_identifier() -> String {
+    "dolorous".into()
+}
+
 fn default_cache_size() -> u32 {
     // 8KiB
     2u32.pow(10) * 8
 }
 
+fn default_read_buffer_size() -> usize {
+    // 8KiB, same as the tokio default
+    2usize.pow(10) * 8
+}
+
+fn default_max_line_length() -> usize {
+    // 1MiB
+    2usize.pow(20)
+}
+
 fn default_restart_attempts() -> u16 {
     5
 }
@@ -138,12 +1104,116 @@ fn default_wroking_directory() -> PathBuf {
     PathBuf::from("/server")
 }
 
+fn default_term_signal() -> Signal {
+    Signal::SIGTERM
+}
+
 fn default_watch_delay() -> Duration {
     Duration::from_secs(60)
 }
 
+fn default_health_check_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_health_check_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_exit_history_size() -> usize {
+    10
+}
+
+fn default_crash_loop_exits() -> usize {
+    5
+}
+
+fn default_crash_loop_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_health_check_host() -> String {
+    "127.0.0.1".into()
+}
+
+fn default_tcp_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_output_buffer_lines() -> usize {
+    1024
+}
+
+fn default_command_history_size() -> usize {
+    100
+}
+
+fn default_rcon_port() -> u16 {
+    25575
+}
+
+fn default_rcon_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_send_and_wait_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_task_history_size() -> usize {
+    10
+}
+
+fn default_email_events() -> Vec<NotificationEvent> {
+    vec![
+        NotificationEvent::CrashLoop,
+        NotificationEvent::StartupFailure,
+        NotificationEvent::BackupFailure,
+    ]
+}
+
+impl Default for SymlinkMode {
+    fn default() -> Self {
+        Self::Follow
+    }
+}
+
+impl Default for BackupMode {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
 impl Default for BackupFileType {
     fn default() -> Self {
         Self::Zip
     }
 }
+
+impl Default for UnavailablePolicy {
+    fn default() -> Self {
+        Self::Error
+    }
+}
+
+impl Default for SmtpSecurity {
+    fn default() -> Self {
+        Self::Starttls
+    }
+}
+
+impl Default for WebhookFormat {
+    fn default() -> Self {
+        Self::Json
+    }
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self::Continue
+    }
+}
@@ -0,0 +1,345 @@
+use crate::process::{CrashLoopAlert, Process, ProcessStatus};
+use crate::CONFIG;
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use nix::fcntl::OFlag;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path};
+use std::time::Duration;
+
+/// Prefix marking a socket line as a control command instead of process input.
+/// Input and output lines starting with it are escaped by doubling it.
+pub const CONTROL_PREFIX: &str = "!";
+
+/// Executes a control command. Returns a human readable response.
+/// Process commands go to the named process, or the default one.
+pub async fn execute(line: &str, process: Option<&str>) -> Result<String> {
+    // Passed on as typed, without shell quoting
+    if let Some(text) = line.strip_prefix("command ") {
+        return command(text, process).await;
+    }
+    let args = shell_words::split(line).wrap_err("Invalid command")?;
+    let Some((command, args)) = args.split_first() else {
+        bail!("Empty command");
+    };
+    match command.as_str() {
+        "signal" => signal(args, process),
+        "dump-output" => dump_output(args, process).await,
+        "health" => health(),
+        "state" => state(process),
+        "status" => status(args, process),
+        "processes" => Ok(processes()),
+        "history" => history(args, process),
+        "progress" => Ok(progress()),
+        "cache" => cache(args, process),
+        "reload" => crate::reload::reload().await,
+        "reopen-socket" => crate::socket::reopen().await,
+        "graceful-restart" => graceful_restart(process),
+        "backup" => backup(args).await,
+        "run-task" => run_task(args).await,
+        _ => bail!("Unknown command: {}", command),
+    }
+}
+
+/// Sends a command to the process. Returns the RCON response, if sent over RCON.
+async fn command(text: &str, process: Option<&str>) -> Result<String> {
+    let process = crate::process::get(process)?;
+    let response = process.command(text, false).await?;
+    Ok(response.unwrap_or_else(|| "Sent to stdin".into()))
+}
+
+fn signal(args: &[String], process: Option<&str>) -> Result<String> {
+    let [name] = args else {
+        bail!("Usage: signal <name>");
+    };
+    let signal = crate::process::parse_signal(name)?;
+    crate::process::get(process)?.send_signal(signal)?;
+    Ok(format!("Sent {}", signal.as_str()))
+}
+
+async fn dump_output(args: &[String], process: Option<&str>) -> Result<String> {
+    let [name] = args else {
+        bail!("Usage: dump-output <file name>");
+    };
+    let directory = CONFIG
+        .load()
+        .as_ref()
+        .and_then(|config| config.dump_directory.clone())
+        .ok_or_else(|| eyre!("No dump-directory configured"))?;
+    // Only a file name, so clients can't write anywhere else
+    let mut components = Path::new(name).components();
+    let (Some(Component::Normal(file_name)), None) = (components.next(), components.next()) else {
+        bail!("Invalid file name: {}", name);
+    };
+    let path = directory.join(file_name);
+    let data = {
+        let process = crate::process::get(process)?;
+        let mut cache = process.output_cache.lock();
+        cache.extract().to_string()
+    };
+    let size = data.len();
+    let output = path.clone();
+    tokio::task::spawn_blocking(move || write_no_follow(&output, data.as_bytes()))
+        .await?
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    Ok(format!("Wrote {} bytes to {}", size, path.display()))
+}
+
+/// Writes a file, without following a symlink in its place out of the directory
+fn write_no_follow(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(OFlag::O_NOFOLLOW.bits())
+        .open(path)?
+        .write_all(data)
+}
+
+fn health() -> Result<String> {
+    let config = CONFIG.load_full().ok_or_else(|| eyre!("Missing config"))?;
+    let stale = crate::backup_manager::health::stale_backups(&config);
+    if !stale.is_empty() {
+        bail!("Stale backups: {}", stale.join(", "));
+    }
+    Ok("healthy".into())
+}
+
+fn graceful_restart(process: Option<&str>) -> Result<String> {
+    let process = crate::process::get(process)?;
+    process
+        .control
+        .send(crate::process::Controls::GracefulRestart)?;
+    Ok("Graceful restart requested".into())
+}
+
+/// Current state of the process, e.g. `running`. A crash loop alert follows on a second line.
+fn state(process: Option<&str>) -> Result<String> {
+    let status = crate::process::get(process)?.status.lock().clone();
+    Ok(match &status.crash_loop {
+        Some(alert) => format!("{}\n{}", status.state.as_str(), describe_alert(alert)),
+        None => status.state.as_str().to_string(),
+    })
+}
+
+fn describe_alert(alert: &CrashLoopAlert) -> String {
+    format!(
+        "CRASH LOOP since {}: {}",
+        alert.at.format("%Y-%m-%d %H:%M:%S"),
+        alert.message
+    )
+}
+
+/// Lines recently sent to the process, oldest first. Only the last `count` with a count.
+fn history(args: &[String], process: Option<&str>) -> Result<String> {
+    let count = match args {
+        [] => None,
+        [count] => Some(count.parse::<usize>().wrap_err("Usage: history [count]")?),
+        _ => bail!("Usage: history [count]"),
+    };
+    let lines = crate::process::get(process)?.history.lines();
+    let skip = count.map_or(0, |count| lines.len().saturating_sub(count));
+    Ok(lines
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(index, line)| format!("{:>5}  {}", index + 1, line))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// All managed processes with their state, one per line
+fn processes() -> String {
+    crate::process::names()
+        .into_iter()
+        .filter_map(|name| {
+            let process = crate::process::get(Some(&name)).ok()?;
+            let state = process.status.lock().state;
+            Some(format!("{} {}", name, state.as_str()))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// State, pid, uptime, start attempt, recent exits and recent task runs of the process,
+/// as text or with `json` as JSON
+fn status(args: &[String], process: Option<&str>) -> Result<String> {
+    let json = match args {
+        [] => false,
+        [format] if format == "json" => true,
+        _ => bail!("Usage: status [json]"),
+    };
+    let process = crate::process::get(process)?;
+    if json {
+        return Ok(status_json(&process).to_string());
+    }
+    let status = process.status.lock().clone();
+    let exits: Vec<_> = process.exit_history.lock().iter().cloned().collect();
+    let uptime = uptime(&status);
+    let mut details = Vec::new();
+    if let Some(pid) = status.pid {
+        details.push(format!("pid {pid}"));
+    }
+    if let Some(uptime) = uptime {
+        details.push(format!("up {}", humantime::format_duration(uptime)));
+    }
+    if let Some(attempt) = status.attempt {
+        details.push(format!("attempt {attempt}"));
+    }
+    if let Some(exit_code) = status.last_exit_code {
+        details.push(format!("last exit code {exit_code}"));
+    }
+    let mut lines = vec![if details.is_empty() {
+        status.state.as_str().to_string()
+    } else {
+        format!("{} ({})", status.state.as_str(), details.join(", "))
+    }];
+    if let Some(alert) = &status.crash_loop {
+        lines.push(describe_alert(alert));
+    }
+    if !exits.is_empty() {
+        lines.push("recent exits:".into());
+    }
+    for exit in exits.iter().rev() {
+        lines.push(format!(
+            "  {} pid {} exit code {}{}",
+            exit.at.format("%Y-%m-%d %H:%M:%S"),
+            exit.pid,
+            exit.exit_code,
+            if exit.restarted { ", restarted" } else { "" }
+        ));
+    }
+    for (name, runs) in crate::tasks::runs(&process.name) {
+        if runs.is_empty() {
+            lines.push(format!("task {name}: never run"));
+            continue;
+        }
+        lines.push(format!("task {name}:"));
+        for run in runs.iter().rev() {
+            let outcome = match &run.error {
+                Some(error) => format!("failed: {error}"),
+                None => "ok".into(),
+            };
+            lines.push(format!(
+                "  {} took {} {}",
+                run.at.format("%Y-%m-%d %H:%M:%S"),
+                humantime::format_duration(Duration::from_secs(run.duration.as_secs())),
+                outcome
+            ));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Status of the process as JSON, see `status`
+pub fn status_json(process: &Process) -> serde_json::Value {
+    let status = process.status.lock().clone();
+    let exits: Vec<_> = process.exit_history.lock().iter().cloned().collect();
+    let tasks: serde_json::Map<String, serde_json::Value> = crate::tasks::runs(&process.name)
+        .into_iter()
+        .map(|(name, runs)| {
+            let runs = runs.iter().map(|run| {
+                serde_json::json!({
+                    "at": run.at.to_rfc3339(),
+                    "duration-secs": run.duration.as_secs_f64(),
+                    "ok": run.error.is_none(),
+                    "error": run.error,
+                })
+            });
+            (name, serde_json::Value::Array(runs.collect()))
+        })
+        .collect();
+    serde_json::json!({
+        "process": process.name,
+        "state": status.state.as_str(),
+        "pid": status.pid,
+        "uptime-secs": uptime(&status).map(|uptime| uptime.as_secs()),
+        "attempt": status.attempt,
+        "last-exit-code": status.last_exit_code,
+        "crash-loop": status.crash_loop.as_ref().map(|alert| serde_json::json!({
+            "at": alert.at.to_rfc3339(),
+            "message": alert.message,
+        })),
+        "exits": exits.iter().map(|exit| serde_json::json!({
+            "pid": exit.pid,
+            "exit-code": exit.exit_code,
+            "at": exit.at.to_rfc3339(),
+            "restarted": exit.restarted,
+        })).collect::<Vec<_>>(),
+        "tasks": tasks,
+    })
+}
+
+/// Time since the current child was started, in whole seconds
+fn uptime(status: &ProcessStatus) -> Option<Duration> {
+    status
+        .started_at
+        .map(|started_at| Duration::from_secs(started_at.elapsed().as_secs()))
+}
+
+fn progress() -> String {
+    let running = crate::backup_manager::progress::describe();
+    if running.is_empty() {
+        return "no backups running".into();
+    }
+    running.join("\n")
+}
+
+fn cache(args: &[String], process: Option<&str>) -> Result<String> {
+    let stderr = match args {
+        [] => false,
+        [stream] if stream == "all" => false,
+        [stream] if stream == "stderr" => true,
+        _ => bail!("Usage: cache [all|stderr]"),
+    };
+    let process = crate::process::get(process)?;
+    let cache = if stderr {
+        process
+            .stderr_cache
+            .as_ref()
+            .ok_or_else(|| eyre!("No separate cache for this stream"))?
+    } else {
+        &process.output_cache
+    };
+    let mut cache = cache.lock();
+    Ok(cache.extract().trim_end().to_string())
+}
+
+async fn backup(args: &[String]) -> Result<String> {
+    let (name, tag) = match args {
+        [name] => (name, None),
+        [name, tag] => (name, Some(tag.as_str())),
+        _ => bail!("Usage: backup <name> [tag]"),
+    };
+    let config = CONFIG.load_full().ok_or_else(|| eyre!("Missing config"))?;
+    let path = crate::backup_manager::run_backup(&config, name, tag).await?;
+    Ok(format!("Backup created at {}", path.display()))
+}
+
+/// Runs a task now. Reports the result of each action, and fails if any failed.
+async fn run_task(args: &[String]) -> Result<String> {
+    let [name] = args else {
+        bail!("Usage: run-task <name>");
+    };
+    let results = crate::tasks::run_task(name).await?;
+    let failed = results.iter().any(Result::is_err);
+    let lines: Vec<_> = results
+        .iter()
+        .enumerate()
+        .map(|(index, result)| match result {
+            Ok(()) => format!("action {}: ok", index + 1),
+            Err(err) => format!("action {}: failed: {:#}", index + 1, err),
+        })
+        .collect();
+    let report = if lines.is_empty() {
+        "task has no actions".to_string()
+    } else {
+        lines.join("\n")
+    };
+    if failed {
+        bail!(report);
+    }
+    Ok(report)
+}
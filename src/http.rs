@@ -0,0 +1,137 @@
+use crate::configs::{ActionType, DolorousConfig, UnavailablePolicy};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::{Report, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{error, info, instrument, Instrument};
+
+#[instrument(skip(config))]
+pub async fn setup(config: &DolorousConfig) -> Result<()> {
+    let Some(http_config) = &config.http else {
+        return Ok(());
+    };
+    if http_config.token.is_empty() {
+        bail!("The HTTP API needs a token");
+    }
+    let address = SocketAddr::new(http_config.address, http_config.port);
+    let token: Arc<str> = http_config.token.as_str().into();
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/start", post(start))
+        .route("/stop", post(stop))
+        .route("/restart", post(restart))
+        .route("/backup/:name", post(backup))
+        .route("/logs", get(logs))
+        .layer(middleware::from_fn_with_state(token, authorize));
+    let server = axum::Server::try_bind(&address)
+        .wrap_err("Failed to bind HTTP API")?
+        .serve(app.into_make_service());
+    info!("HTTP API listening on {}", address);
+    tokio::spawn(
+        async move {
+            if let Err(err) = server.await {
+                error!(?err, "HTTP API failed");
+            }
+        }
+        .in_current_span(),
+    );
+    Ok(())
+}
+
+/// Rejects requests without the bearer token
+async fn authorize<B>(
+    State(token): State<Arc<str>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let received = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !received.map_or(false, |received| {
+        crate::socket::token_matches(received, &token)
+    }) {
+        return (StatusCode::UNAUTHORIZED, "Missing or invalid token").into_response();
+    }
+    next.run(request).await
+}
+
+/// A failed request, answered with the error message
+struct ApiError(Report);
+
+impl From<Report> for ApiError {
+    fn from(err: Report) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": format!("{:#}", self.0) });
+        (StatusCode::CONFLICT, Json(body)).into_response()
+    }
+}
+
+type ApiResult = Result<Json<Value>, ApiError>;
+
+/// Process a request is about. Without a name, that's the default process.
+#[derive(Debug, Deserialize)]
+struct Target {
+    process: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LogsQuery {
+    process: Option<String>,
+    tail: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupQuery {
+    tag: Option<String>,
+}
+
+async fn status(Query(target): Query<Target>) -> ApiResult {
+    let process = crate::process::get(target.process.as_deref())?;
+    Ok(Json(crate::control::status_json(&process)))
+}
+
+async fn run_action(action: ActionType, process: Option<&str>) -> ApiResult {
+    crate::tasks::execute_action(&action, UnavailablePolicy::Error, process).await?;
+    Ok(Json(serde_json::json!({ "ok": true })))
+}
+
+async fn start(Query(target): Query<Target>) -> ApiResult {
+    run_action(ActionType::Start, target.process.as_deref()).await
+}
+
+async fn stop(Query(target): Query<Target>) -> ApiResult {
+    run_action(ActionType::Stop, target.process.as_deref()).await
+}
+
+async fn restart(Query(target): Query<Target>) -> ApiResult {
+    run_action(ActionType::Restart, target.process.as_deref()).await
+}
+
+async fn backup(Path(name): Path<String>, Query(query): Query<BackupQuery>) -> ApiResult {
+    let action = ActionType::Backup {
+        backup: name,
+        tag: query.tag,
+    };
+    run_action(action, None).await
+}
+
+/// Cached output of the process, as plain text
+async fn logs(Query(query): Query<LogsQuery>) -> Result<String, ApiError> {
+    let process = crate::process::get(query.process.as_deref())?;
+    Ok(process.cached_output(query.tail))
+}
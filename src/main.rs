@@ -1,26 +1,36 @@
 mod backup_manager;
+mod client;
 mod configs;
+mod control;
+#[cfg(feature = "http")]
+mod http;
+mod notifications;
+mod permissions;
 mod process;
+mod reload;
 mod socket;
 mod tasks;
+mod telemetry;
+mod validate;
 
 use crate::configs::DolorousConfig;
-use crate::process::Controls;
+use crate::process::{Controls, StateKind};
+use arc_swap::ArcSwapOption;
 use clap::Parser;
-use color_eyre::eyre::WrapErr;
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use nix::sys::wait::wait;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::OnceCell;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-static CONFIG: OnceCell<DolorousConfig> = OnceCell::const_new();
+static CONFIG: ArcSwapOption<DolorousConfig> = ArcSwapOption::const_empty();
+static CONFIG_PATH: OnceCell<PathBuf> = OnceCell::const_new();
 static EXITING: AtomicBool = AtomicBool::new(false);
 
 #[derive(Parser, Debug, Deserialize, Serialize)]
@@ -34,50 +44,123 @@ struct Args {
         default_value = "/etc/dolorous/config.yml"
     )]
     config: PathBuf,
+    /// Print the effective config, with includes merged and environment variables expanded
+    #[arg(long)]
+    dump_config: bool,
+    /// Include tokens, passwords, keys and environment variables in the printed config.
+    /// They're hidden otherwise.
+    #[arg(long, requires = "dump_config")]
+    show_secrets: bool,
+    #[command(subcommand)]
+    command: Option<client::Command>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Args::parse();
-    let config: DolorousConfig =
-        serde_yaml::from_reader(File::open(&args.config).wrap_err("Failed to read config")?)
-            .wrap_err("Failed to read config!")?;
+    let config = configs::load(&args.config)?;
+    if args.dump_config {
+        validate::validate(&config)?;
+        print!("{}", configs::dump(&config, !args.show_secrets)?);
+        return Ok(());
+    }
+    if let Some(command) = args.command {
+        return client::run(&config, command).await;
+    }
+    validate::validate(&config)?;
 
     if std::env::var("DOLOROUS_LOG").is_err() {
         std::env::set_var("DOLOROUS_LOG", &config.log_filter);
     }
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_env("DOLOROUS_LOG"))
-        .init();
-    CONFIG.set(config).unwrap();
-    let config = CONFIG.get().unwrap();
+        .with_filter_reloading();
+    let reload_handle = subscriber.reload_handle();
+    subscriber.init();
+    reload::set_log_filter_reload(move |filter| {
+        reload_handle.reload(EnvFilter::try_new(filter)?)?;
+        Ok(())
+    });
+    CONFIG_PATH.set(args.config).unwrap();
+    let config = Arc::new(config);
+    CONFIG.store(Some(config.clone()));
+    // The process and socket keep using the config they were started with
+    let config: &'static DolorousConfig = Box::leak(Box::new(config));
 
     //backup_manager::run_backup(&config, "default").await?;
+    backup_manager::health::init();
     socket::setup(config).await?;
+    #[cfg(feature = "http")]
+    http::setup(config).await?;
+    #[cfg(not(feature = "http"))]
+    if config.http.is_some() {
+        tracing::warn!("HTTP API configured, but dolorous was built without the http feature");
+    }
+    #[cfg(feature = "prometheus")]
+    telemetry::setup(config)?;
+    #[cfg(not(feature = "prometheus"))]
+    if config.metrics.is_some() {
+        tracing::warn!("Metrics configured, but dolorous was built without the prometheus feature");
+    }
     tasks::start(config).await?;
     process::deamon(config).await;
 
     let mut term_sig = signal(SignalKind::terminate())?;
     let mut int_sig = signal(SignalKind::interrupt())?;
+    let mut hup_sig = signal(SignalKind::hangup())?;
 
-    select! {
-        _ = term_sig.recv() => {},
-        _ = int_sig.recv() => {},
+    loop {
+        select! {
+            _ = term_sig.recv() => break,
+            _ = int_sig.recv() => break,
+            _ = hup_sig.recv() => {
+                info!("Reloading config on SIGHUP");
+                // The process keeps running with its old config if the new one is invalid
+                if let Err(err) = reload::reload().await {
+                    error!(?err, "Failed to reload config");
+                }
+            }
+        }
     }
     info!("Stopping...");
     EXITING.store(true, Ordering::Relaxed);
-    if let Some(control) = process::CONTROL.get() {
-        let _ = control.send(Controls::Stop);
-    }
-    // Wait for child exit
-    let _ = wait();
-    if let Some(path) = &config.socket {
-        info!("Removing socket");
-        if let Err(err) = tokio::fs::remove_file(path).await {
-            error!(?err, "Failed to delete socket");
+    for name in process::names() {
+        if let Ok(process) = process::get(Some(&name)) {
+            let _ = process.control.send(Controls::Stop);
         }
     }
+    wait_for_processes().await;
+    socket::shutdown().await;
+    socket::close().await;
     info!("Stopped!");
     std::process::exit(0);
 }
+
+/// Waits until all processes stopped, at most as long as their stop config allows.
+/// Their exits are reaped by the exit watcher, which keeps running meanwhile.
+async fn wait_for_processes() {
+    let timeout = CONFIG
+        .load_full()
+        .and_then(|config| {
+            config
+                .processes
+                .values()
+                .map(|process| process.stop_config.term_timeout + process.stop_config.kill_timeout)
+                .max()
+        })
+        .unwrap_or_default()
+        + Duration::from_secs(5);
+    let stopped = async {
+        while process::names().iter().any(|name| {
+            process::get(Some(name)).map_or(false, |process| {
+                process.status.lock().state != StateKind::Stopped
+            })
+        }) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    if tokio::time::timeout(timeout, stopped).await.is_err() {
+        warn!("Processes didn't stop in time");
+    }
+}
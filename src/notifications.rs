@@ -0,0 +1,181 @@
+use crate::configs::{EmailConfig, NotificationEvent, SmtpSecurity, WebhookConfig, WebhookFormat};
+use crate::CONFIG;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn, Instrument};
+
+/// Lines of process output included with crashes and failures
+const LOG_TAIL_LINES: usize = 20;
+/// Longest message Discord accepts
+const DISCORD_MAX_LENGTH: usize = 2000;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something notifications are sent about
+#[derive(Debug)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    /// Process the event is about, if any
+    pub process: Option<String>,
+    pub message: String,
+    /// Recent output of the process
+    pub log_tail: Option<String>,
+}
+
+impl Notification {
+    /// Creates the notification. The recent output of the process is attached
+    /// to crashes and failures.
+    pub fn new(event: NotificationEvent, process: Option<&str>, message: String) -> Self {
+        let log_tail = match event {
+            NotificationEvent::Crash
+            | NotificationEvent::CrashLoop
+            | NotificationEvent::StartupFailure
+            | NotificationEvent::BackupFailure => crate::process::get(process)
+                .ok()
+                .map(|process| process.cached_output(Some(LOG_TAIL_LINES))),
+            NotificationEvent::Restart | NotificationEvent::BackupSuccess => None,
+        };
+        Self {
+            event,
+            process: process.map(String::from),
+            message,
+            log_tail,
+        }
+    }
+}
+
+/// Sends the notification to the configured backends in the background.
+/// Failures are only logged.
+pub fn notify(notification: Notification) {
+    let Some(config) = CONFIG.load_full() else {
+        return;
+    };
+    let notification = Arc::new(notification);
+    if let Some(webhook) = &config.notifications.webhook {
+        if wants(&webhook.events, notification.event) {
+            let config = config.clone();
+            let notification = notification.clone();
+            tokio::spawn(
+                async move {
+                    let Some(webhook) = &config.notifications.webhook else {
+                        return;
+                    };
+                    if let Err(err) = send_webhook(webhook, &notification).await {
+                        warn!(?err, "Failed to send webhook notification");
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+    if let Some(email) = &config.notifications.email {
+        if wants(&email.events, notification.event) {
+            let config = config.clone();
+            let notification = notification.clone();
+            tokio::spawn(
+                async move {
+                    let Some(email) = &config.notifications.email else {
+                        return;
+                    };
+                    if let Err(err) = send_email(email, &notification).await {
+                        warn!(?err, "Failed to send email notification");
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+}
+
+/// Whether a backend with the event filter gets the event
+fn wants(events: &[NotificationEvent], event: NotificationEvent) -> bool {
+    events.is_empty() || events.contains(&event)
+}
+
+async fn send_webhook(config: &WebhookConfig, notification: &Notification) -> Result<()> {
+    let body = match config.format {
+        WebhookFormat::Json => serde_json::json!({
+            "event": notification.event,
+            "process": notification.process,
+            "message": notification.message,
+            "log-tail": notification.log_tail,
+        }),
+        // Process output could otherwise ping @everyone or roles
+        WebhookFormat::Discord => serde_json::json!({
+            "content": discord_message(notification),
+            "allowed_mentions": { "parse": [] },
+        }),
+    };
+    reqwest::Client::new()
+        .post(&config.url)
+        .json(&body)
+        .timeout(SEND_TIMEOUT)
+        .send()
+        .await
+        .wrap_err("Failed to send request")?
+        .error_for_status()?;
+    debug!("Sent webhook notification");
+    Ok(())
+}
+
+/// The message with as much of the log tail as fits
+fn discord_message(notification: &Notification) -> String {
+    let mut message = format!("**{}**", notification.message);
+    let Some(log_tail) = notification.log_tail.as_deref() else {
+        return message;
+    };
+    let log_tail = log_tail.trim_end().replace("```", "'''");
+    // Room for the message and the code block markers
+    let room = DISCORD_MAX_LENGTH.saturating_sub(message.chars().count() + 10);
+    let skip = log_tail.chars().count().saturating_sub(room);
+    let log_tail: String = log_tail.chars().skip(skip).collect();
+    if !log_tail.is_empty() {
+        message.push_str(&format!("\n```\n{log_tail}\n```"));
+    }
+    message
+}
+
+async fn send_email(config: &EmailConfig, notification: &Notification) -> Result<()> {
+    let mut message = Message::builder()
+        .from(config.from.parse().wrap_err("Invalid sender address")?)
+        .subject(format!(
+            "[dolorous] {}",
+            notification.message.lines().next().unwrap_or("")
+        ));
+    for to in &config.to {
+        message = message.to(to
+            .parse()
+            .wrap_err_with(|| format!("Invalid recipient address: {to}"))?);
+    }
+    let mut body = notification.message.clone();
+    if let Some(log_tail) = &notification.log_tail {
+        body.push_str("\n\nRecent output:\n\n");
+        body.push_str(log_tail);
+    }
+    let message = message.body(body).wrap_err("Failed to build email")?;
+    let port = config
+        .port
+        .unwrap_or_else(|| config.security.default_port());
+    let mut transport = match config.security {
+        SmtpSecurity::Starttls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        }
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    }
+    .port(port)
+    .timeout(Some(SEND_TIMEOUT));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport
+        .build()
+        .send(message)
+        .await
+        .wrap_err("Failed to send email")?;
+    debug!("Sent email notification");
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use color_eyre::Report;
+use nix::unistd::{getegid, geteuid};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Adds the path and effective user to errors caused by denied permissions
+pub fn explain(err: Report, path: &Path) -> Report {
+    let denied = err
+        .chain()
+        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+        .any(|e| e.kind() == ErrorKind::PermissionDenied);
+    if !denied {
+        return err;
+    }
+    err.wrap_err(format!(
+        "Permission denied on {} (running as uid {}, gid {})",
+        path.display(),
+        geteuid(),
+        getegid()
+    ))
+}
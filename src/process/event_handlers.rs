@@ -1,28 +1,60 @@
-use crate::configs::{DolorousConfig, RestartCondition};
-use crate::process::types::{ProcessState, StoppingState, WantedState};
-use crate::process::{run, OUTPUT_WATCH, STDIN};
-use color_eyre::eyre::WrapErr;
-use nix::sys::signal::{kill, Signal};
-use nix::unistd::Pid;
+use crate::configs::{NotificationEvent, ProcessConfig, RestartCondition, StopProperties};
+use crate::notifications::{notify, Notification};
+use crate::process::types::{
+    CrashLoopAlert, HealthState, OldProcess, ProcessState, StdinData, StoppingState, WantedState,
+};
+use crate::process::{run, Process};
+use chrono::Local;
+use nix::sys::signal::Signal;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::oneshot;
 use tokio::time::Instant;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
+
+/// Old processes of graceful restarts that are being stopped, notified when they exit
+pub type Retiring = HashMap<i32, oneshot::Sender<()>>;
 
 pub async fn handle_exit_event(
-    config: &DolorousConfig,
+    process: &Arc<Process>,
+    config: &ProcessConfig,
+    wanted: &mut WantedState,
     state: &mut ProcessState,
+    reached_running: bool,
     pid: i32,
     exit_code: i32,
 ) {
+    if state.pid() == Some(pid) {
+        if let Some(cause) = limit_exit_cause(config, exit_code) {
+            error!(pid, "{}", cause);
+        }
+    }
+    if let ProcessState::Overlapping { .. } = state {
+        handle_overlap_exit(process, config, state, pid, exit_code);
+        return;
+    }
     match &state {
         #[rustfmt::skip]
         ProcessState::Watching { pid: existing_pid, attempt, .. } if *existing_pid == pid => {
-            warn!(pid, "Process exited during startup: attempt {}/{}, exit code {}", attempt, config.process.restart_attempts, exit_code);
-            { *OUTPUT_WATCH.lock() = None; }
-            { *STDIN.lock() = None; }
-            let timeout_at = Instant::now() + config.process.restart_delay;
+            // Exiting before the watch delay is a failed start, even with exit code 0
+            warn!(pid, "Process exited during startup: attempt {}/{}, exit code {}", attempt, config.restart_attempts, exit_code);
+            if *attempt >= config.restart_attempts {
+                error!("Process keeps exiting during startup, giving up");
+                notify(Notification::new(
+                    NotificationEvent::StartupFailure,
+                    Some(&process.name),
+                    format!("Process {} keeps exiting during startup, giving up after {} attempts", process.name, attempt),
+                ));
+                *wanted = WantedState::Stopped;
+                *state = ProcessState::Stopped;
+                return;
+            }
+            let timeout_at = Instant::now() + config.restart_delay;
             *state = ProcessState::WaitingRestart { timeout_at, attempt: attempt + 1 };
         }
-        ProcessState::Running { pid: exsisting_pid } if *exsisting_pid == pid => {
+        ProcessState::Running {
+            pid: exsisting_pid, ..
+        } if *exsisting_pid == pid => {
             if exit_code != 0 {
                 warn!(pid, "Process exited with non-zero exit code {}", exit_code);
             } else {
@@ -30,15 +62,42 @@ pub async fn handle_exit_event(
             }
 
             let restart = matches!(
-                (&config.process.restart, exit_code != 0),
+                (&config.restart, exit_code != 0),
                 (RestartCondition::Always, _)
                     | (RestartCondition::IfCrashed, true)
                     | (RestartCondition::UnlessCrashed, false)
             );
+            if exit_code != 0 {
+                crate::telemetry::process_crashed(&process.name);
+                notify(Notification::new(
+                    NotificationEvent::Crash,
+                    Some(&process.name),
+                    format!(
+                        "Process {} crashed with exit code {}",
+                        process.name, exit_code
+                    ),
+                ));
+                write_crash_log(process, config, pid).await;
+                if matches!(config.restart, RestartCondition::WarnOnly) {
+                    error!(
+                        pid,
+                        "Process crashed, not restarting! Manual intervention required"
+                    );
+                }
+            }
             if restart {
-                match run::start(config).await {
+                match run::start(process, config, !reached_running).await {
                     Ok(pid) => {
-                        let timeout_at = Instant::now() + config.process.watch_delay;
+                        crate::telemetry::process_restarted(&process.name);
+                        notify(Notification::new(
+                            NotificationEvent::Restart,
+                            Some(&process.name),
+                            format!(
+                                "Process {} restarted after exiting with exit code {}",
+                                process.name, exit_code
+                            ),
+                        ));
+                        let timeout_at = Instant::now() + config.watch_delay;
                         *state = ProcessState::Watching {
                             pid,
                             timeout_at,
@@ -49,13 +108,16 @@ pub async fn handle_exit_event(
                         warn!(?err, "Failed to start server!");
                         *state = ProcessState::WaitingRestart {
                             attempt: 2,
-                            timeout_at: Instant::now() + config.process.restart_delay,
+                            timeout_at: Instant::now() + config.restart_delay,
                         };
                     }
                 }
+            } else {
+                *wanted = WantedState::Stopped;
+                *state = ProcessState::Stopped;
             }
         }
-        ProcessState::Stopping(_) => {
+        ProcessState::Stopping(stopping) if stopping.pid() == pid => {
             info!(exit_code, "Stopped server");
             *state = ProcessState::Stopped;
         }
@@ -63,46 +125,357 @@ pub async fn handle_exit_event(
     }
 }
 
+/// Describes the resource limit the exit code points to, if any. Only SIGXCPU is certain.
+/// The memory limit makes allocations fail instead of killing the process, which then
+/// usually aborts or crashes, so it's only named as a possible cause.
+fn limit_exit_cause(config: &ProcessConfig, exit_code: i32) -> Option<&'static str> {
+    let signal = Signal::try_from(exit_code - super::SIGNAL_EXIT_BASE).ok()?;
+    let cpu_limit = config.cpu_limit.is_some();
+    let memory_limit = config.memory_limit.is_some();
+    match signal {
+        Signal::SIGXCPU if cpu_limit => Some("Process killed for exceeding its CPU time limit"),
+        Signal::SIGKILL if cpu_limit => {
+            Some("Process killed, possibly for reaching its hard CPU time limit")
+        }
+        Signal::SIGABRT | Signal::SIGSEGV if memory_limit => {
+            Some("Process crashed, possibly because allocations failed at its memory limit")
+        }
+        _ => None,
+    }
+}
+
+/// Handles an exit while the old and new process of a graceful restart run side by side
+fn handle_overlap_exit(
+    process: &Process,
+    config: &ProcessConfig,
+    state: &mut ProcessState,
+    pid: i32,
+    exit_code: i32,
+) {
+    let ProcessState::Overlapping {
+        old,
+        new_pid,
+        timeout_at,
+    } = state
+    else {
+        return;
+    };
+    if old.pid == pid {
+        warn!(pid, exit_code, "Old process exited during graceful restart");
+        *state = ProcessState::Watching {
+            pid: *new_pid,
+            timeout_at: *timeout_at,
+            attempt: 1,
+        };
+    } else if *new_pid == pid {
+        warn!(
+            pid,
+            exit_code, "New process exited during startup, keeping the old one"
+        );
+        *process.stdin.lock() = old.stdin.take();
+        *process.output.lock() = old.output.take();
+        *state = running(config, old.pid);
+    }
+}
+
+/// Starts a second process next to the running one. The old process is stopped once the new one
+/// passed the watch delay. Without `graceful-restart`, the process is restarted normally.
+pub async fn handle_graceful_restart(
+    process: &Arc<Process>,
+    config: &ProcessConfig,
+    state: &mut ProcessState,
+) {
+    let ProcessState::Running { pid, .. } = state else {
+        debug!("Process not running, no graceful restart");
+        return;
+    };
+    let pid = *pid;
+    if !config.graceful_restart {
+        info!("Graceful restart disabled, restarting");
+        match super::stop_server_command(process, config, pid) {
+            Ok(s) => *state = s,
+            Err(err) => error!(?err, "Failed to stop server"),
+        }
+        return;
+    }
+    let mut old = OldProcess {
+        pid,
+        stdin: process.stdin.lock().clone(),
+        output: process.output.lock().clone(),
+    };
+    match run::start(process, config, false).await {
+        Ok(new_pid) => {
+            info!(pid, new_pid, "Graceful restart: started new process");
+            *state = ProcessState::Overlapping {
+                old,
+                new_pid,
+                timeout_at: Instant::now() + config.watch_delay,
+            };
+        }
+        Err(err) => {
+            warn!(?err, "Graceful restart: failed to start new process");
+            *process.stdin.lock() = old.stdin.take();
+            *process.output.lock() = old.output.take();
+        }
+    }
+}
+
+/// Sends the stop command to the old process of a graceful restart.
+/// It's signalled like a normal stop if it doesn't exit in time.
+pub fn retire(config: &ProcessConfig, retiring: &mut Retiring, old: &mut OldProcess) {
+    info!(pid = old.pid, "Stopping old process");
+    let stop_config = config.stop_config.clone();
+    // Dropping the sender closes stdin of the old process, after the stop command
+    match old.stdin.take() {
+        Some(stdin)
+            if stdin
+                .send(StdinData::Line(stop_config.stop_command.clone()))
+                .is_ok() => {}
+        _ => warn!(pid = old.pid, "Stdin of old process unavailable"),
+    }
+    let (exited_sender, exited) = oneshot::channel();
+    retiring.insert(old.pid, exited_sender);
+    tokio::spawn(escalate_stop(old.pid, stop_config, exited).in_current_span());
+}
+
+async fn escalate_stop(pid: i32, stop_config: StopProperties, mut exited: oneshot::Receiver<()>) {
+    if tokio::time::timeout(stop_config.term_timeout, &mut exited)
+        .await
+        .is_ok()
+    {
+        return;
+    }
+    let term_signal = stop_config.term_signal;
+    warn!(
+        pid,
+        "Term timeout reached, sending {}",
+        term_signal.as_str()
+    );
+    if let Err(err) = super::kill_group(pid, term_signal) {
+        error!(?err, "Failed to terminate");
+    }
+    if tokio::time::timeout(stop_config.kill_timeout, &mut exited)
+        .await
+        .is_ok()
+    {
+        return;
+    }
+    warn!(pid, "Kill timeout reached");
+    if let Err(err) = super::kill_group(pid, Signal::SIGKILL) {
+        error!(?err, "Failed to kill");
+    }
+}
+
+/// A freshly started process, after the watch delay
+pub fn running(config: &ProcessConfig, pid: i32) -> ProcessState {
+    let restart_at = config.max_uptime.map(|uptime| Instant::now() + uptime);
+    ProcessState::Running {
+        pid,
+        restart_at,
+        health: HealthState::new(config),
+    }
+}
+
+/// Stops restarting a process that keeps exiting
+pub async fn handle_crash_loop(
+    process: &Process,
+    config: &ProcessConfig,
+    state: &mut ProcessState,
+    pid: i32,
+    exit_code: i32,
+) {
+    if exit_code != 0 {
+        write_crash_log(process, config, pid).await;
+    }
+    let Some(crash_loop) = &config.crash_loop else {
+        return;
+    };
+    error!(
+        pid,
+        exit_code,
+        "CRASH LOOP: process exited {} times within {}, not restarting until started manually",
+        crash_loop.max_exits,
+        humantime::format_duration(crash_loop.window)
+    );
+    let message = format!(
+        "exited {} times within {} (last exit code {}), not restarting until started manually",
+        crash_loop.max_exits,
+        humantime::format_duration(crash_loop.window),
+        exit_code
+    );
+    notify(Notification::new(
+        NotificationEvent::CrashLoop,
+        Some(&process.name),
+        format!("Process {} is crash looping: {}", process.name, message),
+    ));
+    process.status.lock().crash_loop = Some(CrashLoopAlert {
+        at: Local::now(),
+        message,
+    });
+    *state = ProcessState::CrashLooping;
+}
+
+/// Writes the output cache to the crash log directory, if configured
+async fn write_crash_log(process: &Process, config: &ProcessConfig, pid: i32) {
+    let Some(directory) = &config.crash_log_directory else {
+        return;
+    };
+    let data = {
+        let mut cache = process.output_cache.lock();
+        cache.extract().to_string()
+    };
+    let path = directory.join(format!(
+        "crash-{}-{}.log",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        pid
+    ));
+    if let Err(err) = tokio::fs::create_dir_all(directory).await {
+        error!(?err, "Failed to create crash log directory");
+        return;
+    }
+    match tokio::fs::write(&path, data).await {
+        Ok(_) => info!("Wrote crash log to {}", path.display()),
+        Err(err) => error!(?err, "Failed to write crash log"),
+    }
+}
+
+/// Counts failed probes and restarts the process once the failure threshold is reached
+pub fn handle_health_checked(
+    process: &Process,
+    config: &ProcessConfig,
+    state: &mut ProcessState,
+    pid: i32,
+    healthy: bool,
+) {
+    let Some(health_check) = &config.health_check else {
+        return;
+    };
+    let ProcessState::Running {
+        pid: running_pid,
+        health,
+        ..
+    } = state
+    else {
+        return;
+    };
+    if *running_pid != pid {
+        return;
+    }
+    health.next_check_at = Some(Instant::now() + health_check.interval);
+    if healthy {
+        if health.failures > 0 {
+            info!(pid, "Health check passed again");
+        }
+        health.failures = 0;
+        return;
+    }
+    health.failures += 1;
+    warn!(
+        pid,
+        "Health check failed ({}/{})", health.failures, health_check.failure_threshold
+    );
+    if health.failures >= health_check.failure_threshold {
+        error!(pid, "Process stopped responding, restarting");
+        match super::stop_server_command(process, config, pid) {
+            Ok(s) => *state = s,
+            Err(err) => error!(?err, "Failed to stop server"),
+        }
+    }
+}
+
+/// Ends the startup of the process early, as if the watch delay had passed
+pub async fn handle_ready(
+    process: &Arc<Process>,
+    config: &ProcessConfig,
+    wanted: &mut WantedState,
+    state: &mut ProcessState,
+    reached_running: &mut bool,
+    retiring: &mut Retiring,
+    pid: i32,
+) {
+    match state {
+        ProcessState::Watching {
+            pid: starting_pid, ..
+        }
+        | ProcessState::Overlapping {
+            new_pid: starting_pid,
+            ..
+        } if *starting_pid == pid => {
+            info!(pid, "Process ready");
+            handle_timeout_reached(process, config, wanted, state, reached_running, retiring).await
+        }
+        _ => {}
+    }
+}
+
 pub async fn handle_timeout_reached(
-    config: &DolorousConfig,
+    process: &Arc<Process>,
+    config: &ProcessConfig,
     wanted: &mut WantedState,
     state: &mut ProcessState,
+    reached_running: &mut bool,
+    retiring: &mut Retiring,
 ) {
     match state {
         ProcessState::Watching { pid, .. } => {
             debug!(?pid, "Process started succesfully!");
-            *state = ProcessState::Running { pid: *pid };
-        }
-        ProcessState::WaitingRestart { attempt, .. } => match run::start(config).await {
-            Ok(pid) => {
-                let timeout_at = Instant::now() + config.process.watch_delay;
-                *state = ProcessState::Watching {
-                    pid,
-                    timeout_at,
-                    attempt: *attempt,
-                };
-            }
-            Err(err) => {
-                if *attempt >= config.process.restart_attempts {
-                    error!("Failed to start server");
-                    *wanted = WantedState::Stopped;
-                    *state = ProcessState::Stopped;
-                } else {
-                    warn!(?err, "Failed to start server, retriying");
-                    *state = ProcessState::WaitingRestart {
-                        attempt: *attempt + 1,
-                        timeout_at: Instant::now() + config.process.restart_delay,
+            *reached_running = true;
+            *state = running(config, *pid);
+        }
+        ProcessState::Overlapping { old, new_pid, .. } => {
+            info!(new_pid, "New process started, replacing the old one");
+            let new_pid = *new_pid;
+            retire(config, retiring, old);
+            *state = running(config, new_pid);
+        }
+        ProcessState::WaitingRestart { attempt, .. } => {
+            match run::start(process, config, !*reached_running).await {
+                Ok(pid) => {
+                    let timeout_at = Instant::now() + config.watch_delay;
+                    *state = ProcessState::Watching {
+                        pid,
+                        timeout_at,
+                        attempt: *attempt,
                     };
                 }
+                Err(err) => {
+                    if *attempt >= config.restart_attempts {
+                        error!(?err, "Failed to start server");
+                        notify(Notification::new(
+                            NotificationEvent::StartupFailure,
+                            Some(&process.name),
+                            format!(
+                                "Process {} failed to start after {} attempts: {:#}",
+                                process.name, attempt, err
+                            ),
+                        ));
+                        *wanted = WantedState::Stopped;
+                        *state = ProcessState::Stopped;
+                    } else {
+                        warn!(?err, "Failed to start server, retriying");
+                        *state = ProcessState::WaitingRestart {
+                            attempt: *attempt + 1,
+                            timeout_at: Instant::now() + config.restart_delay,
+                        };
+                    }
+                }
             }
-        },
+        }
+        ProcessState::Running { pid, .. } => {
+            info!("Maximum uptime reached, restarting");
+            match super::stop_server_command(process, config, *pid) {
+                Ok(s) => *state = s,
+                Err(err) => error!(?err, "Failed to stop server"),
+            }
+        }
         ProcessState::Stopping(StoppingState::Command { pid, .. }) => {
-            warn!("Term timeout reached");
-            match kill(Pid::from_raw(*pid), Signal::SIGTERM).wrap_err("Failed to send signal") {
+            let term_signal = config.stop_config.term_signal;
+            warn!("Term timeout reached, sending {}", term_signal.as_str());
+            match super::kill_group(*pid, term_signal) {
                 Ok(_) => {
                     *state = ProcessState::Stopping(StoppingState::Terminate {
                         pid: *pid,
-                        timeout_at: Instant::now() + config.process.stop_config.kill_timeout,
+                        timeout_at: Instant::now() + config.stop_config.kill_timeout,
                     })
                 }
                 Err(err) => {
@@ -116,12 +489,10 @@ pub async fn handle_timeout_reached(
         }
         ProcessState::Stopping(StoppingState::Terminate { pid, .. }) => {
             warn!("Kill timeout reached");
-            if let Err(err) =
-                kill(Pid::from_raw(*pid), Signal::SIGKILL).wrap_err("Failed to send signal")
-            {
+            if let Err(err) = super::kill_group(*pid, Signal::SIGKILL) {
                 error!(?err, "Failed to kill");
             }
-            *state = ProcessState::Stopping(StoppingState::Kill);
+            *state = ProcessState::Stopping(StoppingState::Kill { pid: *pid });
         }
         _ => {}
     }
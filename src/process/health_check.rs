@@ -0,0 +1,50 @@
+use super::{Process, StdinData};
+use crate::configs::{HealthCheckConfig, HealthProbe};
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use regex::Regex;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::debug;
+
+/// Runs the probe once. Returns whether it passed within the timeout.
+pub async fn probe(process: &Process, config: &HealthCheckConfig) -> bool {
+    match tokio::time::timeout(config.timeout, run_probe(process, &config.probe)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            debug!(?err, "Health check failed");
+            false
+        }
+        Err(_) => {
+            debug!("Health check timed out");
+            false
+        }
+    }
+}
+
+async fn run_probe(process: &Process, probe: &HealthProbe) -> Result<()> {
+    match probe {
+        HealthProbe::Tcp { host, port } => {
+            TcpStream::connect((host.as_str(), *port))
+                .await
+                .wrap_err("Failed to connect")?;
+            Ok(())
+        }
+        HealthProbe::Command { command, pattern } => {
+            let pattern = Regex::new(pattern).wrap_err("Invalid health check pattern")?;
+            // Only output after the command counts
+            let mut output = process
+                .subscribe_output()
+                .ok_or_else(|| eyre!("Output unavailable"))?;
+            let stdin = process.stdin()?;
+            stdin.send(StdinData::Line(command.clone()))?;
+            loop {
+                match output.recv().await {
+                    Ok(line) if pattern.is_match(&line.text) => return Ok(()),
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => bail!("Output closed"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::configs::ProcessConfig;
+use color_eyre::eyre::WrapErr;
+use color_eyre::Result;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::warn;
+
+/// Recent lines sent to the process, oldest first
+pub struct CommandHistory {
+    lines: Mutex<VecDeque<String>>,
+    size: usize,
+    /// Lines to append to the history file, written by `spawn_writer`'s task
+    file: Option<UnboundedSender<String>>,
+}
+
+impl CommandHistory {
+    /// Creates the history, with the lines saved in the history file
+    pub fn load(config: &ProcessConfig) -> Self {
+        let size = config.command_history_size;
+        let mut lines = VecDeque::new();
+        if let Some(path) = &config.command_history_file {
+            match read(path, size) {
+                Ok(saved) => lines = saved,
+                Err(err) => warn!(?err, "Failed to load command history"),
+            }
+        }
+        Self {
+            lines: Mutex::new(lines),
+            size,
+            file: config.command_history_file.clone().map(spawn_writer),
+        }
+    }
+
+    pub fn record(&self, line: &str) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() || self.size == 0 {
+            return;
+        }
+        {
+            let mut lines = self.lines.lock();
+            while lines.len() >= self.size {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+        if let Some(file) = &self.file {
+            let _ = file.send(line.to_string());
+        }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().iter().cloned().collect()
+    }
+}
+
+/// Reads the last `size` lines of the history file, and shortens the file to them
+fn read(path: &Path, size: usize) -> Result<VecDeque<String>> {
+    if !path.exists() {
+        return Ok(VecDeque::new());
+    }
+    let data = std::fs::read_to_string(path).wrap_err("Failed to read history file")?;
+    let mut lines: VecDeque<String> = data.lines().map(String::from).collect();
+    if lines.len() > size {
+        lines.drain(..lines.len() - size);
+        let data: String = lines.iter().map(|line| format!("{line}\n")).collect();
+        std::fs::write(path, data).wrap_err("Failed to shorten history file")?;
+    }
+    Ok(lines)
+}
+
+/// Appends the lines sent to it to the history file, in order, off the caller's thread
+fn spawn_writer(path: PathBuf) -> UnboundedSender<String> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(line) = receiver.recv().await {
+            if let Err(err) = append(&path, &line).await {
+                warn!(?err, "Failed to save command history");
+            }
+        }
+    });
+    sender
+}
+
+async fn append(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{line}\n").as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use super::types::Stream;
+use super::Process;
+use crate::configs::{LogTarget, ProcessConfig};
+use color_eyre::eyre::{eyre, WrapErr};
+use color_eyre::Result;
+use libsystemd::logging::{journal_send, Priority};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, Instrument, Span};
+
+/// Destinations the process output is written to, besides connected clients
+pub struct OutputSinks {
+    /// Process to write the cache of, if it's a target
+    cache: Option<Arc<Process>>,
+    /// Whether cached lines get the prefix, so replays match what clients see live
+    cache_prefixed: bool,
+    /// Lines for the syslog and journald writer thread
+    system: Option<mpsc::UnboundedSender<(Stream, String)>>,
+    /// Lines for the log file writer task
+    file: Option<mpsc::Sender<String>>,
+    /// Lines not written to the log file because the writer fell behind
+    file_dropped: Arc<AtomicU64>,
+}
+
+/// Lines waiting for the log file writer. Further lines are dropped, so a stuck disk
+/// doesn't fill the memory.
+const FILE_BACKLOG: usize = 4096;
+
+impl OutputSinks {
+    /// Targets that fail to open are logged and skipped
+    pub fn new(process: &Arc<Process>, config: &ProcessConfig, pid: i32) -> Self {
+        let mut sinks = Self {
+            cache: None,
+            cache_prefixed: config.line_format.socket,
+            system: None,
+            file: None,
+            file_dropped: Arc::default(),
+        };
+        let mut system = SystemLog {
+            identifier: config.log_identifier.clone(),
+            syslog: None,
+            journald: false,
+        };
+        for target in &config.log_targets {
+            if let Err(err) = sinks.add(process, &mut system, target, pid) {
+                error!(?err, "Failed to open log target");
+            }
+        }
+        if system.syslog.is_some() || system.journald {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let span = Span::current();
+            // Both block on their sockets, so they're kept off the runtime
+            tokio::task::spawn_blocking(move || span.in_scope(|| system.run(receiver)));
+            sinks.system = Some(sender);
+        }
+        sinks
+    }
+
+    fn add(
+        &mut self,
+        process: &Arc<Process>,
+        system: &mut SystemLog,
+        target: &LogTarget,
+        pid: i32,
+    ) -> Result<()> {
+        match target {
+            LogTarget::Cache => self.cache = Some(process.clone()),
+            LogTarget::Syslog => {
+                let formatter = Formatter3164 {
+                    facility: Facility::LOG_DAEMON,
+                    hostname: None,
+                    process: system.identifier.clone(),
+                    pid: pid as u32,
+                };
+                let logger = syslog::unix(formatter)
+                    .map_err(|err| eyre!("Failed to connect to syslog: {}", err))?;
+                system.syslog = Some(logger);
+            }
+            LogTarget::Journald => system.journald = true,
+            LogTarget::File {
+                path,
+                max_size,
+                max_files,
+            } => {
+                let (sender, receiver) = mpsc::channel(FILE_BACKLOG);
+                let file = RotatingFile {
+                    path: path.clone(),
+                    max_size: max_size.map(|size| size.as_u64()),
+                    max_files: *max_files,
+                    dropped: self.file_dropped.clone(),
+                };
+                tokio::spawn(file.run(receiver).in_current_span());
+                self.file = Some(sender);
+            }
+        }
+        Ok(())
+    }
+
+    /// The prefix is written to the log file, and to the cache if clients get it too.
+    /// Syslog and journald have their own timestamps.
+    pub fn write(&self, stream: Stream, prefix: &str, line: &str) {
+        if let Some(process) = &self.cache {
+            let prefix = if self.cache_prefixed { prefix } else { "" };
+            // Stderr also goes to the output cache, which has both streams in order
+            let stderr_cache = match stream {
+                Stream::Stderr => process.stderr_cache.as_ref(),
+                Stream::Stdout => None,
+            };
+            for cache in std::iter::once(&process.output_cache).chain(stderr_cache) {
+                let mut cache = cache.lock();
+                if let Err(err) = cache.write_str(prefix).and_then(|_| cache.write_str(line)) {
+                    error!(?err, "Cache error");
+                }
+            }
+        }
+        if let Some(system) = &self.system {
+            let _ = system.send((stream, line.trim_end().to_string()));
+        }
+        if let Some(file) = &self.file {
+            // Written on a separate task, so a slow disk doesn't hold up the readers
+            if let Err(TrySendError::Full(_)) = file.try_send(format!("{prefix}{line}")) {
+                self.file_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Syslog and journald, written to on a blocking thread
+struct SystemLog {
+    identifier: String,
+    syslog: Option<Logger<LoggerBackend, Formatter3164>>,
+    journald: bool,
+}
+
+impl SystemLog {
+    /// Writes lines until all senders are dropped
+    fn run(mut self, mut receiver: mpsc::UnboundedReceiver<(Stream, String)>) {
+        while let Some((stream, message)) = receiver.blocking_recv() {
+            if let Some(syslog) = &mut self.syslog {
+                let result = match stream {
+                    Stream::Stdout => syslog.info(&message),
+                    Stream::Stderr => syslog.warning(&message),
+                };
+                if let Err(err) = result {
+                    error!(%err, "Syslog error");
+                }
+            }
+            if self.journald {
+                let priority = match stream {
+                    Stream::Stdout => Priority::Info,
+                    Stream::Stderr => Priority::Warning,
+                };
+                let vars = [("SYSLOG_IDENTIFIER", self.identifier.as_str())];
+                if let Err(err) = journal_send(priority, &message, vars.into_iter()) {
+                    error!(?err, "Journald error");
+                }
+            }
+        }
+    }
+}
+
+/// Log file, rotated once it reaches the maximum size
+struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_files: usize,
+    /// Lines dropped while the backlog was full, noted in the file once it catches up
+    dropped: Arc<AtomicU64>,
+}
+
+impl RotatingFile {
+    /// Writes lines until all senders are dropped
+    async fn run(self, mut receiver: mpsc::Receiver<String>) {
+        let (mut writer, mut size) = match open(&self.path).await {
+            Ok(opened) => opened,
+            Err(err) => {
+                error!(?err, "Failed to open log file");
+                return;
+            }
+        };
+        while let Some(line) = receiver.recv().await {
+            let mut next = Some(line);
+            while let Some(line) = next {
+                if matches!(self.max_size, Some(max) if size > 0 && size + line.len() as u64 > max)
+                {
+                    match self.rotate(&mut writer).await {
+                        Ok((rotated, rotated_size)) => {
+                            writer = rotated;
+                            size = rotated_size;
+                        }
+                        Err(err) => error!(?err, "Failed to rotate log file"),
+                    }
+                }
+                match writer.write_all(line.as_bytes()).await {
+                    Ok(()) => size += line.len() as u64,
+                    Err(err) => error!(?err, "Log file error"),
+                }
+                next = receiver.try_recv().ok();
+                if next.is_none() {
+                    let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        next = Some(format!("[dolorous: {dropped} lines dropped]\n"));
+                    }
+                }
+            }
+            // Flush once the backlog is written
+            if let Err(err) = writer.flush().await {
+                error!(?err, "Log file error");
+            }
+        }
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and starts a new file
+    async fn rotate(&self, writer: &mut BufWriter<File>) -> Result<(BufWriter<File>, u64)> {
+        writer.flush().await?;
+        if self.max_files == 0 {
+            tokio::fs::remove_file(&self.path).await?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                match tokio::fs::rename(self.rotated(index), self.rotated(index + 1)).await {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(err.into())
+                    }
+                    _ => {}
+                }
+            }
+            tokio::fs::rename(&self.path, self.rotated(1)).await?;
+        }
+        debug!("Rotated log file {}", self.path.display());
+        open(&self.path).await
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+}
+
+/// Opens the file for appending. Returns it with its current size.
+async fn open(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let size = file.metadata().await?.len();
+    Ok((BufWriter::new(file), size))
+}
@@ -1,76 +1,291 @@
 mod event_handlers;
+mod health_check;
+mod history;
+mod log_targets;
+mod rcon;
 mod run;
 mod types;
 
 use self::types::*;
-use crate::configs::DolorousConfig;
-use color_eyre::eyre::{eyre, WrapErr};
+pub use self::types::{
+    CrashLoopAlert, ExitRecord, OutputLine, ProcessStatus, StateKind, StdinData, Stream,
+};
+use crate::configs::{DolorousConfig, ProcessConfig, DEFAULT_PROCESS};
+use chrono::Local;
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
 use log_buffer::LogBuffer;
 use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
 use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
 use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::select;
 use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::{mpsc, watch, OnceCell};
+use tokio::sync::{broadcast, mpsc, OnceCell};
 use tokio::time::Instant;
-use tracing::{debug, error, instrument, warn};
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 
-pub static CONTROL: OnceCell<mpsc::UnboundedSender<Controls>> = OnceCell::const_new();
-pub static OUTPUT_WATCH: Mutex<Option<watch::Receiver<String>>> = Mutex::new(None);
-pub static STDIN: Mutex<Option<mpsc::UnboundedSender<String>>> = Mutex::new(None);
-pub static OUTPUT_CACHE: OnceCell<Mutex<LogBuffer<Vec<u8>>>> = OnceCell::const_new();
+/// Exit codes above this mean the process was killed by a signal
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+/// Managed processes by name
+static PROCESSES: OnceCell<HashMap<String, Arc<Process>>> = OnceCell::const_new();
+
+/// Where the exit watcher sends the exit of each child
+static EXIT_ROUTES: Mutex<ExitRoutes> = Mutex::new(ExitRoutes {
+    senders: BTreeMap::new(),
+    unclaimed: VecDeque::new(),
+});
+
+/// Exits reaped before their child was routed that are kept for its process
+const UNCLAIMED_EXITS: usize = 64;
+
+struct ExitRoutes {
+    /// Process to send the exit of each started child to, by pid
+    senders: BTreeMap<i32, mpsc::UnboundedSender<(i32, i32)>>,
+    /// Pids and exit codes of exits without a route yet, oldest first
+    unclaimed: VecDeque<(i32, i32)>,
+}
+
+/// A managed process, shared with the socket, tasks and backups
+pub struct Process {
+    pub name: String,
+    pub config: &'static ProcessConfig,
+    pub control: mpsc::UnboundedSender<Controls>,
+    /// Output of the running process, line by line
+    pub output: Mutex<Option<broadcast::Sender<OutputLine>>>,
+    pub stdin: Mutex<Option<mpsc::UnboundedSender<StdinData>>>,
+    pub output_cache: Mutex<LogBuffer<Vec<u8>>>,
+    /// Only set if stderr has its own cache, besides the output cache
+    pub stderr_cache: Option<Mutex<LogBuffer<Vec<u8>>>>,
+    pub status: Mutex<ProcessStatus>,
+    /// Recent exits of the process, oldest first
+    pub exit_history: Mutex<VecDeque<ExitRecord>>,
+    /// Output lines matching the ready, idle or active pattern, by pid
+    matches: mpsc::UnboundedSender<(i32, OutputMatch)>,
+    /// Pids of processes whose stdin was closed
+    stdin_closed: mpsc::UnboundedSender<i32>,
+    /// Pids and exit codes of the children of this process
+    exits: mpsc::UnboundedSender<(i32, i32)>,
+    /// Kept open between commands
+    rcon: tokio::sync::Mutex<Option<rcon::Connection>>,
+    /// Lines sent to the process by users and tasks
+    pub history: history::CommandHistory,
+}
+
+impl Process {
+    /// Sender to the stdin of the running process
+    pub fn stdin(&self) -> Result<mpsc::UnboundedSender<StdinData>> {
+        self.stdin
+            .lock()
+            .clone()
+            .ok_or_else(|| eyre!("Stdin unavailable"))
+    }
+
+    /// Sends a command to the process, over RCON if configured unless it's raw.
+    /// Returns the RCON response.
+    pub async fn command(&self, command: &str, raw: bool) -> Result<Option<String>> {
+        self.history.record(command);
+        if let (Some(rcon_config), false) = (&self.config.rcon, raw) {
+            let response = rcon::command(&self.rcon, rcon_config, command.trim()).await?;
+            return Ok(Some(response));
+        }
+        let data = if raw {
+            StdinData::Raw(command.as_bytes().to_vec())
+        } else {
+            StdinData::Line(command.to_string())
+        };
+        self.stdin()?.send(data)?;
+        Ok(None)
+    }
+
+    /// Receiver of the output of the running process
+    pub fn subscribe_output(&self) -> Option<broadcast::Receiver<OutputLine>> {
+        self.output
+            .lock()
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+    }
+
+    /// Cached output, only the last `lines` lines if set
+    pub fn cached_output(&self, lines: Option<usize>) -> String {
+        let mut cache = self.output_cache.lock();
+        let output = cache.extract();
+        match lines {
+            Some(lines) => tail(output, lines).to_string(),
+            None => output.to_string(),
+        }
+    }
+
+    /// Sends a signal to the running process
+    pub fn send_signal(&self, signal: Signal) -> Result<()> {
+        let status = self.status.lock().clone();
+        let (StateKind::Running, Some(pid)) = (status.state, status.pid) else {
+            bail!("Process not running");
+        };
+        kill_group(pid, signal)
+    }
+}
+
+/// The last `lines` lines of the output
+fn tail(output: &str, lines: usize) -> &str {
+    if lines == 0 {
+        return "";
+    }
+    let complete = output.strip_suffix('\n').unwrap_or(output);
+    match complete.rmatch_indices('\n').nth(lines - 1) {
+        Some((index, _)) => &output[index + 1..],
+        None => output,
+    }
+}
+
+/// Finds a managed process by name.
+/// Without a name, that's the only process, or the one configured with `process`.
+pub fn get(name: Option<&str>) -> Result<Arc<Process>> {
+    let processes = PROCESSES.get().ok_or_else(|| eyre!("Uninitialized"))?;
+    let process = match name {
+        Some(name) => processes.get(name),
+        None if processes.len() == 1 => processes.values().next(),
+        None => processes.get(DEFAULT_PROCESS),
+    };
+    match (process, name) {
+        (Some(process), _) => Ok(process.clone()),
+        (None, Some(name)) => bail!("Unknown process: {}", name),
+        (None, None) => bail!("Multiple processes, name one of: {}", names().join(", ")),
+    }
+}
+
+/// Names of all managed processes, sorted
+pub fn names() -> Vec<String> {
+    let mut names: Vec<String> = PROCESSES
+        .get()
+        .map(|processes| processes.keys().cloned().collect())
+        .unwrap_or_default();
+    names.sort();
+    names
+}
 
 #[instrument(skip(config))]
 pub async fn deamon(config: &'static DolorousConfig) {
-    let output_cache = Mutex::new(LogBuffer::new(vec![0; config.process.cache_size as usize]));
-    OUTPUT_CACHE
-        .set(output_cache)
-        .wrap_err("Already running")
-        .unwrap();
-
-    let (control_sender, control_receiver) = mpsc::unbounded_channel();
-    CONTROL
-        .set(control_sender)
+    let mut processes = HashMap::new();
+    for (name, process_config) in &config.processes {
+        let (control_sender, control_receiver) = mpsc::unbounded_channel();
+        let (match_sender, match_receiver) = mpsc::unbounded_channel();
+        let (stdin_closed_sender, stdin_closed_receiver) = mpsc::unbounded_channel();
+        let (exit_sender, exit_receiver) = mpsc::unbounded_channel::<(i32, i32)>();
+        let cache = |size: u32| Mutex::new(LogBuffer::new(vec![0; size as usize]));
+        let process = Arc::new(Process {
+            name: name.clone(),
+            config: process_config,
+            control: control_sender,
+            output: Mutex::new(None),
+            stdin: Mutex::new(None),
+            output_cache: cache(process_config.cache_size),
+            stderr_cache: process_config.stderr_cache_size.map(cache),
+            status: Mutex::new(ProcessStatus {
+                state: StateKind::Stopped,
+                pid: None,
+                started_at: None,
+                attempt: None,
+                last_exit_code: None,
+                crash_loop: None,
+            }),
+            exit_history: Mutex::new(VecDeque::new()),
+            matches: match_sender,
+            stdin_closed: stdin_closed_sender,
+            exits: exit_sender,
+            rcon: tokio::sync::Mutex::new(None),
+            history: history::CommandHistory::load(process_config),
+        });
+        crate::telemetry::process_state(name, StateKind::Stopped);
+        processes.insert(name.clone(), process.clone());
+        tokio::spawn(
+            run_deamon(
+                process,
+                process_config,
+                control_receiver,
+                exit_receiver,
+                match_receiver,
+                stdin_closed_receiver,
+            )
+            .instrument(info_span!("process", name)),
+        );
+    }
+    PROCESSES
+        .set(processes)
         .wrap_err("Already running")
         .unwrap();
+    start_exit_watcher();
+}
 
-    let (exit_sender, exit_receiver) = mpsc::unbounded_channel::<(i32, i32)>();
-    start_exit_watcher(exit_sender);
-
-    tokio::spawn(run_deamon(config, control_receiver, exit_receiver));
+/// Sends the exit of the child to the process that started it
+fn route_exit(process: &Process, pid: i32) {
+    let mut routes = EXIT_ROUTES.lock();
+    let unclaimed = routes
+        .unclaimed
+        .iter()
+        .position(|(exited, _)| *exited == pid);
+    match unclaimed.and_then(|index| routes.unclaimed.remove(index)) {
+        // Already reaped
+        Some(exit) => {
+            let _ = process.exits.send(exit);
+        }
+        None => {
+            routes.senders.insert(pid, process.exits.clone());
+        }
+    }
 }
 
 async fn run_deamon(
-    config: &DolorousConfig,
+    process: Arc<Process>,
+    config: &'static ProcessConfig,
     mut control_receiver: UnboundedReceiver<Controls>,
     mut exit_receiver: UnboundedReceiver<(i32, i32)>,
+    mut match_receiver: UnboundedReceiver<(i32, OutputMatch)>,
+    mut stdin_closed_receiver: UnboundedReceiver<i32>,
 ) {
+    // Results of health check probes, by pid
+    let (health_sender, mut health_receiver) = mpsc::unbounded_channel::<(i32, bool)>();
     let mut wanted = WantedState::Running;
     let mut state = ProcessState::Stopped;
+    // Whether the process has ever been started successfully
+    let mut reached_running = false;
+    // Held while the process runs so stdin isn't closed when all clients disconnect
+    let mut stdin_sentinel: Option<(i32, mpsc::UnboundedSender<StdinData>)> = None;
+    let mut exit_history = ExitHistory::default();
+    // Running process tracked for the idle timeout, and when it's stopped for being idle
+    let mut idle: Option<(i32, Option<Instant>)> = None;
+    // Old processes of graceful restarts, while they stop
+    let mut retiring: event_handlers::Retiring = HashMap::new();
 
     loop {
         match (&wanted, &state) {
-            (WantedState::Running, ProcessState::Stopped) => match run::start(config).await {
-                Ok(pid) => {
-                    let timeout_at = Instant::now() + config.process.watch_delay;
-                    state = ProcessState::Watching {
-                        pid,
-                        timeout_at,
-                        attempt: 1,
-                    };
+            (WantedState::Running, ProcessState::Stopped) => {
+                match run::start(&process, config, !reached_running).await {
+                    Ok(pid) => {
+                        let timeout_at = Instant::now() + config.watch_delay;
+                        state = ProcessState::Watching {
+                            pid,
+                            timeout_at,
+                            attempt: 1,
+                        };
+                    }
+                    Err(err) => {
+                        warn!(?err, "Failed to start server!");
+                        state = ProcessState::WaitingRestart {
+                            attempt: 2,
+                            timeout_at: Instant::now() + config.restart_delay,
+                        };
+                    }
                 }
-                Err(err) => {
-                    warn!(?err, "Failed to start server!");
-                    state = ProcessState::WaitingRestart {
-                        attempt: 2,
-                        timeout_at: Instant::now() + config.process.restart_delay,
-                    };
-                }
-            },
-            (WantedState::Stopped, ProcessState::Running { pid }) => {
-                match stop_server_command(config, *pid) {
+            }
+            (WantedState::Stopped, ProcessState::Running { pid, .. }) => {
+                match stop_server_command(&process, config, *pid) {
                     Ok(s) => state = s,
                     Err(err) => {
                         error!(?err, "Failed to stop servr");
@@ -80,24 +295,215 @@ async fn run_deamon(
             _ => {}
         }
 
-        let event = fetch_event(&mut control_receiver, &mut exit_receiver, &mut state).await;
+        if config.keep_stdin_open {
+            update_stdin_sentinel(&process, &mut stdin_sentinel, &state);
+        }
+        if let Some(timeout) = config.idle_timeout {
+            idle = match (&state, idle) {
+                (ProcessState::Running { pid, .. }, Some((idle_pid, stop_at)))
+                    if *pid == idle_pid =>
+                {
+                    Some((idle_pid, stop_at))
+                }
+                (ProcessState::Running { pid, .. }, _) => {
+                    Some((*pid, Some(Instant::now() + timeout)))
+                }
+                _ => None,
+            };
+        }
+        {
+            let mut status = process.status.lock();
+            if status.pid != state.pid() {
+                status.started_at = state.pid().map(|_| Instant::now());
+            }
+            if status.state != state.kind() {
+                crate::telemetry::process_state(&process.name, state.kind());
+            }
+            status.state = state.kind();
+            status.pid = state.pid();
+            status.attempt = state.attempt();
+        }
+
+        let event = fetch_event(
+            &mut control_receiver,
+            &mut exit_receiver,
+            &mut health_receiver,
+            &mut match_receiver,
+            &mut stdin_closed_receiver,
+            &mut state,
+            idle.and_then(|(_, stop_at)| stop_at),
+        )
+        .await;
 
         match event {
             Event::Start => {
                 wanted = WantedState::Running;
+                if let ProcessState::CrashLooping = state {
+                    info!("Start request: resuming after crash loop");
+                    exit_history.clear();
+                    process.status.lock().crash_loop = None;
+                    state = ProcessState::Stopped;
+                }
             }
             Event::Stop => {
                 wanted = WantedState::Stopped;
-                if let ProcessState::Watching { pid, .. } = &state {
+                if let ProcessState::CrashLooping = state {
+                    process.status.lock().crash_loop = None;
+                    state = ProcessState::Stopped;
+                }
+                if let ProcessState::Overlapping { old, .. } = &mut state {
+                    debug!("Stop request: stopping the old process");
+                    event_handlers::retire(config, &mut retiring, old);
+                }
+                if let ProcessState::Watching { pid, .. }
+                | ProcessState::Overlapping { new_pid: pid, .. } = &state
+                {
                     debug!("Stop request: skipping watching");
-                    state = ProcessState::Running { pid: *pid };
+                    state = event_handlers::running(config, *pid);
                 }
             }
+            Event::GracefulRestart => {
+                wanted = WantedState::Running;
+                event_handlers::handle_graceful_restart(&process, config, &mut state).await
+            }
             Event::ProcessExited { pid, exit_code } => {
-                event_handlers::handle_exit_event(config, &mut state, pid, exit_code).await
+                kill_orphans(pid);
+                if let Some(exited) = retiring.remove(&pid) {
+                    info!(pid, exit_code, "Old process stopped");
+                    let _ = exited.send(());
+                    // Replaced by the new process
+                    record_exit(&process, config, pid, exit_code, true);
+                    continue;
+                }
+                let current = match &state {
+                    ProcessState::Overlapping { old, new_pid, .. } => {
+                        old.pid == pid || *new_pid == pid
+                    }
+                    state => state.pid() == Some(pid),
+                };
+                if state.pid() == Some(pid) {
+                    process.status.lock().last_exit_code = Some(exit_code);
+                    // Ends the output of connected clients, and input goes nowhere until
+                    // a new process is started
+                    *process.output.lock() = None;
+                    *process.stdin.lock() = None;
+                }
+                if let (
+                    ProcessState::Watching { pid: current, .. }
+                    | ProcessState::Running { pid: current, .. },
+                    Some(crash_loop),
+                ) = (&state, &config.crash_loop)
+                {
+                    // Clean exits aren't crashes, signals count as non-zero codes
+                    if *current == pid && exit_code != 0 && exit_history.record(crash_loop) {
+                        event_handlers::handle_crash_loop(
+                            &process, config, &mut state, pid, exit_code,
+                        )
+                        .await;
+                        record_exit(&process, config, pid, exit_code, false);
+                        continue;
+                    }
+                }
+                event_handlers::handle_exit_event(
+                    &process,
+                    config,
+                    &mut wanted,
+                    &mut state,
+                    reached_running,
+                    pid,
+                    exit_code,
+                )
+                .await;
+                if current {
+                    let restarted = matches!(state, ProcessState::WaitingRestart { .. })
+                        || state.pid().map_or(false, |new_pid| new_pid != pid);
+                    record_exit(&process, config, pid, exit_code, restarted);
+                }
             }
             Event::TimeoutReached => {
-                event_handlers::handle_timeout_reached(config, &mut wanted, &mut state).await
+                event_handlers::handle_timeout_reached(
+                    &process,
+                    config,
+                    &mut wanted,
+                    &mut state,
+                    &mut reached_running,
+                    &mut retiring,
+                )
+                .await
+            }
+            Event::HealthCheckDue => {
+                if let (ProcessState::Running { pid, health, .. }, Some(check_config)) =
+                    (&mut state, &config.health_check)
+                {
+                    // No further probes until this one is done
+                    health.next_check_at = None;
+                    let pid = *pid;
+                    let sender = health_sender.clone();
+                    let process = process.clone();
+                    tokio::spawn(
+                        async move {
+                            let healthy = health_check::probe(&process, check_config).await;
+                            let _ = sender.send((pid, healthy));
+                        }
+                        .in_current_span(),
+                    );
+                }
+            }
+            Event::HealthChecked { pid, healthy } => {
+                event_handlers::handle_health_checked(&process, config, &mut state, pid, healthy)
+            }
+            Event::Idle { pid } => {
+                if let (Some((idle_pid, stop_at)), Some(timeout)) = (&mut idle, config.idle_timeout)
+                {
+                    if *idle_pid == pid && stop_at.is_none() {
+                        debug!("Process idle");
+                        *stop_at = Some(Instant::now() + timeout);
+                    }
+                }
+            }
+            Event::Active { pid } => {
+                if let (Some((idle_pid, stop_at)), Some(timeout)) = (&mut idle, config.idle_timeout)
+                {
+                    if *idle_pid == pid {
+                        debug!("Process active");
+                        *stop_at = match config.idle_pattern {
+                            Some(_) => None,
+                            None => Some(Instant::now() + timeout),
+                        };
+                    }
+                }
+            }
+            Event::StdinClosed { pid } => {
+                if let (true, ProcessState::Watching { .. } | ProcessState::Running { .. }) =
+                    (config.stop_on_stdin_eof, &state)
+                {
+                    if state.pid() == Some(pid) {
+                        info!(pid, "Stdin closed, stopping");
+                        wanted = WantedState::Stopped;
+                        // The stop command can't be sent anymore, only wait for the exit
+                        state = ProcessState::Stopping(StoppingState::Command {
+                            timeout_at: Instant::now() + config.stop_config.term_timeout,
+                            pid,
+                        });
+                    }
+                }
+            }
+            Event::IdleTimeout => {
+                info!("Process idle for too long, stopping");
+                wanted = WantedState::Stopped;
+                idle = None;
+            }
+            Event::Ready { pid } => {
+                event_handlers::handle_ready(
+                    &process,
+                    config,
+                    &mut wanted,
+                    &mut state,
+                    &mut reached_running,
+                    &mut retiring,
+                    pid,
+                )
+                .await
             }
         }
     }
@@ -106,28 +512,47 @@ async fn run_deamon(
 async fn fetch_event(
     control_receiver: &mut UnboundedReceiver<Controls>,
     exit_receiver: &mut UnboundedReceiver<(i32, i32)>,
+    health_receiver: &mut UnboundedReceiver<(i32, bool)>,
+    match_receiver: &mut UnboundedReceiver<(i32, OutputMatch)>,
+    stdin_closed_receiver: &mut UnboundedReceiver<i32>,
     state: &mut ProcessState,
+    idle_stop_at: Option<Instant>,
 ) -> Event {
+    let health_check_at = match &state {
+        ProcessState::Running { health, .. } => health.next_check_at,
+        _ => None,
+    };
     let timeout = match &state {
         ProcessState::Watching { timeout_at, .. } => Some(timeout_at),
         ProcessState::WaitingRestart { timeout_at, .. } => Some(timeout_at),
         ProcessState::Stopping(StoppingState::Command { timeout_at, .. }) => Some(timeout_at),
         ProcessState::Stopping(StoppingState::Terminate { timeout_at, .. }) => Some(timeout_at),
+        ProcessState::Overlapping { timeout_at, .. } => Some(timeout_at),
+        ProcessState::Running {
+            restart_at: Some(restart_at),
+            ..
+        } => Some(restart_at),
         _ => None,
     };
 
     match timeout {
         Some(t) => {
             select! {
-                Some(control) = control_receiver.recv() => {
-                    match control {
-                        Controls::Start => Event::Start,
-                        Controls::Stop => Event::Stop,
-                    }
-                },
+                // Control messages first, so a stop isn't delayed by other busy branches
+                biased;
+                Some(control) = control_receiver.recv() => control.into(),
                 Some((pid, exit_code)) = exit_receiver.recv() => {
                     Event::ProcessExited { pid, exit_code }
                 },
+                Some((pid, healthy)) = health_receiver.recv() => {
+                    Event::HealthChecked { pid, healthy }
+                },
+                Some((pid, matched)) = match_receiver.recv() => matched.event(pid),
+                Some(pid) = stdin_closed_receiver.recv() => Event::StdinClosed { pid },
+                _ = sleep_until_some(idle_stop_at) => Event::IdleTimeout,
+                _ = sleep_until_some(health_check_at) => {
+                    Event::HealthCheckDue
+                },
                 _ = tokio::time::sleep_until(*t) => {
                     Event::TimeoutReached
                 },
@@ -135,28 +560,101 @@ async fn fetch_event(
         }
         None => {
             select! {
-                Some(control) = control_receiver.recv() => {
-                    match control {
-                        Controls::Start => Event::Start,
-                        Controls::Stop => Event::Stop,
-                    }
-                },
+                // Control messages first, so a stop isn't delayed by other busy branches
+                biased;
+                Some(control) = control_receiver.recv() => control.into(),
                 Some((pid, exit_code)) = exit_receiver.recv() => {
                     Event::ProcessExited { pid, exit_code }
                 },
+                Some((pid, healthy)) = health_receiver.recv() => {
+                    Event::HealthChecked { pid, healthy }
+                },
+                Some((pid, matched)) = match_receiver.recv() => matched.event(pid),
+                Some(pid) = stdin_closed_receiver.recv() => Event::StdinClosed { pid },
+                _ = sleep_until_some(idle_stop_at) => Event::IdleTimeout,
+                _ = sleep_until_some(health_check_at) => {
+                    Event::HealthCheckDue
+                },
             }
         }
     }
 }
 
-fn start_exit_watcher(channel: mpsc::UnboundedSender<(i32, i32)>) {
+/// Sleeps until the given time, or forever without one
+async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Adds an exit to the history, dropping the oldest beyond the configured size
+fn record_exit(
+    process: &Process,
+    config: &ProcessConfig,
+    pid: i32,
+    exit_code: i32,
+    restarted: bool,
+) {
+    let mut history = process.exit_history.lock();
+    history.push_back(ExitRecord {
+        pid,
+        exit_code,
+        at: Local::now(),
+        restarted,
+    });
+    while history.len() > config.exit_history_size {
+        history.pop_front();
+    }
+}
+
+fn update_stdin_sentinel(
+    process: &Process,
+    sentinel: &mut Option<(i32, mpsc::UnboundedSender<StdinData>)>,
+    state: &ProcessState,
+) {
+    let held_pid = sentinel.as_ref().map(|(pid, _)| *pid);
+    match (state.pid(), held_pid) {
+        (Some(pid), Some(held_pid)) if pid == held_pid => {}
+        (Some(pid), _) => {
+            *sentinel = process
+                .stdin
+                .lock()
+                .as_ref()
+                .cloned()
+                .map(|sender| (pid, sender));
+        }
+        (None, _) if matches!(state, ProcessState::Stopping(_)) => {}
+        (None, _) => *sentinel = None,
+    }
+}
+
+fn start_exit_watcher() {
+    let send = |pid: Pid, exit_code: i32| {
+        let exit = (pid.as_raw() as i32, exit_code);
+        let mut routes = EXIT_ROUTES.lock();
+        match routes.senders.remove(&exit.0) {
+            Some(sender) => {
+                if let Err(err) = sender.send(exit) {
+                    error!(?err, "Exit send error");
+                }
+            }
+            // Started but not routed yet, or not a managed child
+            None => {
+                if routes.unclaimed.len() >= UNCLAIMED_EXITS {
+                    routes.unclaimed.pop_front();
+                }
+                routes.unclaimed.push_back(exit);
+            }
+        }
+    };
     std::thread::spawn(move || {
         loop {
             match waitpid(None, None) {
-                Ok(WaitStatus::Exited(pid, exit_code)) => {
-                    if let Err(err) = channel.send((pid.as_raw() as i32, exit_code)) {
-                        error!(?err, "Exit send error");
-                    }
+                Ok(WaitStatus::Exited(pid, exit_code)) => send(pid, exit_code),
+                // Reported like a shell does, as 128 + the signal number
+                Ok(WaitStatus::Signaled(pid, signal, _)) => {
+                    send(pid, SIGNAL_EXIT_BASE + signal as i32)
                 }
                 Err(Errno::ECHILD) => {
                     // No child processes
@@ -172,22 +670,115 @@ fn start_exit_watcher(channel: mpsc::UnboundedSender<(i32, i32)>) {
     });
 }
 
-fn stop_server_command(config: &DolorousConfig, pid: i32) -> Result<ProcessState> {
-    let stdin_channel = STDIN
-        .lock()
-        .as_ref()
-        .cloned()
-        .ok_or_else(|| eyre!("Stdin unavailable"))?;
-    stdin_channel.send(config.process.stop_config.stop_command.clone())?;
-    let timeout_at = Instant::now() + config.process.stop_config.term_timeout;
+fn stop_server_command(
+    process: &Process,
+    config: &ProcessConfig,
+    pid: i32,
+) -> Result<ProcessState> {
+    let stdin_channel = process.stdin()?;
+    stdin_channel.send(StdinData::Line(config.stop_config.stop_command.clone()))?;
+    let timeout_at = Instant::now() + config.stop_config.term_timeout;
     Ok(ProcessState::Stopping(StoppingState::Command {
         timeout_at,
         pid,
     }))
 }
 
-#[derive(Debug)]
+/// Parses a signal name, with or without the `SIG` prefix
+pub fn parse_signal(name: &str) -> Result<Signal> {
+    let name = name.trim().to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    Signal::from_str(&name).map_err(|_| eyre!("Unknown signal: {}", name))
+}
+
+/// Sends a signal to the process group of the child. The child leads its own group,
+/// so processes it forked (e.g. the JVM started by a wrapper script) get the signal too.
+pub fn kill_group(pid: i32, signal: Signal) -> Result<()> {
+    kill(Pid::from_raw(-pid), signal).wrap_err("Failed to send signal")
+}
+
+/// Kills what's left of the process group of an exited child, e.g. processes it forked that
+/// didn't exit with it. Their group outlives the child, so its id isn't reused for a new one.
+fn kill_orphans(pid: i32) {
+    if kill(Pid::from_raw(-pid), Signal::SIGKILL).is_ok() {
+        warn!(pid, "Killed processes left behind by the exited process");
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum Controls {
     Start,
     Stop,
+    /// Starts a new process before stopping the running one, see `graceful-restart`
+    GracefulRestart,
+}
+
+impl From<Controls> for Event {
+    fn from(control: Controls) -> Self {
+        match control {
+            Controls::Start => Event::Start,
+            Controls::Stop => Event::Stop,
+            Controls::GracefulRestart => Event::GracefulRestart,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn running_process_schedules_health_check() {
+        let yaml = "command: sleep 60\nrestart: never\nstop-config: {}\nworking-directory: /\n\
+            health-check: {type: tcp, port: 25565}\n";
+        let config: ProcessConfig = serde_yaml::from_str(yaml).unwrap();
+        let state = event_handlers::running(&config, 1);
+        assert!(matches!(
+            state,
+            ProcessState::Running {
+                health: HealthState {
+                    next_check_at: Some(_),
+                    ..
+                },
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn stop_is_not_starved_by_exit_events() {
+        let (control_sender, mut control_receiver) = unbounded_channel();
+        let (exit_sender, mut exit_receiver) = unbounded_channel();
+        let (_health_sender, mut health_receiver) = unbounded_channel();
+        let (_match_sender, mut match_receiver) = unbounded_channel();
+        let (_stdin_closed_sender, mut stdin_closed_receiver) = unbounded_channel();
+        for pid in 0..10_000 {
+            exit_sender.send((pid, 0)).unwrap();
+        }
+        // Sent from another task while the loop is busy with exits
+        tokio::spawn(async move { control_sender.send(Controls::Stop).unwrap() });
+        let mut state = ProcessState::Stopped;
+        for iteration in 0..1000 {
+            let event = fetch_event(
+                &mut control_receiver,
+                &mut exit_receiver,
+                &mut health_receiver,
+                &mut match_receiver,
+                &mut stdin_closed_receiver,
+                &mut state,
+                None,
+            )
+            .await;
+            if let Event::Stop = event {
+                println!("stop fetched after {iteration} exit events");
+                return;
+            }
+        }
+        panic!("stop not fetched within 1000 events");
+    }
 }
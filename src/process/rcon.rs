@@ -0,0 +1,139 @@
+use crate::configs::RconConfig;
+use color_eyre::eyre::{bail, WrapErr};
+use color_eyre::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tracing::debug;
+
+const LOGIN: i32 = 3;
+const COMMAND: i32 = 2;
+const RESPONSE: i32 = 0;
+/// Largest packet accepted from the server
+const MAX_PACKET_LENGTH: i32 = 1024 * 1024;
+
+/// An authenticated RCON connection
+pub struct Connection {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+impl Connection {
+    async fn connect(config: &RconConfig) -> Result<Self> {
+        let stream = TcpStream::connect((config.address, config.port))
+            .await
+            .wrap_err("Failed to connect to RCON")?;
+        let mut connection = Self { stream, next_id: 1 };
+        let id = connection.send(LOGIN, &config.password).await?;
+        let (response_id, _) = connection.receive().await?;
+        if response_id == -1 {
+            bail!("RCON authentication failed");
+        }
+        if response_id != id {
+            bail!("Unexpected RCON login response");
+        }
+        Ok(connection)
+    }
+
+    /// Sends a packet. Returns its id.
+    async fn send(&mut self, kind: i32, body: &str) -> Result<i32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        let mut packet = Vec::with_capacity(body.len() + 14);
+        packet.extend((body.len() as i32 + 10).to_le_bytes());
+        packet.extend(id.to_le_bytes());
+        packet.extend(kind.to_le_bytes());
+        packet.extend(body.as_bytes());
+        packet.extend([0, 0]);
+        self.stream.write_all(&packet).await?;
+        Ok(id)
+    }
+
+    /// Receives a packet. Returns its id and body.
+    async fn receive(&mut self) -> Result<(i32, String)> {
+        let length = self.stream.read_i32_le().await?;
+        if !(10..=MAX_PACKET_LENGTH).contains(&length) {
+            bail!("Invalid RCON packet length: {}", length);
+        }
+        let mut packet = vec![0; length as usize];
+        self.stream.read_exact(&mut packet).await?;
+        let id = i32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let body = &packet[8..packet.len() - 2];
+        Ok((id, String::from_utf8_lossy(body).into_owned()))
+    }
+
+    async fn command(&mut self, command: &str) -> Result<String> {
+        let ids = self.send_command(command).await?;
+        self.read_response(ids).await
+    }
+
+    /// Sends the command, followed by the empty packet marking the end of its response.
+    /// Returns the ids of both.
+    async fn send_command(&mut self, command: &str) -> Result<(i32, i32)> {
+        let id = self.send(COMMAND, command).await?;
+        // Long responses are split into several packets. The server answers
+        // packets in order, so the reply to an empty one marks the end.
+        let end = self.send(RESPONSE, "").await?;
+        Ok((id, end))
+    }
+
+    async fn read_response(&mut self, (id, end): (i32, i32)) -> Result<String> {
+        let mut response = String::new();
+        loop {
+            let (response_id, body) = self.receive().await?;
+            if response_id == end {
+                break;
+            }
+            if response_id == id {
+                response.push_str(&body);
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Sends a command over RCON, connecting first if needed. Returns the response.
+pub async fn command(
+    connection: &Mutex<Option<Connection>>,
+    config: &RconConfig,
+    command: &str,
+) -> Result<String> {
+    let mut connection = connection.lock().await;
+    if let Some(existing) = connection.as_mut() {
+        let result = timeout(config.timeout, async {
+            match existing.send_command(command).await {
+                Ok(ids) => Some(existing.read_response(ids).await),
+                // E.g. the process restarted since, try again with a new connection
+                Err(err) => {
+                    debug!(?err, "RCON connection lost, reconnecting");
+                    None
+                }
+            }
+        })
+        .await;
+        match result {
+            Ok(Some(Ok(response))) => return Ok(response),
+            // The command may have run, so it isn't sent again
+            Ok(Some(Err(err))) => {
+                *connection = None;
+                return Err(err.wrap_err("Failed to read RCON response"));
+            }
+            Ok(None) => {}
+            Err(_) => {
+                *connection = None;
+                bail!("RCON command timed out");
+            }
+        }
+        *connection = None;
+    }
+    let (fresh, response) = timeout(config.timeout, async {
+        let mut fresh = Connection::connect(config).await?;
+        let response = fresh.command(command).await?;
+        Ok::<_, color_eyre::Report>((fresh, response))
+    })
+    .await
+    .wrap_err("RCON command timed out")??;
+    *connection = Some(fresh);
+    Ok(response)
+}
@@ -1,130 +1,248 @@
-use super::{OUTPUT_CACHE, OUTPUT_WATCH, STDIN};
-use crate::configs::DolorousConfig;
-use color_eyre::eyre::{eyre, WrapErr};
+use super::log_targets::OutputSinks;
+use super::types::{OutputLine, OutputMatch, StdinData, Stream};
+use super::Process;
+use crate::configs::{LineFormat, ProcessConfig};
+use chrono::Local;
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
-use std::fmt::Write;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::pty::openpty;
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use nix::unistd::{setpgid, setsid, Pid};
+use regex::Regex;
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
 use tokio::process::Command;
-use tokio::sync::{mpsc, watch};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
 use tracing::{debug, error, info, info_span, instrument, warn, Instrument};
 
-/// Returns pid of started process
-#[instrument(skip(config))]
-pub async fn start(config: &DolorousConfig) -> Result<i32> {
-    let command = shell_words::split(&config.process.command).wrap_err("Invalid command")?;
-    let mut child = Command::new(&command[0])
+/// Smallest allowed read buffer capacity
+const MIN_READ_BUFFER_SIZE: usize = 1024;
+
+/// Returns pid of started process.
+/// The process is started in its own process group, which is signalled as a whole,
+/// so children it forks don't outlive it. Whatever is left of the group once the process
+/// exited is killed.
+#[instrument(skip_all, fields(process = %process.name, first_start))]
+pub async fn start(
+    process: &Arc<Process>,
+    config: &ProcessConfig,
+    first_start: bool,
+) -> Result<i32> {
+    let command = shell_words::split(&config.command).wrap_err("Invalid command")?;
+    let program = command.first().ok_or_else(|| eyre!("Empty command"))?;
+    let program = resolve_program(program, config.search_path.as_deref())?;
+    let working_directory = &config.working_directory;
+    if !working_directory.exists() {
+        std::fs::create_dir_all(working_directory).wrap_err_with(|| {
+            format!(
+                "Failed to create working directory {}",
+                working_directory.display()
+            )
+        })?;
+        info!("Created working directory {}", working_directory.display());
+    }
+    let mut command_builder = Command::new(program);
+    if config.env_clear {
+        command_builder.env_clear();
+    }
+    command_builder.envs(&config.env);
+    if let Some(search_path) = &config.search_path {
+        command_builder.env("PATH", search_path);
+    }
+    let pty = if config.pty { Some(open_pty()?) } else { None };
+    match &pty {
+        Some(pty) => {
+            command_builder
+                .stdin(slave_stdio(&pty.slave)?)
+                .stdout(slave_stdio(&pty.slave)?)
+                .stderr(slave_stdio(&pty.slave)?);
+            // Safety: setsid and ioctl are async-signal-safe
+            unsafe {
+                command_builder.pre_exec(|| {
+                    // A new session, with the pty as controlling terminal
+                    setsid()?;
+                    set_controlling_terminal(0, 0)?;
+                    Ok(())
+                });
+            }
+        }
+        None => {
+            command_builder
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            // Safety: setpgid is async-signal-safe
+            unsafe {
+                command_builder.pre_exec(|| {
+                    setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(std::io::Error::from)
+                });
+            }
+        }
+    }
+    limit_resources(&mut command_builder, config);
+    let mut child = command_builder
         .args(&command[1..])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdin(Stdio::piped())
-        .current_dir(&config.process.working_directory)
+        .current_dir(working_directory)
         .spawn()
         .wrap_err("Failed to spawn child!")?;
+    // Closes the parent's copies of the pty slave, so reading the master ends when the child exits
+    drop(command_builder);
 
     let pid = child.id().ok_or_else(|| eyre!("Child exited instantly"))? as i32;
+    super::route_exit(process, pid);
 
-    let stdout = child
-        .stdout
-        .take()
-        .ok_or_else(|| eyre!("Missing child stdout"))?;
-    let stderr = child
-        .stderr
-        .take()
-        .ok_or_else(|| eyre!("Missing child stderr"))?;
-    let mut stdin = child
-        .stdin
-        .take()
-        .ok_or_else(|| eyre!("Missing child stdin!"))?;
-
-    let (merge_sender, mut merge_receiver) = mpsc::unbounded_channel::<String>();
-    let merge_sender_err = merge_sender.clone();
-    // Stdout reader
-    tokio::spawn(
-        async move {
-            let mut reader = BufReader::new(stdout);
-            loop {
-                let mut line = String::new();
-                match reader.read_line(&mut line).await {
-                    Ok(n) if n < 1 => {
-                        break;
-                    }
-                    Err(err) => {
-                        error!(?err, "Reading stdout failed");
-                        continue;
-                    }
-                    _ => {}
-                }
-                let mut cache = OUTPUT_CACHE.get().unwrap().lock();
-                debug!("Stdout: {line:?}");
-                if let Err(err) = cache.write_str(line.as_str()) {
-                    error!(?err, "Cache error");
-                }
-                let _ = merge_sender.send(line);
-            }
-            debug!("Stdout closed");
+    let (stdout, stderr, mut stdin): (ChildOutput, Option<ChildOutput>, ChildInput) = match pty {
+        Some(pty) => {
+            drop(pty.slave);
+            let master = PtyMaster::new(pty.master)?;
+            (Box::new(master.clone()), None, Box::new(master))
+        }
+        None => {
+            let stdout = child
+                .stdout
+                .take()
+                .ok_or_else(|| eyre!("Missing child stdout"))?;
+            let stderr = child
+                .stderr
+                .take()
+                .ok_or_else(|| eyre!("Missing child stderr"))?;
+            let stdin = child
+                .stdin
+                .take()
+                .ok_or_else(|| eyre!("Missing child stdin!"))?;
+            (
+                Box::new(stdout),
+                Some(Box::new(stderr) as ChildOutput),
+                Box::new(stdin),
+            )
         }
+    };
+
+    let read_buffer_size = if config.read_buffer_size < MIN_READ_BUFFER_SIZE {
+        warn!(
+            "Read buffer size {} too small, using {}",
+            config.read_buffer_size, MIN_READ_BUFFER_SIZE
+        );
+        MIN_READ_BUFFER_SIZE
+    } else {
+        config.read_buffer_size
+    };
+    let max_line_length = config.max_line_length.max(1);
+    let patterns = Arc::new(OutputPatterns::new(config, pid, process.matches.clone())?);
+
+    let sinks = Arc::new(OutputSinks::new(process, config, pid));
+    let (merge_sender, mut merge_receiver) = mpsc::unbounded_channel::<OutputLine>();
+    tokio::spawn(
+        read_output(
+            stdout,
+            Stream::Stdout,
+            read_buffer_size,
+            max_line_length,
+            config.line_format,
+            patterns.clone(),
+            sinks.clone(),
+            merge_sender.clone(),
+        )
         .instrument(info_span!("read_stdout", pid)),
     );
+    if let Some(stderr) = stderr {
+        tokio::spawn(
+            read_output(
+                stderr,
+                Stream::Stderr,
+                read_buffer_size,
+                max_line_length,
+                config.line_format,
+                patterns,
+                sinks,
+                merge_sender,
+            )
+            .instrument(info_span!("read_stderr", pid)),
+        );
+    }
+
+    let (output_sender, _) = broadcast::channel(config.output_buffer_lines.max(1));
+    let _ = process.output.lock().insert(output_sender.clone());
 
-    // Stderr reader
+    // Output merger
+    let mut rate_limiter = config.output_rate_limit.map(RateLimiter::new);
+    let name = process.name.clone();
     tokio::spawn(
         async move {
-            let mut reader = BufReader::new(stderr);
+            let mut summary_interval = tokio::time::interval(RATE_LIMIT_WINDOW);
             loop {
-                let mut line = String::new();
-                match reader.read_line(&mut line).await {
-                    Ok(n) if n < 1 => {
-                        break;
+                let line = select! {
+                    line = merge_receiver.recv() => match line {
+                        Some(line) => {
+                            crate::telemetry::output_produced(&name, line.text.len());
+                            line
+                        }
+                        None => break,
+                    },
+                    _ = summary_interval.tick() => {
+                        match rate_limiter.as_mut().and_then(RateLimiter::take_summary) {
+                            // Notices from dolorous itself go to stderr
+                            Some(summary) => OutputLine {
+                                stream: Stream::Stderr,
+                                text: summary,
+                            },
+                            None => continue,
+                        }
                     }
-                    Err(err) => {
-                        error!(?err, "Reading stderr failed");
+                };
+                if let Some(limiter) = &mut rate_limiter {
+                    if !limiter.allow() {
                         continue;
                     }
-                    _ => {}
-                }
-                let mut cache = OUTPUT_CACHE.get().unwrap().lock();
-                debug!("Stderr: {line:?}");
-                if let Err(err) = cache.write_str(line.as_str()) {
-                    error!(?err, "Cache error");
-                }
-                let _ = merge_sender_err.send(line);
-            }
-            debug!("Stderr closed");
-        }
-        .instrument(info_span!("read_stderr", pid)),
-    );
-
-    let (watch_sender, watch_receiver) = watch::channel::<String>("".into());
-    let _ = OUTPUT_WATCH.lock().insert(watch_receiver);
-
-    // Output merger
-    tokio::spawn(
-        async move {
-            while let Some(line) = merge_receiver.recv().await {
-                if let Err(err) = watch_sender.send(line) {
-                    warn!(?err, "Watch merge error");
                 }
+                // Fails only without receivers
+                let _ = output_sender.send(line);
             }
         }
         .in_current_span()
         .instrument(info_span!("merge_output", pid)),
     );
 
-    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
-    let _ = STDIN.lock().insert(sender);
+    let (sender, mut receiver) = mpsc::unbounded_channel::<StdinData>();
+    if first_start {
+        for command in &config.first_start_commands {
+            let _ = sender.send(StdinData::Line(command.clone()));
+        }
+    }
+    for command in &config.startup_commands {
+        let _ = sender.send(StdinData::Line(command.clone()));
+    }
+    let _ = process.stdin.lock().insert(sender);
+
+    let stdin_closed = process.stdin_closed.clone();
 
     tokio::spawn(
         async move {
-            while let Some(line) = receiver.recv().await {
-                if let Err(_err) = stdin.write_all(line.trim().as_bytes()).await {
+            while let Some(data) = receiver.recv().await {
+                if let Err(_err) = stdin.write_all(&data.into_bytes()).await {
                     break;
                 }
-                if let Err(_err) = stdin.write_all(b"\n").await {
+                if let Err(_err) = stdin.flush().await {
                     break;
                 }
             }
             info!("Stdin closed");
+            let _ = stdin_closed.send(pid);
         }
         .instrument(info_span!("write_stdin", pid)),
     );
@@ -132,3 +250,364 @@ pub async fn start(config: &DolorousConfig) -> Result<i32> {
     info!("Child started: {}", pid);
     Ok(pid)
 }
+
+type ChildOutput = Box<dyn AsyncRead + Unpin + Send>;
+type ChildInput = Box<dyn AsyncWrite + Unpin + Send>;
+
+// TIOCSCTTY, to make the pty the controlling terminal of the child
+nix::ioctl_write_int_bad!(set_controlling_terminal, nix::libc::TIOCSCTTY);
+
+/// Applies the configured resource limits to the child, with rlimits
+#[cfg(target_os = "linux")]
+fn limit_resources(command: &mut Command, config: &ProcessConfig) {
+    use nix::sys::resource::{setrlimit, Resource};
+    let memory = config.memory_limit.map(|limit| limit.as_u64());
+    let cpu = config.cpu_limit.map(|limit| limit.as_secs().max(1));
+    if memory.is_none() && cpu.is_none() {
+        return;
+    }
+    // Safety: setrlimit is async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(memory) = memory {
+                setrlimit(Resource::RLIMIT_AS, Some(memory), Some(memory))?;
+            }
+            if let Some(cpu) = cpu {
+                // SIGXCPU at the soft limit, SIGKILL at the hard one
+                setrlimit(Resource::RLIMIT_CPU, Some(cpu), Some(cpu + 1))?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn limit_resources(_command: &mut Command, config: &ProcessConfig) {
+    if config.memory_limit.is_some() || config.cpu_limit.is_some() {
+        warn!("Resource limits aren't supported on this platform, ignoring them");
+    }
+}
+
+/// Both ends of a pseudo-terminal, closed when dropped
+struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+}
+
+/// Opens a pseudo-terminal for the child. Input isn't echoed back, like with pipes.
+fn open_pty() -> Result<Pty> {
+    let pty = openpty(None, None).wrap_err("Failed to open pty")?;
+    // Safety: openpty returned new descriptors, owned by nothing else
+    let pty = unsafe {
+        Pty {
+            master: OwnedFd::from_raw_fd(pty.master),
+            slave: OwnedFd::from_raw_fd(pty.slave),
+        }
+    };
+    let slave = pty.slave.as_raw_fd();
+    let mut termios = tcgetattr(slave).wrap_err("Failed to configure pty")?;
+    termios.local_flags.remove(LocalFlags::ECHO);
+    tcsetattr(slave, SetArg::TCSANOW, &termios).wrap_err("Failed to configure pty")?;
+    Ok(pty)
+}
+
+fn slave_stdio(slave: &OwnedFd) -> Result<Stdio> {
+    let fd = slave.try_clone().wrap_err("Failed to duplicate pty")?;
+    Ok(Stdio::from(fd))
+}
+
+/// Non-blocking pty master, read and written through the reactor instead of the blocking pool
+#[derive(Clone)]
+struct PtyMaster(Arc<AsyncFd<std::fs::File>>);
+
+impl PtyMaster {
+    fn new(master: OwnedFd) -> Result<Self> {
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .wrap_err("Failed to configure pty")?;
+        let master = AsyncFd::new(std::fs::File::from(master)).wrap_err("Failed to watch pty")?;
+        Ok(Self(Arc::new(master)))
+    }
+}
+
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|master| master.get_ref().read(unfilled)) {
+                Ok(read) => {
+                    buf.advance(read?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PtyMaster {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|master| master.get_ref().write(buf)) {
+                Ok(written) => return Poll::Ready(written),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Patterns the output of a process is matched against, reported to the deamon
+struct OutputPatterns {
+    pid: i32,
+    sender: mpsc::UnboundedSender<(i32, OutputMatch)>,
+    ready: Option<Regex>,
+    idle: Option<Regex>,
+    active: Option<Regex>,
+}
+
+impl OutputPatterns {
+    fn new(
+        config: &ProcessConfig,
+        pid: i32,
+        sender: mpsc::UnboundedSender<(i32, OutputMatch)>,
+    ) -> Result<Self> {
+        let compile = |pattern: &Option<String>, what: &str| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .wrap_err_with(|| format!("Invalid {what} pattern"))
+        };
+        // Idle tracking is off without a timeout
+        let idle_timeout = config.idle_timeout.is_some();
+        Ok(Self {
+            pid,
+            sender,
+            ready: compile(&config.ready_pattern, "ready")?,
+            idle: compile(&config.idle_pattern, "idle")?.filter(|_| idle_timeout),
+            active: compile(&config.active_pattern, "active")?.filter(|_| idle_timeout),
+        })
+    }
+
+    /// Reports the patterns the line matches. `ready` is cleared once the ready pattern matched.
+    fn check(&self, line: &str, ready: &mut bool) {
+        let line = line.trim_end();
+        let matches = |pattern: &Option<Regex>| {
+            pattern
+                .as_ref()
+                .map_or(false, |pattern| pattern.is_match(line))
+        };
+        let mut found = Vec::new();
+        if *ready && matches(&self.ready) {
+            *ready = false;
+            found.push(OutputMatch::Ready);
+        }
+        if matches(&self.idle) {
+            found.push(OutputMatch::Idle);
+        }
+        if matches(&self.active) {
+            found.push(OutputMatch::Active);
+        }
+        for matched in found {
+            let _ = self.sender.send((self.pid, matched));
+        }
+    }
+}
+
+/// Reads the output of the child line by line, until it's closed
+async fn read_output(
+    output: ChildOutput,
+    stream: Stream,
+    read_buffer_size: usize,
+    max_line_length: usize,
+    line_format: LineFormat,
+    patterns: Arc<OutputPatterns>,
+    sinks: Arc<OutputSinks>,
+    merge_sender: mpsc::UnboundedSender<OutputLine>,
+) {
+    let mut reader = BufReader::with_capacity(read_buffer_size, output);
+    // Whether the ready pattern is still to be matched
+    let mut ready = true;
+    loop {
+        let mut buffer = Vec::new();
+        let truncated = match read_line_capped(&mut reader, &mut buffer, max_line_length).await {
+            Ok((n, _)) if n < 1 => {
+                break;
+            }
+            Ok((_, truncated)) => truncated,
+            // The pty master fails with EIO once the child closed the slave
+            Err(err) if err.raw_os_error() == Some(Errno::EIO as i32) => break,
+            Err(err) => {
+                error!(?err, "Reading {} failed", stream.as_str());
+                continue;
+            }
+        };
+        if truncated {
+            // Don't leave half of a multibyte character at the cut
+            if let Err(err) = std::str::from_utf8(&buffer) {
+                if err.error_len().is_none() {
+                    buffer.truncate(err.valid_up_to());
+                }
+            }
+        }
+        let mut line = String::from_utf8_lossy(&buffer).into_owned();
+        if truncated {
+            warn!(
+                "{} line longer than {} bytes, truncated",
+                stream.as_str(),
+                max_line_length
+            );
+            line.push_str(" [dolorous: line truncated]\n");
+        }
+        debug!("{}: {line:?}", stream.as_str());
+        patterns.check(&line, &mut ready);
+        let prefix = line_prefix(line_format, stream);
+        sinks.write(stream, &prefix, &line);
+        if line_format.socket {
+            line.insert_str(0, &prefix);
+        }
+        let _ = merge_sender.send(OutputLine { stream, text: line });
+    }
+    debug!("{} closed", stream.as_str());
+}
+
+/// Timestamp and stream tag to put before a line, as configured
+fn line_prefix(format: LineFormat, stream: Stream) -> String {
+    let mut prefix = String::new();
+    if format.timestamp {
+        prefix.push_str(&Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z ").to_string());
+    }
+    if format.stream {
+        prefix.push_str(&format!("[{}] ", stream.as_str()));
+    }
+    prefix
+}
+
+/// Reads a line like `read_line`, keeping at most `max_length` bytes of it. The rest of a longer
+/// line is skipped. Returns the number of bytes read and whether the line was truncated.
+async fn read_line_capped<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    max_length: usize,
+) -> std::io::Result<(usize, bool)> {
+    let mut read = 0;
+    let mut truncated = false;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            break;
+        }
+        let (used, done) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (available.len(), false),
+        };
+        let kept = used.min(max_length.saturating_sub(line.len()));
+        line.extend_from_slice(&available[..kept]);
+        truncated |= kept < used;
+        reader.consume(used);
+        read += used;
+        if done {
+            break;
+        }
+    }
+    Ok((read, truncated))
+}
+
+/// Finds the executable for a program name, searching the given or inherited `PATH`
+fn resolve_program(program: &str, search_path: Option<&str>) -> Result<PathBuf> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+    let search_path = match search_path {
+        Some(path) => OsString::from(path),
+        None => std::env::var_os("PATH").unwrap_or_default(),
+    };
+    let directories: Vec<PathBuf> = std::env::split_paths(&search_path).collect();
+    for directory in &directories {
+        let candidate = directory.join(program);
+        let executable = std::fs::metadata(&candidate)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if executable {
+            return Ok(candidate);
+        }
+    }
+    let searched: Vec<_> = directories
+        .iter()
+        .map(|d| d.display().to_string())
+        .collect();
+    bail!(
+        "Command {:?} not found on PATH (searched: {})",
+        program,
+        searched.join(", ")
+    )
+}
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Limits the number of lines forwarded per second
+struct RateLimiter {
+    limit: u32,
+    window_start: Instant,
+    count: u32,
+    suppressed: u64,
+}
+
+impl RateLimiter {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            count: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns whether the line may be forwarded
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= RATE_LIMIT_WINDOW {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count > self.limit {
+            self.suppressed += 1;
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Returns a notice about suppressed lines, if any were suppressed since the last one
+    fn take_summary(&mut self) -> Option<String> {
+        if self.suppressed == 0 {
+            return None;
+        }
+        let summary = format!(
+            "[dolorous] Output rate limited: suppressed {} lines\n",
+            self.suppressed
+        );
+        self.suppressed = 0;
+        // The summary itself always gets through
+        self.count = 0;
+        self.window_start = Instant::now();
+        Some(summary)
+    }
+}
@@ -1,3 +1,7 @@
+use crate::configs::{CrashLoopConfig, ProcessConfig};
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::Instant;
 
 #[derive(Debug)]
@@ -20,21 +24,270 @@ pub enum ProcessState {
     },
     Running {
         pid: i32,
+        /// Proactive restart after the maximum uptime
+        restart_at: Option<Instant>,
+        health: HealthState,
     },
     Stopping(StoppingState),
+    /// The process exited too often in a short time. It's only started again on request.
+    CrashLooping,
+    /// Graceful restart: the new process is watched while the old one keeps running.
+    /// The new process owns the stdin and output from its start, so input goes to it and
+    /// new clients see its output. Already connected clients keep the old output until the
+    /// old process exits. If the new process fails, the old one gets them back.
+    Overlapping {
+        old: OldProcess,
+        new_pid: i32,
+        timeout_at: Instant,
+    },
+}
+
+/// The process being replaced by a graceful restart
+#[derive(Debug)]
+pub struct OldProcess {
+    pub pid: i32,
+    pub stdin: Option<mpsc::UnboundedSender<StdinData>>,
+    pub output: Option<broadcast::Sender<OutputLine>>,
+}
+
+impl ProcessState {
+    /// Pid of the current child, if any.
+    pub fn pid(&self) -> Option<i32> {
+        match self {
+            ProcessState::Watching { pid, .. }
+            | ProcessState::Running { pid, .. }
+            | ProcessState::Overlapping { new_pid: pid, .. } => Some(*pid),
+            ProcessState::Stopping(stopping) => Some(stopping.pid()),
+            _ => None,
+        }
+    }
+
+    /// Current start attempt, while starting or waiting to restart
+    pub fn attempt(&self) -> Option<u16> {
+        match self {
+            ProcessState::Watching { attempt, .. }
+            | ProcessState::WaitingRestart { attempt, .. } => Some(*attempt),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> StateKind {
+        match self {
+            ProcessState::Stopped => StateKind::Stopped,
+            ProcessState::Watching { .. } => StateKind::Starting,
+            ProcessState::WaitingRestart { .. } => StateKind::WaitingRestart,
+            ProcessState::Running { .. } => StateKind::Running,
+            ProcessState::Stopping(_) => StateKind::Stopping,
+            ProcessState::CrashLooping => StateKind::CrashLooping,
+            // The old process still serves
+            ProcessState::Overlapping { .. } => StateKind::Running,
+        }
+    }
+}
+
+/// Times of recent unexpected exits, to detect crash loops
+#[derive(Debug, Default)]
+pub struct ExitHistory {
+    exits: VecDeque<Instant>,
+}
+
+impl ExitHistory {
+    /// Records an exit. Returns whether the process exited too often within the window.
+    pub fn record(&mut self, config: &CrashLoopConfig) -> bool {
+        let now = Instant::now();
+        self.exits.push_back(now);
+        while let Some(first) = self.exits.front() {
+            if now.duration_since(*first) <= config.window {
+                break;
+            }
+            self.exits.pop_front();
+        }
+        self.exits.len() >= config.max_exits
+    }
+
+    pub fn clear(&mut self) {
+        self.exits.clear();
+    }
+}
+
+#[derive(Debug)]
+pub struct HealthState {
+    /// Time of the next probe. Unset while a probe runs or without a health check.
+    pub next_check_at: Option<Instant>,
+    /// Failed probes in a row
+    pub failures: u32,
+}
+
+impl HealthState {
+    pub fn new(config: &ProcessConfig) -> Self {
+        Self {
+            next_check_at: config
+                .health_check
+                .as_ref()
+                .map(|health_check| Instant::now() + health_check.interval),
+            failures: 0,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum StoppingState {
     Command { timeout_at: Instant, pid: i32 },
     Terminate { timeout_at: Instant, pid: i32 },
-    Kill,
+    Kill { pid: i32 },
+}
+
+impl StoppingState {
+    /// Pid of the process being stopped
+    pub fn pid(&self) -> i32 {
+        match self {
+            StoppingState::Command { pid, .. }
+            | StoppingState::Terminate { pid, .. }
+            | StoppingState::Kill { pid } => *pid,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Event {
     Start,
     Stop,
-    ProcessExited { pid: i32, exit_code: i32 },
+    GracefulRestart,
+    ProcessExited {
+        pid: i32,
+        exit_code: i32,
+    },
     TimeoutReached,
+    HealthCheckDue,
+    HealthChecked {
+        pid: i32,
+        healthy: bool,
+    },
+    /// The output of the process matched the ready pattern
+    Ready {
+        pid: i32,
+    },
+    /// The output of the process matched the idle pattern
+    Idle {
+        pid: i32,
+    },
+    /// The output of the process matched the active pattern
+    Active {
+        pid: i32,
+    },
+    IdleTimeout,
+    StdinClosed {
+        pid: i32,
+    },
+}
+
+/// Pattern the output of a process matched
+#[derive(Debug, Clone, Copy)]
+pub enum OutputMatch {
+    Ready,
+    Idle,
+    Active,
+}
+
+impl OutputMatch {
+    pub fn event(self, pid: i32) -> Event {
+        match self {
+            OutputMatch::Ready => Event::Ready { pid },
+            OutputMatch::Idle => Event::Idle { pid },
+            OutputMatch::Active => Event::Active { pid },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Data written to the stdin of the process
+#[derive(Debug)]
+pub enum StdinData {
+    /// Written trimmed and followed by a newline, like typed into a console
+    Line(String),
+    /// Written exactly as is
+    Raw(Vec<u8>),
+}
+
+impl StdinData {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            StdinData::Line(line) => format!("{}\n", line.trim()).into_bytes(),
+            StdinData::Raw(bytes) => bytes,
+        }
+    }
+}
+
+/// A line of process output, and the stream it was written to
+#[derive(Debug, Clone)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// Simplified process state, shared outside of the deamon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateKind {
+    Stopped,
+    Starting,
+    WaitingRestart,
+    Running,
+    Stopping,
+    CrashLooping,
+}
+
+impl StateKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StateKind::Stopped => "stopped",
+            StateKind::Starting => "starting",
+            StateKind::WaitingRestart => "waiting-restart",
+            StateKind::Running => "running",
+            StateKind::Stopping => "stopping",
+            StateKind::CrashLooping => "crash-looping",
+        }
+    }
+}
+
+/// An exit of the process
+#[derive(Debug, Clone)]
+pub struct ExitRecord {
+    pub pid: i32,
+    pub exit_code: i32,
+    pub at: DateTime<Local>,
+    /// Whether the process was started again
+    pub restarted: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessStatus {
+    pub state: StateKind,
+    pub pid: Option<i32>,
+    /// When the current child was started
+    pub started_at: Option<Instant>,
+    pub attempt: Option<u16>,
+    pub last_exit_code: Option<i32>,
+    /// Set while the process is crash looping, until it's started again
+    pub crash_loop: Option<CrashLoopAlert>,
+}
+
+/// Why a process stopped being restarted
+#[derive(Debug, Clone)]
+pub struct CrashLoopAlert {
+    pub at: DateTime<Local>,
+    pub message: String,
 }
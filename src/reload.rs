@@ -0,0 +1,111 @@
+use crate::configs;
+use crate::{CONFIG, CONFIG_PATH};
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+type LogFilterReload = Box<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+static LOG_FILTER_RELOAD: OnceCell<LogFilterReload> = OnceCell::const_new();
+
+pub fn set_log_filter_reload(reload: impl Fn(&str) -> Result<()> + Send + Sync + 'static) {
+    let _ = LOG_FILTER_RELOAD.set(Box::new(reload));
+}
+
+/// Re-reads the config file and applies the parts that can change at runtime.
+/// Returns a summary of the changes.
+pub async fn reload() -> Result<String> {
+    let path = CONFIG_PATH
+        .get()
+        .ok_or_else(|| eyre!("Missing config path"))?;
+    let new = configs::load(path)?;
+    crate::validate::validate(&new)?;
+    let old = CONFIG.load_full().ok_or_else(|| eyre!("Missing config"))?;
+
+    let mut changes = Vec::new();
+    changes.extend(diff_map("tasks", &old.tasks, &new.tasks));
+    changes.extend(diff_map("backups", &old.backups, &new.backups));
+    if old.log_filter != new.log_filter {
+        if let Some(reload) = LOG_FILTER_RELOAD.get() {
+            reload(&new.log_filter)?;
+        }
+        changes.push(format!(
+            "log filter: {} -> {}",
+            old.log_filter, new.log_filter
+        ));
+    }
+    if !configs::same(&old.notifications, &new.notifications) {
+        changes.push("notifications".into());
+    }
+    let mut deferred = Vec::new();
+    if !configs::same(&old.processes, &new.processes) {
+        deferred.push("processes");
+    }
+    if old.socket != new.socket
+        || old.socket_mode != new.socket_mode
+        || old.socket_group != new.socket_group
+        || old.socket_access != new.socket_access
+    {
+        deferred.push("socket (or reopen-socket)");
+    }
+    if old.tcp_socket != new.tcp_socket {
+        deferred.push("tcp socket");
+    }
+    if old.http != new.http {
+        deferred.push("http");
+    }
+    if old.metrics != new.metrics {
+        deferred.push("metrics");
+    }
+
+    let new = Arc::new(new);
+    CONFIG.store(Some(new.clone()));
+    crate::tasks::start(&new).await?;
+
+    if !deferred.is_empty() {
+        changes.push(format!("requires restart: {}", deferred.join(", ")));
+    }
+    if changes.is_empty() {
+        changes.push("no changes".into());
+    }
+    info!("Config reloaded: {}", changes.join("; "));
+    Ok(changes.join("\n"))
+}
+
+fn diff_map<T: Serialize>(
+    what: &str,
+    old: &HashMap<String, T>,
+    new: &HashMap<String, T>,
+) -> Vec<String> {
+    let mut added: Vec<&str> = Vec::new();
+    let mut changed: Vec<&str> = Vec::new();
+    for (name, value) in new {
+        match old.get(name) {
+            None => added.push(name),
+            Some(old_value) if !configs::same(old_value, value) => changed.push(name),
+            _ => {}
+        }
+    }
+    let mut removed: Vec<&str> = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .map(String::as_str)
+        .collect();
+
+    let mut changes = Vec::new();
+    for (kind, names) in [
+        ("added", &mut added),
+        ("removed", &mut removed),
+        ("changed", &mut changed),
+    ] {
+        if !names.is_empty() {
+            names.sort_unstable();
+            changes.push(format!("{what} {kind}: {}", names.join(", ")));
+        }
+    }
+    changes
+}
@@ -0,0 +1,136 @@
+use crate::configs::{ActionType, UnavailablePolicy};
+use crate::process::OutputLine;
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A request of a client in JSON mode, one per line
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub(super) enum Request {
+    /// Input for the process
+    Command {
+        data: String,
+    },
+    Start,
+    Stop,
+    Backup {
+        name: String,
+        #[serde(default)]
+        tag: Option<String>,
+    },
+    Status,
+    /// Switches the process of the connection, handled by the socket.
+    /// Answered with the cached output of the process as `replay` lines.
+    Attach {
+        process: String,
+    },
+}
+
+/// A line sent to a client in JSON mode
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+enum Response<'a> {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<Value>,
+    },
+    Error {
+        message: String,
+    },
+    Output {
+        stream: &'static str,
+        line: &'a str,
+    },
+    /// A line of cached output, sent on attaching. The cache doesn't keep the stream.
+    Replay {
+        line: &'a str,
+    },
+    /// Output lines were dropped because the client fell behind
+    Dropped {
+        count: u64,
+    },
+    /// Sent before dolorous exits
+    Shutdown,
+}
+
+impl Response<'_> {
+    fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap_or_default();
+        line.push('\n');
+        line
+    }
+}
+
+/// Parses a request line. Returns the error response line if it's invalid.
+pub(super) fn parse(line: &str) -> Result<Request, String> {
+    serde_json::from_str(line).map_err(|err| {
+        Response::Error {
+            message: format!("Invalid request: {err}"),
+        }
+        .to_line()
+    })
+}
+
+/// Handles a request. Returns the response line.
+pub(super) async fn handle(request: Request, process: Option<&str>) -> String {
+    respond(execute(request, process).await)
+}
+
+/// Formats the result of a request
+pub fn respond(result: Result<Option<Value>>) -> String {
+    match result {
+        Ok(data) => Response::Ok { data },
+        Err(err) => Response::Error {
+            message: format!("{err:#}"),
+        },
+    }
+    .to_line()
+}
+
+async fn execute(request: Request, process: Option<&str>) -> Result<Option<Value>> {
+    let action = match request {
+        Request::Command { data } => {
+            let process = crate::process::get(process)?;
+            let response = process.command(&data, false).await?;
+            return Ok(response.map(Value::String));
+        }
+        Request::Start => ActionType::Start,
+        Request::Stop => ActionType::Stop,
+        Request::Backup { name, tag } => ActionType::Backup { backup: name, tag },
+        Request::Status => {
+            let process = crate::process::get(process)?;
+            return Ok(Some(crate::control::status_json(&process)));
+        }
+        Request::Attach { .. } => bail!("Attach is handled by the connection"),
+    };
+    crate::tasks::execute_action(&action, UnavailablePolicy::Error, process).await?;
+    Ok(None)
+}
+
+/// Tells the client output lines were dropped
+pub fn dropped(count: u64) -> String {
+    Response::Dropped { count }.to_line()
+}
+
+/// Tells the client dolorous is exiting
+pub fn shutdown() -> String {
+    Response::Shutdown.to_line()
+}
+
+/// Formats a line of process output
+pub fn output(line: &OutputLine) -> String {
+    Response::Output {
+        stream: line.stream.as_str(),
+        line: line.text.trim_end_matches(['\r', '\n']),
+    }
+    .to_line()
+}
+
+/// Formats cached output, one `replay` line per line
+pub fn replay(text: &str) -> String {
+    text.lines()
+        .map(|line| Response::Replay { line }.to_line())
+        .collect()
+}
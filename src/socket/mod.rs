@@ -0,0 +1,831 @@
+mod json;
+
+use crate::configs::{DolorousConfig, SocketAccessConfig, TcpSocketConfig};
+use crate::control::{self, CONTROL_PREFIX};
+use crate::process::{OutputLine, Process, StdinData, Stream};
+use crate::{CONFIG, EXITING};
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use color_eyre::Result;
+use nix::unistd::{chown, getgrouplist, getuid, Gid, Group, Uid, User};
+use parking_lot::Mutex;
+use std::ffi::CString;
+use std::fs::{DirBuilder, Permissions};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::unix::UCred;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, instrument, warn, Instrument};
+
+#[instrument(skip(config))]
+pub async fn setup(config: &DolorousConfig) -> Result<()> {
+    if let Some(tcp_config) = &config.tcp_socket {
+        run_tcp_socket(tcp_config).await?;
+    }
+    let Some(socket_path) = &config.socket else {
+        info!("No socket set");
+        return Ok(());
+    };
+    run_socket(config, socket_path, false).await
+}
+
+/// Path and accept loop of the open Unix socket
+static LISTENER: Mutex<Option<(PathBuf, JoinHandle<()>)>> = Mutex::new(None);
+
+/// Closes the Unix socket and opens it again with the current config, e.g. after its path or
+/// permissions changed. Connected clients stay connected.
+pub async fn reopen() -> Result<String> {
+    let config = CONFIG.load_full().ok_or_else(|| eyre!("Missing config"))?;
+    let Some(path) = &config.socket else {
+        close().await;
+        return Ok("Socket closed, none configured".into());
+    };
+    run_socket(&config, path, true).await?;
+    Ok(format!("Socket reopened at {}", path.display()))
+}
+
+/// Stops accepting connections and removes the socket file
+pub async fn close() {
+    let Some((path, accept_loop)) = LISTENER.lock().take() else {
+        return;
+    };
+    accept_loop.abort();
+    info!("Removing socket");
+    if let Err(err) = tokio::fs::remove_file(path).await {
+        error!(?err, "Failed to delete socket");
+    }
+}
+
+/// Binds the socket. With `replace`, an existing socket file is replaced without a moment
+/// the path doesn't accept connections: the new socket is bound next to it and moved over it.
+fn bind(config: &DolorousConfig, path: &Path, replace: bool) -> Result<UnixListener> {
+    if !replace || !path.exists() {
+        let listener = bind_private(path)?;
+        // Not left behind open to anyone
+        if let Err(err) = set_permissions(config, path) {
+            let _ = std::fs::remove_file(path);
+            return Err(err);
+        }
+        return Ok(listener);
+    }
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Invalid socket path"))?;
+    let temporary = path.with_file_name(format!(".{}.new", file_name.to_string_lossy()));
+    let _ = std::fs::remove_file(&temporary);
+    let listener = bind_private(&temporary)?;
+    if let Err(err) = set_permissions(config, &temporary) {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(err);
+    }
+    if let Err(err) = std::fs::rename(&temporary, path) {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(err.into());
+    }
+    Ok(listener)
+}
+
+/// Binds a socket only its owner can connect to, until `set_permissions` opens it up.
+/// It's bound in a fresh private directory and moved to `path` once its mode is restricted.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid socket path")
+    })?;
+    let directory = path.with_file_name(format!(".{}.bind", file_name.to_string_lossy()));
+    let _ = std::fs::remove_dir_all(&directory);
+    DirBuilder::new().mode(0o700).create(&directory)?;
+    let bound = directory.join(file_name);
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, Permissions::from_mode(0o600))?;
+        // Unlike a rename, fails like a bind would if the path is taken
+        std::fs::hard_link(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&directory);
+    listener
+}
+
+/// Whether a peer may use the socket. Its supplementary groups are looked up by its uid,
+/// which can block on the user database.
+fn allowed(access: &SocketAccessConfig, cred: &UCred) -> bool {
+    if cred.uid() == getuid().as_raw()
+        || access.allowed_uids.contains(&cred.uid())
+        || access.allowed_gids.contains(&cred.gid())
+    {
+        return true;
+    }
+    if access.allowed_gids.is_empty() {
+        return false;
+    }
+    match supplementary_groups(cred) {
+        Ok(groups) => groups
+            .iter()
+            .any(|gid| access.allowed_gids.contains(&gid.as_raw())),
+        Err(err) => {
+            warn!(?err, uid = cred.uid(), "Failed to look up groups of peer");
+            false
+        }
+    }
+}
+
+/// Groups of the user the peer runs as, from the user database
+fn supplementary_groups(cred: &UCred) -> Result<Vec<Gid>> {
+    let user = User::from_uid(Uid::from_raw(cred.uid()))?.ok_or_else(|| eyre!("Unknown user"))?;
+    let name = CString::new(user.name)?;
+    Ok(getgrouplist(&name, user.gid)?)
+}
+
+/// Applies `socket-mode` and `socket-group` to the socket file
+fn set_permissions(config: &DolorousConfig, path: &Path) -> Result<()> {
+    if let Some(mode) = &config.socket_mode {
+        let mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+            .wrap_err_with(|| format!("Invalid socket mode: {mode}"))?;
+        std::fs::set_permissions(path, Permissions::from_mode(mode))
+            .wrap_err("Failed to set socket mode")?;
+    }
+    if let Some(group) = &config.socket_group {
+        let gid = match group.parse::<u32>() {
+            Ok(gid) => Gid::from_raw(gid),
+            Err(_) => {
+                Group::from_name(group)?
+                    .ok_or_else(|| eyre!("Unknown socket group: {}", group))?
+                    .gid
+            }
+        };
+        chown(path, None, Some(gid)).wrap_err("Failed to set socket group")?;
+    }
+    Ok(())
+}
+
+#[instrument(skip(config))]
+async fn run_socket(config: &DolorousConfig, path: &Path, replace: bool) -> Result<()> {
+    let listener = bind(config, path, replace)
+        .wrap_err("Failed to bind socket")
+        .map_err(|err| crate::permissions::explain(err, path))?;
+    let access = Arc::new(config.socket_access.clone());
+    info!("Opened socket at {}", path.to_string_lossy());
+
+    let accept_loop = tokio::spawn(async move {
+        while !EXITING.load(Ordering::Relaxed) {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let cred = stream.peer_cred();
+                    let peer_cred = cred
+                        .as_ref()
+                        .map(|c| format!("{c:?}"))
+                        .unwrap_or_else(|_| "<unknown>".into());
+                    let (reader, mut writer) = stream.into_split();
+                    let access = access.clone();
+                    tokio::spawn(async move {
+                        let permitted = match cred {
+                            Ok(cred) => {
+                                tokio::task::spawn_blocking(move || allowed(&access, &cred))
+                                    .await
+                                    .unwrap_or(false)
+                            }
+                            Err(_) => false,
+                        };
+                        if !permitted {
+                            warn!(peer_cred, "Client not allowed to use the socket");
+                            let response = format_response(Err(eyre!("Permission denied")));
+                            let _ = writer.write_all(response.as_bytes()).await;
+                            return Ok(());
+                        }
+                        handle_client(BufReader::new(reader), writer)
+                            .instrument(info_span!("handle_client", ?peer_cred))
+                            .await
+                    });
+                }
+                Err(err) => {
+                    error!(?err, "Failed to accept connection");
+                }
+            }
+        }
+    });
+    let previous = LISTENER.lock().replace((path.to_path_buf(), accept_loop));
+    if let Some((previous_path, previous_loop)) = previous {
+        previous_loop.abort();
+        if previous_path != path {
+            if let Err(err) = tokio::fs::remove_file(&previous_path).await {
+                warn!(?err, "Failed to delete previous socket");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument(skip_all, fields(address = %config.address, port = config.port))]
+async fn run_tcp_socket(config: &TcpSocketConfig) -> Result<()> {
+    if config.token.is_empty() {
+        bail!("The TCP socket needs a token");
+    }
+    let listener = TcpListener::bind((config.address, config.port))
+        .await
+        .wrap_err("Failed to bind TCP socket")?;
+    info!("Opened TCP socket at {}", listener.local_addr()?);
+    let token: Arc<str> = config.token.as_str().into();
+
+    tokio::spawn(async move {
+        while !EXITING.load(Ordering::Relaxed) {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let token = token.clone();
+                    tokio::spawn(
+                        async move {
+                            let (reader, mut writer) = stream.into_split();
+                            let mut reader = BufReader::new(reader);
+                            if let Err(err) = authenticate(&mut reader, &token).await {
+                                warn!(?err, "Client rejected");
+                                let _ =
+                                    writer.write_all(format_response(Err(err)).as_bytes()).await;
+                                return Ok(());
+                            }
+                            handle_client(reader, writer).await
+                        }
+                        .instrument(info_span!("handle_tcp_client", %peer)),
+                    );
+                }
+                Err(err) => {
+                    error!(?err, "Failed to accept TCP connection");
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Time a TCP client has to send its token
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest line read as a token, so unauthenticated clients can't fill the memory
+const MAX_TOKEN_LINE: u64 = 4096;
+
+/// Checks the token a TCP client sends as its first line, before anything else is accepted
+async fn authenticate<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, token: &str) -> Result<()> {
+    let mut line = String::new();
+    tokio::time::timeout(
+        TOKEN_TIMEOUT,
+        (&mut *reader).take(MAX_TOKEN_LINE).read_line(&mut line),
+    )
+    .await
+    .wrap_err("No token received")?
+    .wrap_err("Failed to read token")?;
+    if !token_matches(line.trim_end_matches(['\r', '\n']), token) {
+        bail!("Invalid token");
+    }
+    info!("Client authenticated");
+    Ok(())
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of the token matched
+pub fn token_matches(received: &str, token: &str) -> bool {
+    received.len() == token.len()
+        && received
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Cancelled when dolorous exits, to tell connected clients
+static SHUTDOWN: Mutex<Option<CancellationToken>> = Mutex::new(None);
+/// Client writers still running
+static ACTIVE_WRITERS: AtomicUsize = AtomicUsize::new(0);
+/// Time the shutdown message may take to be written
+const SHUTDOWN_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn shutdown_token() -> CancellationToken {
+    SHUTDOWN
+        .lock()
+        .get_or_insert_with(CancellationToken::new)
+        .clone()
+}
+
+/// Counts a client writer as running while it's alive
+struct ActiveWriter;
+
+impl ActiveWriter {
+    fn new() -> Self {
+        ACTIVE_WRITERS.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for ActiveWriter {
+    fn drop(&mut self) {
+        ACTIVE_WRITERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Tells all connected clients dolorous is exiting, and waits for the messages to be written
+pub async fn shutdown() {
+    shutdown_token().cancel();
+    let deadline = Instant::now() + SHUTDOWN_WRITE_TIMEOUT;
+    while ACTIVE_WRITERS.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Line ending a batch of commands started with `batch`
+const BATCH_END: &str = "!end";
+/// Time the replay waits for a first line, which can ask for it as `tail` or JSON
+const REPLAY_DELAY: Duration = Duration::from_millis(200);
+/// Most bytes forwarded to stdin at once in raw input mode
+const RAW_CHUNK_SIZE: usize = 8192;
+
+/// Escapes process output starting with the control prefix by doubling it, so it can't be
+/// mistaken for a response. Input starting with a doubled prefix reaches the process with one.
+fn escape(text: &str) -> String {
+    text.split_inclusive('\n')
+        .map(|line| {
+            if line.starts_with(CONTROL_PREFIX) {
+                format!("{CONTROL_PREFIX}{line}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Removes the escape from input starting with a doubled control prefix
+fn unescape(mut line: String) -> String {
+    if line.starts_with(&format!("{CONTROL_PREFIX}{CONTROL_PREFIX}")) {
+        line.replace_range(..CONTROL_PREFIX.len(), "");
+    }
+    line
+}
+
+/// Formats a control command response. Multi-line responses have every
+/// line but the last marked with a `-` after the status, like SMTP replies.
+fn format_response(response: Result<String>) -> String {
+    let (status, message) = match response {
+        Ok(message) => ("ok", message),
+        Err(err) => ("err", format!("{err:#}")),
+    };
+    let mut lines: Vec<&str> = message.lines().collect();
+    let last = lines.pop().unwrap_or_default();
+    let mut output = String::new();
+    for line in lines {
+        output.push_str(&format!("{CONTROL_PREFIX}{status}-{line}\n"));
+    }
+    output.push_str(&format!("{CONTROL_PREFIX}{status} {last}\n"));
+    output
+}
+
+/// Which process output a client receives, set with `!output <mode>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputMode {
+    /// Stdout and stderr combined, as plain text
+    Plain,
+    /// Stdout and stderr combined, each line prefixed with `!stdout ` or `!stderr `
+    Tagged,
+    /// A single stream, as plain text
+    Only(Stream),
+    /// Both streams as JSON, see `json`
+    Json,
+}
+
+impl OutputMode {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "plain" => OutputMode::Plain,
+            "tagged" => OutputMode::Tagged,
+            "stdout" => OutputMode::Only(Stream::Stdout),
+            "stderr" => OutputMode::Only(Stream::Stderr),
+            _ => bail!(
+                "Unknown output mode: {} (plain, tagged, stdout or stderr)",
+                name
+            ),
+        })
+    }
+
+    /// Formats a line for the client, if it receives it
+    fn format(&self, line: &OutputLine) -> Option<String> {
+        match self {
+            OutputMode::Plain => Some(escape(&line.text)),
+            OutputMode::Tagged => Some(format!(
+                "{CONTROL_PREFIX}{} {}",
+                line.stream.as_str(),
+                line.text
+            )),
+            OutputMode::Only(stream) if *stream == line.stream => Some(escape(&line.text)),
+            OutputMode::Only(_) => None,
+            OutputMode::Json => Some(json::output(line)),
+        }
+    }
+
+    /// Tells the client output lines were dropped because it fell behind
+    fn dropped(&self, count: u64) -> String {
+        match self {
+            OutputMode::Json => json::dropped(count),
+            _ => format!("[dolorous: {count} lines dropped]\n"),
+        }
+    }
+}
+
+/// Sends all lines of a batch to stdin, in order
+fn send_batch(channel: &mpsc::UnboundedSender<StdinData>, lines: Vec<String>) -> Result<String> {
+    let total = lines.len();
+    info!("To stdin: batch of {} lines", total);
+    for (index, line) in lines.into_iter().enumerate() {
+        if channel.send(StdinData::Line(line)).is_err() {
+            bail!("Stdin closed after {}/{} lines", index, total);
+        }
+    }
+    Ok(format!("Sent {total} lines"))
+}
+
+/// Forwards backup progress events to the client until it disconnects
+fn forward_progress(responses: mpsc::UnboundedSender<String>) {
+    let mut events = crate::backup_manager::progress::subscribe();
+    tokio::spawn(
+        async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if responses.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// The process input goes to
+fn attached(process: &Option<Arc<Process>>) -> Result<&Arc<Process>> {
+    process
+        .as_ref()
+        .ok_or_else(|| eyre!("No process attached, use {CONTROL_PREFIX}attach <name>"))
+}
+
+/// Output receiver and cached output of a process, for a client attaching to it
+type Attachment = (Option<broadcast::Receiver<OutputLine>>, String);
+
+fn attachment(process: &Process, json: bool) -> Attachment {
+    (process.subscribe_output(), replay(process, None, json))
+}
+
+/// Cached output of a process, limited to the last `lines` lines and `replay-lines`.
+/// As `replay` lines with the JSON protocol.
+fn replay(process: &Process, lines: Option<usize>, json: bool) -> String {
+    let limit = CONFIG
+        .load()
+        .as_ref()
+        .and_then(|config| config.replay_lines);
+    let lines = match (lines, limit) {
+        (Some(lines), Some(limit)) => Some(lines.min(limit)),
+        (lines, limit) => lines.or(limit),
+    };
+    let cached = process.cached_output(lines);
+    if json {
+        json::replay(&cached)
+    } else {
+        escape(&cached)
+    }
+}
+
+/// First line of a client, if it sends one within `REPLAY_DELAY`. Read errors and
+/// disconnects are left to the input loop.
+async fn first_replay_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Option<String> {
+    // Waiting for any data can be cancelled, reading the line can't
+    if tokio::time::timeout(REPLAY_DELAY, reader.fill_buf())
+        .await
+        .is_err()
+    {
+        return None;
+    }
+    let mut line = String::new();
+    match reader.read_line(&mut line).await {
+        Ok(n) if n > 0 => Some(line),
+        _ => None,
+    }
+}
+
+/// Forwards input to stdin unchanged, in chunks as it arrives, until the client disconnects.
+/// Control commands aren't recognized anymore, any bytes may be part of the input.
+async fn forward_raw<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    process: &Option<Arc<Process>>,
+    response_sender: &mpsc::UnboundedSender<String>,
+) {
+    loop {
+        let mut chunk = Vec::with_capacity(RAW_CHUNK_SIZE);
+        match reader.read_buf(&mut chunk).await {
+            Ok(0) => {
+                info!("Client connection closed");
+                return;
+            }
+            Err(err) => {
+                warn!(?err, "Error receiving from client");
+                return;
+            }
+            Ok(_) => {}
+        }
+        let sent = attached(process)
+            .and_then(|process| Ok(process.stdin()?.send(StdinData::Raw(chunk))?));
+        if let Err(err) = sent {
+            warn!(?err, "Send error");
+            let _ = response_sender.send(format_response(Err(err)));
+        }
+    }
+}
+
+/// Waits for the next output line, or forever without a process
+async fn next_output(
+    output: &mut Option<broadcast::Receiver<OutputLine>>,
+) -> Result<OutputLine, RecvError> {
+    match output {
+        Some(output) => output.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Serves a client: replays the cached output, then streams output and takes input.
+/// A `tail` or `protocol json` sent right after connecting shapes the replay.
+async fn handle_client<R, W>(mut reader: BufReader<R>, mut writer: W) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    info!("Client connection opened");
+    // Without a default process, clients have to attach to one first
+    let mut process = crate::process::get(None).ok();
+    // Attached once the replay is sent
+    let mut output = None;
+
+    let (response_sender, mut response_receiver) = mpsc::unbounded_channel::<String>();
+    let (attach_sender, mut attach_receiver) = mpsc::unbounded_channel::<Attachment>();
+    let (mode_sender, mode_receiver) = watch::channel(OutputMode::Plain);
+
+    // Transport input to process
+    tokio::spawn(
+        async move {
+            // Lines collected since a `batch` command
+            let mut batch: Option<Vec<String>> = None;
+            let mut subscribed = false;
+            // Input is passed through unchanged from then on, see `forward_raw`
+            let mut raw_input = false;
+            // Lines are JSON requests, see `json`
+            let mut json_protocol = false;
+            // First line, read before the replay
+            let mut first_line = None;
+            if let Some(default) = &process {
+                let line = first_replay_line(&mut reader).await;
+                let replay = match line
+                    .as_deref()
+                    .and_then(|line| line.strip_prefix(CONTROL_PREFIX))
+                {
+                    Some(command) if command.trim() == "protocol json" => {
+                        json_protocol = true;
+                        let _ = mode_sender.send(OutputMode::Json);
+                        let response = Ok("Switched to the JSON protocol".into());
+                        Some(format_response(response) + &replay(default, None, true))
+                    }
+                    Some(command) => match command.trim().strip_prefix("tail ") {
+                        Some(lines) => lines.trim().parse::<usize>().ok().map(|lines| {
+                            let response = Ok(format!("Replayed up to {lines} lines"));
+                            replay(default, Some(lines), false) + &format_response(response)
+                        }),
+                        None => None,
+                    },
+                    None => None,
+                };
+                match replay {
+                    // Answered with the replay
+                    Some(replay) => {
+                        let _ = attach_sender.send((default.subscribe_output(), replay));
+                    }
+                    None => {
+                        let _ = attach_sender.send(attachment(default, false));
+                        first_line = line;
+                    }
+                }
+            }
+            loop {
+                let line = match first_line.take() {
+                    Some(line) => line,
+                    None => {
+                        let mut line = String::new();
+                        match reader.read_line(&mut line).await {
+                            Ok(n) if n < 1 => {
+                                info!("Client connection closed");
+                                break;
+                            }
+                            Err(err) => {
+                                warn!(?err, "Error receiving from client");
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        line
+                    }
+                };
+                if json_protocol {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let response = match json::parse(line.trim()) {
+                        Ok(json::Request::Attach { process: name }) => {
+                            match crate::process::get(Some(&name)) {
+                                Ok(attached) => {
+                                    let (receiver, replay) = attachment(&attached, true);
+                                    // Sent together, so the replay follows the response
+                                    let response = json::respond(Ok(None)) + &replay;
+                                    let _ = attach_sender.send((receiver, response));
+                                    process = Some(attached);
+                                    continue;
+                                }
+                                Err(err) => json::respond(Err(err)),
+                            }
+                        }
+                        Ok(request) => {
+                            let name = process.as_ref().map(|process| process.name.as_str());
+                            json::handle(request, name).await
+                        }
+                        Err(response) => response,
+                    };
+                    let _ = response_sender.send(response);
+                    continue;
+                }
+                if let Some(lines) = &mut batch {
+                    if line.trim() == BATCH_END {
+                        let lines = batch.take().unwrap_or_default();
+                        let response = attached(&process).and_then(|process| {
+                            for line in &lines {
+                                process.history.record(line);
+                            }
+                            send_batch(&process.stdin()?, lines)
+                        });
+                        let _ = response_sender.send(format_response(response));
+                    } else {
+                        lines.push(unescape(line));
+                    }
+                    continue;
+                }
+                if let Some(command) = line
+                    .strip_prefix(CONTROL_PREFIX)
+                    .filter(|command| !command.starts_with(CONTROL_PREFIX))
+                {
+                    info!("Control command: {:?}", command);
+                    if command.trim() == "batch" {
+                        batch = Some(Vec::new());
+                        continue;
+                    }
+                    if command.trim() == "subscribe progress" {
+                        if !subscribed {
+                            subscribed = true;
+                            forward_progress(response_sender.clone());
+                        }
+                        let response = Ok("Subscribed to backup progress".into());
+                        let _ = response_sender.send(format_response(response));
+                        continue;
+                    }
+                    if let Some(mode) = command.trim().strip_prefix("input ") {
+                        let response = match mode.trim() {
+                            "raw" => Ok(true),
+                            "line" => Ok(false),
+                            mode => Err(eyre!("Unknown input mode: {}", mode)),
+                        }
+                        .map(|raw| {
+                            raw_input = raw;
+                            format!("Input mode set to {}", mode.trim())
+                        });
+                        let _ = response_sender.send(format_response(response));
+                        if raw_input {
+                            break;
+                        }
+                        continue;
+                    }
+                    if command.trim() == "protocol json" {
+                        // Acknowledged in the text format, everything after is JSON
+                        json_protocol = true;
+                        let _ = mode_sender.send(OutputMode::Json);
+                        let response = Ok("Switched to the JSON protocol".into());
+                        let _ = response_sender.send(format_response(response));
+                        continue;
+                    }
+                    if let Some(name) = command.trim().strip_prefix("output ") {
+                        let name = name.trim();
+                        let response = OutputMode::parse(name).map(|mode| {
+                            let _ = mode_sender.send(mode);
+                            format!("Output mode set to {name}")
+                        });
+                        let _ = response_sender.send(format_response(response));
+                        continue;
+                    }
+                    if let Some(lines) = command.trim().strip_prefix("tail ") {
+                        let response = match (lines.trim().parse::<usize>(), &process) {
+                            (Ok(lines), Some(process)) => {
+                                let _ = response_sender.send(replay(process, Some(lines), false));
+                                Ok(format!("Replayed up to {lines} lines"))
+                            }
+                            (Ok(_), None) => Err(eyre!("No process attached")),
+                            (Err(_), _) => Err(eyre!("Usage: tail <lines>")),
+                        };
+                        let _ = response_sender.send(format_response(response));
+                        continue;
+                    }
+                    if let Some(name) = command.trim().strip_prefix("attach ") {
+                        let response = crate::process::get(Some(name.trim())).map(|attached| {
+                            let _ = attach_sender.send(attachment(&attached, false));
+                            let message = format!("Attached to {}", attached.name);
+                            process = Some(attached);
+                            message
+                        });
+                        let _ = response_sender.send(format_response(response));
+                        continue;
+                    }
+                    let name = process.as_ref().map(|process| process.name.as_str());
+                    let response = control::execute(command.trim(), name).await;
+                    let _ = response_sender.send(format_response(response));
+                    continue;
+                }
+                let line = unescape(line);
+                info!("To stdin: {:?}", line);
+                let sent = attached(&process).and_then(|process| {
+                    process.history.record(&line);
+                    Ok(process.stdin()?.send(StdinData::Line(line))?)
+                });
+                if let Err(err) = sent {
+                    warn!(?err, "Send error");
+                    let _ = response_sender.send(format_response(Err(err)));
+                }
+            }
+            if raw_input {
+                forward_raw(&mut reader, &process, &response_sender).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    // Transport process output to socket
+    tokio::spawn(
+        async move {
+            let _active = ActiveWriter::new();
+            let shutdown = shutdown_token();
+            let forward = async {
+                loop {
+                    let line = select! {
+                        line = next_output(&mut output) => match line {
+                            Ok(line) => {
+                                let Some(text) = mode_receiver.borrow().format(&line) else {
+                                    continue;
+                                };
+                                text
+                            }
+                            // The client didn't keep up, the oldest lines were dropped
+                            Err(RecvError::Lagged(count)) => mode_receiver.borrow().dropped(count),
+                            Err(RecvError::Closed) => {
+                                // Process exited, only control command responses until attached again
+                                output = None;
+                                continue;
+                            }
+                        },
+                        Some((attached, data)) = attach_receiver.recv() => {
+                            output = attached;
+                            data
+                        }
+                        response = response_receiver.recv() => match response {
+                            Some(response) => response,
+                            None => break,
+                        },
+                    };
+                    if writer.write_all(line.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            };
+            let cancelled = select! {
+                _ = forward => false,
+                _ = shutdown.cancelled() => true,
+            };
+            if cancelled {
+                let message = if *mode_receiver.borrow() == OutputMode::Json {
+                    json::shutdown()
+                } else {
+                    format!("{CONTROL_PREFIX}shutdown dolorous shutting down\n")
+                };
+                // A client that doesn't read doesn't hold up the exit
+                let write = writer.write_all(message.as_bytes());
+                let _ = tokio::time::timeout(SHUTDOWN_WRITE_TIMEOUT, write).await;
+            }
+        }
+        .in_current_span(),
+    );
+    Ok(())
+}
@@ -1,58 +1,184 @@
-use crate::configs::ActionType;
-use crate::process::Controls;
+use crate::configs::{ActionType, Condition, UnavailablePolicy};
+use crate::process::{Controls, OutputLine, Process, StateKind};
 use crate::CONFIG;
-use color_eyre::eyre::{bail, eyre};
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
+use regex::Regex;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tracing::{debug, info};
 
-pub async fn execute_action(action: &ActionType) -> Result<()> {
+/// Runs an action. Process actions go to the named process, or the default one.
+pub async fn execute_action(
+    action: &ActionType,
+    policy: UnavailablePolicy,
+    process: Option<&str>,
+) -> Result<()> {
+    if needs_process(action) && !process_available(&*crate::process::get(process)?) {
+        match policy {
+            UnavailablePolicy::Skip => {
+                debug!("Process not running, skipping action");
+                return Ok(());
+            }
+            UnavailablePolicy::Error => bail!("Process not running"),
+        }
+    }
     match action {
-        ActionType::Backup { backup } => backup_action(backup).await,
-        ActionType::Command { command } => command_action(command).await,
-        ActionType::Start => start_action().await,
-        ActionType::Stop => stop_action().await,
-        ActionType::Restart => restart_action().await,
+        ActionType::Backup { backup, tag } => backup_action(backup, tag.as_deref()).await,
+        ActionType::Command { command, raw } => command_action(command, *raw, process).await,
+        ActionType::Start => control_action(&[Controls::Start], process).await,
+        ActionType::Stop => control_action(&[Controls::Stop], process).await,
+        ActionType::Restart => control_action(&[Controls::Stop, Controls::Start], process).await,
+        ActionType::GracefulRestart => control_action(&[Controls::GracefulRestart], process).await,
+        ActionType::Signal { signal } => signal_action(signal, process).await,
+        ActionType::SendAndWait {
+            command,
+            pattern,
+            timeout,
+        } => send_and_wait_action(command, pattern, *timeout, process).await,
+        ActionType::Wait { duration } => {
+            tokio::time::sleep(*duration).await;
+            Ok(())
+        }
+        ActionType::WaitFor { pattern, timeout } => {
+            wait_for_action(pattern, *timeout, process).await
+        }
+        ActionType::If {
+            condition,
+            then,
+            r#else,
+        } => if_action(condition, then, r#else, policy, process).await,
     }
 }
 
-async fn backup_action(backup: &str) -> Result<()> {
-    let config = CONFIG.get().ok_or_else(|| eyre!("Missing config"))?;
-    crate::backup_manager::run_backup(config, backup).await?;
-    Ok(())
+/// Stop and restart also work on a stopped process, stopping one is a no-op
+fn needs_process(action: &ActionType) -> bool {
+    matches!(
+        action,
+        ActionType::Command { .. }
+            | ActionType::SendAndWait { .. }
+            | ActionType::GracefulRestart
+            | ActionType::Signal { .. }
+    )
+}
+
+fn process_available(process: &Process) -> bool {
+    matches!(
+        process.status.lock().state,
+        StateKind::Starting | StateKind::Running
+    )
+}
+
+async fn signal_action(signal: &str, process: Option<&str>) -> Result<()> {
+    let signal = crate::process::parse_signal(signal)?;
+    crate::process::get(process)?.send_signal(signal)
 }
 
-async fn command_action(command: &str) -> Result<()> {
-    let sender = {
-        let opt = crate::process::STDIN.lock();
-        let Some(sender) = &*opt else {
-            bail!("Uninitialized");
-        };
-        sender.clone()
+async fn wait_for_action(
+    pattern: &str,
+    timeout: Option<Duration>,
+    process: Option<&str>,
+) -> Result<()> {
+    let pattern = Regex::new(pattern).wrap_err("Invalid wait-for pattern")?;
+    let mut output = crate::process::get(process)?
+        .subscribe_output()
+        .ok_or_else(|| eyre!("Output unavailable"))?;
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, wait_for_line(&mut output, &pattern))
+            .await
+            .wrap_err_with(|| format!("No output matched {pattern} in time"))?,
+        None => wait_for_line(&mut output, &pattern).await,
+    }
+}
+
+async fn send_and_wait_action(
+    command: &str,
+    pattern: &str,
+    timeout: Duration,
+    process: Option<&str>,
+) -> Result<()> {
+    let pattern = Regex::new(pattern).wrap_err("Invalid send-and-wait pattern")?;
+    let process = crate::process::get(process)?;
+    // Subscribe first, the response may arrive right away
+    let mut output = process
+        .subscribe_output()
+        .ok_or_else(|| eyre!("Output unavailable"))?;
+    let wait = async {
+        if let Some(response) = process.command(command, false).await? {
+            // Sent over RCON, the response may not show up in the output
+            if pattern.is_match(&response) {
+                return Ok(());
+            }
+        }
+        wait_for_line(&mut output, &pattern).await
     };
-    sender.send(command.to_string())?;
-    Ok(())
+    tokio::time::timeout(timeout, wait)
+        .await
+        .wrap_err_with(|| format!("No output matched {pattern} in time"))?
+}
+
+/// Waits for the next output line matching the pattern
+async fn wait_for_line(output: &mut Receiver<OutputLine>, pattern: &Regex) -> Result<()> {
+    loop {
+        match output.recv().await {
+            Ok(line) if pattern.is_match(&line.text) => {
+                debug!("Output matched: {}", line.text.trim_end());
+                return Ok(());
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => bail!("Output closed"),
+        }
+    }
 }
 
-async fn start_action() -> Result<()> {
-    let Some(control) = crate::process::CONTROL.get().cloned() else {
-        bail!("Uninitialized");
+async fn if_action(
+    condition: &Condition,
+    then: &[ActionType],
+    r#else: &[ActionType],
+    policy: UnavailablePolicy,
+    process: Option<&str>,
+) -> Result<()> {
+    let branch = if check_condition(condition, &*crate::process::get(process)?) {
+        then
+    } else {
+        r#else
     };
-    control.send(Controls::Start)?;
+    for action in branch {
+        Box::pin(execute_action(action, policy, process)).await?;
+    }
     Ok(())
 }
 
-async fn stop_action() -> Result<()> {
-    let Some(control) = crate::process::CONTROL.get().cloned() else {
-        bail!("Uninitialized")
-    };
-    control.send(Controls::Stop)?;
+fn check_condition(condition: &Condition, process: &Process) -> bool {
+    let status = process.status.lock().clone();
+    match condition {
+        Condition::Running => status.state == StateKind::Running,
+        Condition::Stopped => matches!(status.state, StateKind::Stopped | StateKind::CrashLooping),
+        Condition::ExitCode { exit_code } => status.last_exit_code == Some(*exit_code),
+        Condition::Not { condition } => !check_condition(condition, process),
+    }
+}
+
+async fn backup_action(backup: &str, tag: Option<&str>) -> Result<()> {
+    let config = CONFIG.load_full().ok_or_else(|| eyre!("Missing config"))?;
+    crate::backup_manager::run_backup(&config, backup, tag).await?;
     Ok(())
 }
 
-async fn restart_action() -> Result<()> {
-    let Some(control) = crate::process::CONTROL.get().cloned() else {
-        bail!("Uninitialized")
-    };
-    control.send(Controls::Stop)?;
-    control.send(Controls::Start)?;
+async fn command_action(command: &str, raw: bool, process: Option<&str>) -> Result<()> {
+    let process = crate::process::get(process)?;
+    if let Some(response) = process.command(command, raw).await? {
+        info!("RCON response: {}", response);
+    }
+    Ok(())
+}
+
+/// Sends control messages to the process, in order
+async fn control_action(controls: &[Controls], process: Option<&str>) -> Result<()> {
+    let process = crate::process::get(process)?;
+    for control in controls {
+        process.control.send(*control)?;
+    }
     Ok(())
 }
@@ -1,45 +1,365 @@
 mod actions;
 
-use crate::configs::{DolorousConfig, TaskConfig};
-use chrono::Local;
+pub use self::actions::execute_action;
+
+use crate::configs::{self, DolorousConfig, ErrorPolicy, TaskConfig};
+use crate::process::StateKind;
+use chrono::{DateTime, Local, TimeZone};
+use chrono_tz::Tz;
+use color_eyre::eyre::{bail, eyre, WrapErr};
 use color_eyre::Result;
 use cron::Schedule;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 use std::str::FromStr;
+use std::time::Duration;
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tracing::{error, info, info_span, warn, Instrument};
 
+/// Running schedulers, with the config they were started with
+static SCHEDULERS: Mutex<BTreeMap<String, (TaskConfig, JoinHandle<()>)>> =
+    Mutex::new(BTreeMap::new());
+/// Longest time the scheduler sleeps before checking the clock again
+const CLOCK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Recent runs of each task, oldest first
+static RUNS: Mutex<BTreeMap<String, VecDeque<TaskRun>>> = Mutex::new(BTreeMap::new());
+
+/// A finished run of a task
+#[derive(Debug, Clone)]
+pub struct TaskRun {
+    pub at: DateTime<Local>,
+    pub duration: Duration,
+    /// First error of the run, if an action failed
+    pub error: Option<String>,
+}
+
+/// Starts schedulers for all tasks. Schedulers of removed or changed tasks are stopped.
 pub async fn start(config: &DolorousConfig) -> Result<()> {
+    let mut schedulers = SCHEDULERS.lock();
+    schedulers.retain(|name, (task, handle)| {
+        let keep = matches!(config.tasks.get(name), Some(new) if configs::same(task, new));
+        if !keep {
+            handle.abort();
+        }
+        keep
+    });
+    RUNS.lock()
+        .retain(|name, _| config.tasks.contains_key(name));
     for (name, cfg) in &config.tasks {
-        tokio::spawn(task_scheduler(cfg.clone()).instrument(info_span!("task_scheduler", name)));
+        if schedulers.contains_key(name) {
+            continue;
+        }
+        let handle = tokio::spawn(
+            task_scheduler(name.clone(), cfg.clone())
+                .instrument(info_span!("task_scheduler", name)),
+        );
+        schedulers.insert(name.clone(), (cfg.clone(), handle));
     }
     Ok(())
 }
 
-async fn task_scheduler(config: TaskConfig) {
-    let Ok(schedule) = Schedule::from_str(&config.schedule) else {
-        error!("Invalid task schedule: {}", &config.schedule);
-        return;
+/// When a task runs
+pub enum TaskSchedule {
+    Cron(Schedule, Option<Tz>),
+    Every(Duration),
+    Once(DateTime<Local>),
+}
+
+impl TaskSchedule {
+    pub fn new(config: &TaskConfig) -> Result<Self> {
+        Ok(match (&config.schedule, config.every, config.once) {
+            (Some(schedule), _, _) => TaskSchedule::Cron(
+                Schedule::from_str(schedule)
+                    .wrap_err_with(|| format!("Invalid task schedule: {schedule}"))?,
+                config.timezone,
+            ),
+            (_, Some(every), _) => TaskSchedule::Every(every),
+            (_, _, Some(once)) => TaskSchedule::Once(once),
+            _ => bail!("No task schedule"),
+        })
+    }
+
+    /// Time of the first run after `after`.
+    /// Cron times repeated when the clocks go back for DST only run the first time.
+    pub fn next_run(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            TaskSchedule::Cron(schedule, None) => next_cron_run(schedule, after),
+            TaskSchedule::Cron(schedule, Some(timezone)) => {
+                next_cron_run(schedule, after.with_timezone(timezone))
+                    .map(|datetime| datetime.with_timezone(&Local))
+            }
+            TaskSchedule::Every(every) => Some(after + chrono::Duration::from_std(*every).ok()?),
+            TaskSchedule::Once(once) => (*once > after).then_some(*once),
+        }
+    }
+
+    /// Usual time between runs, if the task repeats
+    pub fn interval(&self) -> Option<chrono::Duration> {
+        let first = self.next_run(Local::now())?;
+        let second = self.next_run(first)?;
+        Some(second - first)
+    }
+}
+
+/// First cron time after `after` that is also later on the wall clock
+fn next_cron_run<T: TimeZone>(schedule: &Schedule, after: DateTime<T>) -> Option<DateTime<T>> {
+    schedule
+        .after(&after)
+        .find(|datetime| datetime.naive_local() > after.naive_local())
+}
+
+/// Wall clock the scheduler reads and waits on, replaced in tests
+trait Clock {
+    fn now(&self) -> DateTime<Local>;
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Sleeps until the wall clock reaches `datetime`. The clock is checked regularly,
+/// so it being set while sleeping, e.g. by NTP, doesn't make the task run early or late.
+async fn sleep_until(clock: &impl Clock, datetime: DateTime<Local>) {
+    while let Ok(remaining) = (datetime - clock.now()).to_std() {
+        if remaining.is_zero() {
+            break;
+        }
+        clock.sleep(remaining.min(CLOCK_CHECK_INTERVAL)).await;
+    }
+}
+
+/// Waits for the runs of a schedule
+struct Scheduler<C> {
+    schedule: TaskSchedule,
+    clock: C,
+    /// Next runs are computed from the last scheduled time, not the clock,
+    /// so the clock going back after a run doesn't run the task again
+    last: DateTime<Local>,
+}
+
+impl<C: Clock> Scheduler<C> {
+    fn new(schedule: TaskSchedule, clock: C) -> Self {
+        let last = clock.now();
+        Self {
+            schedule,
+            clock,
+            last,
+        }
+    }
+
+    /// Waits for the next run. Returns its scheduled time, or nothing once there are no more.
+    async fn next(&mut self) -> Option<DateTime<Local>> {
+        loop {
+            let datetime = self.schedule.next_run(self.last)?;
+            self.last = datetime;
+            if datetime < self.clock.now() {
+                warn!("Task deadline passed");
+                // E.g. the clock jumped forward, skip all missed runs at once
+                self.last = self.clock.now();
+                continue;
+            }
+            sleep_until(&self.clock, datetime).await;
+            return Some(datetime);
+        }
+    }
+}
+
+/// Whether the process is up, not stopped, starting or restarting
+fn process_running(process: Option<&str>) -> bool {
+    match crate::process::get(process) {
+        Ok(process) => process.status.lock().state == StateKind::Running,
+        Err(err) => {
+            warn!(?err, "Unable to check the process state");
+            false
+        }
+    }
+}
+
+async fn task_scheduler(name: String, config: TaskConfig) {
+    let schedule = match TaskSchedule::new(&config) {
+        Ok(schedule) => schedule,
+        Err(err) => {
+            error!(?err, "Invalid task");
+            return;
+        }
     };
-    for datetime in schedule.upcoming(Local) {
-        let Ok(time_until) = (datetime - Local::now()).to_std() else {
-            warn!("Task deadline passed");
+    let mut scheduler = Scheduler::new(schedule, SystemClock);
+    if let TaskSchedule::Once(once) = scheduler.schedule {
+        if once <= scheduler.last {
+            warn!("Task run time {} passed, not running it", once.to_rfc3339());
+        }
+    }
+    while scheduler.next().await.is_some() {
+        if !config.run_if_stopped && !process_running(config.process.as_deref()) {
+            info!("Process not running, skipping task");
             continue;
-        };
-        tokio::time::sleep_until(Instant::now() + time_until).await;
-        let actions = config.actions.clone();
+        }
+        let name = name.clone();
+        let config = config.clone();
         tokio::spawn(
             async move {
-                info!("Running task...");
-                for (index, action) in actions.iter().enumerate() {
-                    if let Err(err) = actions::execute_action(action)
-                        .instrument(info_span!("execute_action", index))
-                        .await
-                    {
-                        error!(?err, "Error running task");
-                    }
-                }
+                run_actions(&name, &config).await;
             }
             .instrument(info_span!("run_task")),
         );
     }
 }
+
+/// Runs the actions of a task in order. Returns the result of each action.
+async fn run_actions(name: &str, config: &TaskConfig) -> Vec<Result<()>> {
+    info!("Running task...");
+    let at = Local::now();
+    let started = Instant::now();
+    let deadline = config.timeout.map(|timeout| Instant::now() + timeout);
+    let mut results = Vec::with_capacity(config.actions.len());
+    for (index, action) in config.actions.iter().enumerate() {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let limit = match (config.action_timeout, remaining) {
+            (Some(action_timeout), Some(remaining)) => Some(action_timeout.min(remaining)),
+            (action_timeout, remaining) => action_timeout.or(remaining),
+        };
+        let execute =
+            actions::execute_action(action, config.when_unavailable, config.process.as_deref())
+                .instrument(info_span!("execute_action", index));
+        // Dropping the action cancels it, backups clean up their partial archive
+        let result = match limit {
+            Some(limit) => tokio::time::timeout(limit, execute)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(eyre!(
+                        "Timed out after {}",
+                        humantime::format_duration(limit)
+                    ))
+                }),
+            None => execute.await,
+        };
+        let failed = result.is_err();
+        if let Err(err) = &result {
+            error!(?err, "Error running task");
+        }
+        results.push(result);
+        if failed && config.on_error == ErrorPolicy::Abort {
+            if index + 1 < config.actions.len() {
+                error!("Action failed, skipping the remaining actions");
+            }
+            break;
+        }
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            if index + 1 < config.actions.len() {
+                error!("Task timed out, skipping the remaining actions");
+            }
+            break;
+        }
+    }
+    let error = results.iter().enumerate().find_map(|(index, result)| {
+        let err = result.as_ref().err()?;
+        Some(format!("action {}: {:#}", index + 1, err))
+    });
+    let run = TaskRun {
+        at,
+        duration: started.elapsed(),
+        error,
+    };
+    let mut runs = RUNS.lock();
+    let history = runs.entry(name.to_string()).or_default();
+    history.push_back(run);
+    while history.len() > config.history_size {
+        history.pop_front();
+    }
+    results
+}
+
+/// Tasks acting on the process with their recent runs, oldest first, sorted by name
+pub fn runs(process: &str) -> Vec<(String, Vec<TaskRun>)> {
+    let tasks: Vec<_> = SCHEDULERS
+        .lock()
+        .iter()
+        .filter(|(_, (config, _))| {
+            crate::process::get(config.process.as_deref())
+                .map_or(false, |target| target.name == process)
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    let runs = RUNS.lock();
+    tasks
+        .into_iter()
+        .map(|name| {
+            let history = runs
+                .get(&name)
+                .map(|history| history.iter().cloned().collect());
+            (name, history.unwrap_or_default())
+        })
+        .collect()
+}
+
+/// Runs a task right away, outside of its schedule. Returns the result of each action.
+pub async fn run_task(name: &str) -> Result<Vec<Result<()>>> {
+    let config = SCHEDULERS
+        .lock()
+        .get(name)
+        .map(|(config, _)| config.clone())
+        .ok_or_else(|| eyre!("Unknown task: {}", name))?;
+    Ok(run_actions(name, &config)
+        .instrument(info_span!("run_task", name))
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock that moves only when slept on, by the time slept or by a queued jump
+    struct FakeClock {
+        now: Mutex<DateTime<Local>>,
+        jumps: Mutex<VecDeque<chrono::Duration>>,
+    }
+
+    impl Clock for &FakeClock {
+        fn now(&self) -> DateTime<Local> {
+            *self.now.lock()
+        }
+
+        fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+            let step = match self.jumps.lock().pop_front() {
+                Some(jump) => jump,
+                None => chrono::Duration::from_std(duration).unwrap(),
+            };
+            *self.now.lock() += step;
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn clock_stepping_back_doesnt_repeat_runs() {
+        let start = Local.with_ymd_and_hms(2022, 6, 15, 10, 30, 0).unwrap();
+        let clock = &FakeClock {
+            now: Mutex::new(start),
+            // Set back an hour while waiting for the first run
+            jumps: Mutex::new(VecDeque::from([chrono::Duration::hours(-1)])),
+        };
+        let schedule = TaskSchedule::Cron(Schedule::from_str("0 0 * * * *").unwrap(), None);
+        let mut scheduler = Scheduler::new(schedule, clock);
+
+        let first = scheduler.next().await.unwrap();
+        assert_eq!(first, start + chrono::Duration::minutes(30));
+        assert!(clock.now() >= first, "ran before its time");
+
+        // Set back after the run, the same time doesn't run again
+        *clock.now.lock() = start;
+        let second = scheduler.next().await.unwrap();
+        assert_eq!(second, first + chrono::Duration::hours(1));
+        assert!(clock.now() >= second, "ran before its time");
+    }
+}
@@ -0,0 +1,89 @@
+use crate::process::StateKind;
+use metrics::{counter, gauge, histogram, increment_counter};
+use std::time::Duration;
+
+const STATES: [StateKind; 6] = [
+    StateKind::Stopped,
+    StateKind::Starting,
+    StateKind::WaitingRestart,
+    StateKind::Running,
+    StateKind::Stopping,
+    StateKind::CrashLooping,
+];
+
+/// Starts serving the metrics at `/metrics`, if configured.
+/// Until then, recording metrics does nothing.
+#[cfg(feature = "prometheus")]
+pub fn setup(config: &crate::configs::DolorousConfig) -> color_eyre::Result<()> {
+    use color_eyre::eyre::WrapErr;
+    use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+
+    let Some(metrics_config) = &config.metrics else {
+        return Ok(());
+    };
+    let address = std::net::SocketAddr::new(metrics_config.address, metrics_config.port);
+    PrometheusBuilder::new()
+        .with_http_listener(address)
+        .set_buckets_for_metric(
+            Matcher::Full("dolorous_backup_duration_seconds".into()),
+            &[
+                1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+            ],
+        )
+        .wrap_err("Invalid metrics buckets")?
+        .install()
+        .wrap_err("Failed to start metrics endpoint")?;
+    tracing::info!("Metrics endpoint listening on {}", address);
+    Ok(())
+}
+
+/// Sets the state gauge of the process, 1 for the current state and 0 for the others
+pub fn process_state(process: &str, state: StateKind) {
+    for other in STATES {
+        let value = if other == state { 1.0 } else { 0.0 };
+        gauge!(
+            "dolorous_process_state",
+            value,
+            "process" => process.to_string(),
+            "state" => other.as_str()
+        );
+    }
+}
+
+pub fn process_crashed(process: &str) {
+    increment_counter!("dolorous_process_crashes_total", "process" => process.to_string());
+}
+
+pub fn process_restarted(process: &str) {
+    increment_counter!("dolorous_process_restarts_total", "process" => process.to_string());
+}
+
+/// Counts bytes of output produced by the process
+pub fn output_produced(process: &str, bytes: usize) {
+    counter!(
+        "dolorous_output_bytes_total",
+        bytes as u64,
+        "process" => process.to_string()
+    );
+}
+
+pub fn backup_failed(backup: &str) {
+    increment_counter!("dolorous_backup_failures_total", "backup" => backup.to_string());
+}
+
+/// Records a successful backup, with the size of the archive if known
+pub fn backup_succeeded(backup: &str, duration: Duration, size: Option<u64>) {
+    histogram!(
+        "dolorous_backup_duration_seconds",
+        duration.as_secs_f64(),
+        "backup" => backup.to_string()
+    );
+    if let Some(size) = size {
+        gauge!("dolorous_backup_size_bytes", size as f64, "backup" => backup.to_string());
+    }
+    gauge!(
+        "dolorous_backup_last_success_timestamp_seconds",
+        chrono::Utc::now().timestamp() as f64,
+        "backup" => backup.to_string()
+    );
+}
@@ -0,0 +1,208 @@
+use crate::configs::{
+    ActionType, BackupMode, DolorousConfig, HealthProbe, ProcessConfig, TaskConfig,
+};
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use cron::Schedule;
+use regex::Regex;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Checks the parts of the config that would otherwise only fail once used,
+/// e.g. cron schedules and names of backups. Reports all problems at once.
+pub fn validate(config: &DolorousConfig) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut names: Vec<_> = config.processes.keys().collect();
+    names.sort();
+    for name in names {
+        check_process(
+            &format!("process {name}"),
+            &config.processes[name],
+            &mut errors,
+        );
+    }
+    let mut names: Vec<_> = config.backups.keys().collect();
+    names.sort();
+    for name in names {
+        let backup = &config.backups[name];
+        if !backup.location.exists() {
+            errors.push(format!(
+                "backup {}: location {} doesn't exist",
+                name,
+                backup.location.display()
+            ));
+        }
+        check_process_name(
+            &format!("backup {name}"),
+            config,
+            backup.process.as_deref(),
+            &mut errors,
+        );
+        if backup.keep_last == Some(0) {
+            errors.push(format!(
+                "backup {name}: keep-last 0 would delete every backup"
+            ));
+        }
+        let mut tags: Vec<_> = backup.tag_retention.keys().collect();
+        tags.sort();
+        for tag in tags {
+            if backup.tag_retention[tag].keep_last == Some(0) {
+                errors.push(format!(
+                    "backup {name}: keep-last 0 for tag {tag} would delete every backup"
+                ));
+            }
+        }
+        let removes_local = backup.sftp.as_ref().map_or(false, |sftp| !sftp.keep_local);
+        if removes_local && matches!(backup.backup_mode, BackupMode::Incremental) {
+            errors.push(format!(
+                "backup {name}: incremental backups need the local copy, set sftp keep-local"
+            ));
+        }
+    }
+    let mut names: Vec<_> = config.tasks.keys().collect();
+    names.sort();
+    for name in names {
+        check_task(
+            &format!("task {name}"),
+            config,
+            &config.tasks[name],
+            &mut errors,
+        );
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<_> = errors.iter().map(|error| format!("  - {error}")).collect();
+    bail!("Invalid config:\n{}", list.join("\n"))
+}
+
+fn check_process(what: &str, process: &ProcessConfig, errors: &mut Vec<String>) {
+    let patterns = [
+        ("ready-pattern", &process.ready_pattern),
+        ("idle-pattern", &process.idle_pattern),
+        ("active-pattern", &process.active_pattern),
+    ];
+    for (field, pattern) in patterns {
+        if let Some(pattern) = pattern {
+            check_pattern(&format!("{what}: {field}"), pattern, errors);
+        }
+    }
+    if let Some(health_check) = &process.health_check {
+        if let HealthProbe::Command { pattern, .. } = &health_check.probe {
+            check_pattern(&format!("{what}: health check pattern"), pattern, errors);
+        }
+        check_timeout(
+            &format!("{what}: health check interval"),
+            health_check.interval,
+            errors,
+        );
+        check_timeout(
+            &format!("{what}: health check timeout"),
+            health_check.timeout,
+            errors,
+        );
+    }
+    if let Some(rcon) = &process.rcon {
+        check_timeout(&format!("{what}: rcon timeout"), rcon.timeout, errors);
+    }
+}
+
+fn check_task(what: &str, config: &DolorousConfig, task: &TaskConfig, errors: &mut Vec<String>) {
+    if let Some(schedule) = &task.schedule {
+        if let Err(err) = Schedule::from_str(schedule) {
+            errors.push(format!("{what}: invalid schedule {schedule:?}: {err}"));
+        }
+    }
+    if let Some(every) = task.every {
+        check_timeout(&format!("{what}: every"), every, errors);
+    }
+    if let Some(timeout) = task.action_timeout {
+        check_timeout(&format!("{what}: action timeout"), timeout, errors);
+    }
+    if let Some(timeout) = task.timeout {
+        check_timeout(&format!("{what}: timeout"), timeout, errors);
+    }
+    check_process_name(what, config, task.process.as_deref(), errors);
+    check_actions(what, config, &task.actions, errors);
+}
+
+fn check_actions(
+    what: &str,
+    config: &DolorousConfig,
+    actions: &[ActionType],
+    errors: &mut Vec<String>,
+) {
+    for (index, action) in actions.iter().enumerate() {
+        let what = format!("{what}: action {}", index + 1);
+        match action {
+            ActionType::Backup { backup, .. } => {
+                if !config.backups.contains_key(backup) {
+                    errors.push(format!("{what}: undefined backup {backup}"));
+                }
+            }
+            ActionType::Signal { signal } => {
+                if let Err(err) = crate::process::parse_signal(signal) {
+                    errors.push(format!("{what}: {err}"));
+                }
+            }
+            ActionType::SendAndWait {
+                pattern, timeout, ..
+            } => {
+                check_pattern(&what, pattern, errors);
+                check_timeout(&what, *timeout, errors);
+            }
+            ActionType::WaitFor { pattern, timeout } => {
+                check_pattern(&what, pattern, errors);
+                if let Some(timeout) = timeout {
+                    check_timeout(&what, *timeout, errors);
+                }
+            }
+            ActionType::If { then, r#else, .. } => {
+                check_actions(&format!("{what} then"), config, then, errors);
+                check_actions(&format!("{what} else"), config, r#else, errors);
+            }
+            ActionType::Command { .. }
+            | ActionType::Start
+            | ActionType::Stop
+            | ActionType::Restart
+            | ActionType::GracefulRestart
+            | ActionType::Wait { .. } => {}
+        }
+    }
+}
+
+/// Checks a process referenced by name exists. Without a name, there has to be a default one.
+fn check_process_name(
+    what: &str,
+    config: &DolorousConfig,
+    process: Option<&str>,
+    errors: &mut Vec<String>,
+) {
+    match process {
+        Some(name) if !config.processes.contains_key(name) => {
+            errors.push(format!("{what}: undefined process {name}"));
+        }
+        None if config.processes.len() > 1
+            && !config
+                .processes
+                .contains_key(crate::configs::DEFAULT_PROCESS) =>
+        {
+            errors.push(format!(
+                "{what}: multiple processes, name one with `process`"
+            ));
+        }
+        _ => {}
+    }
+}
+
+fn check_pattern(what: &str, pattern: &str, errors: &mut Vec<String>) {
+    if let Err(err) = Regex::new(pattern) {
+        errors.push(format!("{what}: invalid pattern {pattern:?}: {err}"));
+    }
+}
+
+fn check_timeout(what: &str, timeout: Duration, errors: &mut Vec<String>) {
+    if timeout.is_zero() {
+        errors.push(format!("{what}: has to be longer than 0s"));
+    }
+}